@@ -0,0 +1,1143 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+use crate::notes::*;
+use crate::ratio::Ratio;
+use crate::notation::{self, mk_note, mk_rest, shape_note, ReadOptions};
+use crate::notation::{DYNAMICS, SFORZANDO};
+use crate::repeats::{self, Flow};
+use crate::style::{Articulation, Style};
+
+// ABC 2.1 tunes into a Score, a movement for each tune (X:) named by its
+// title. Each voice (V:) is a staff played on the piano. Repeats and
+// D.C./D.S. are played out as the options say, and a voice overlay (&)
+// plays its notes from the start of the bar with the others. Grace notes,
+// chord symbols, lyrics and the decorations we have nothing for are
+// skipped.
+
+#[derive(Debug)]
+pub struct AbcError {
+    // From 1.
+    pub line: usize,
+    pub column: usize,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for AbcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: expected {}, found {}",
+               self.line, self.column, self.expected, self.found)
+    }
+}
+
+impl std::error::Error for AbcError {}
+
+type Res<T> = Result<T, AbcError>;
+
+fn fail<T>(line: usize, column: usize, expected: &str,
+           found: impl fmt::Display) -> Res<T> {
+    Err(AbcError {
+        line,
+        column,
+        expected: expected.to_owned(),
+        found: found.to_string(),
+    })
+}
+
+pub fn load(path: impl AsRef<Path>, opts: &ReadOptions) -> Res<Score> {
+    match fs::read(path) {
+        Ok(data) => read(&text(data), opts),
+        Err(e) => fail(0, 0, "a readable file", e),
+    }
+}
+
+pub fn read_abc(mut r: impl Read, opts: &ReadOptions) -> Res<Score> {
+    let mut data = vec![];
+    if let Err(e) = r.read_to_end(&mut data) {
+        return fail(0, 0, "a readable file", e);
+    }
+    read(&text(data), opts)
+}
+
+// UTF-8, or else Latin-1 as older files are.
+fn text(data: Vec<u8>) -> String {
+    match String::from_utf8(data) {
+        Ok(s) => s,
+        Err(e) => e.into_bytes().into_iter().map(|b| b as char).collect(),
+    }
+}
+
+fn read(src: &str, opts: &ReadOptions) -> Res<Score> {
+    let mut score = Score::default();
+    let mut tune: Option<Tune> = None;
+    for (i, line) in src.lines().enumerate() {
+        let line = strip_comment(line);
+        let ln = i + 1;
+        // A blank line ends a tune; text between tunes is free.
+        if line.trim().is_empty() {
+            if let Some(t) = tune.take() {
+                t.finish(&mut score)?;
+            }
+            continue;
+        }
+        if let Some(('X', _)) = field(line, false) {
+            if let Some(t) = tune.take() {
+                t.finish(&mut score)?;
+            }
+            tune = Some(Tune::new(ln, opts));
+            continue;
+        }
+        if let Some(t) = &mut tune {
+            t.read_line(line, ln)?;
+        }
+    }
+    if let Some(t) = tune.take() {
+        t.finish(&mut score)?;
+    }
+    if score.movements.is_empty() {
+        return fail(1, 1, "a tune starting with X:", "none");
+    }
+    if score.movements.len() == 1 {
+        score.title = score.movements[0].name.clone();
+    }
+    Ok(score)
+}
+
+// Up to a % that isn't escaped. %% directives go too.
+fn strip_comment(line: &str) -> &str {
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        if c == '%' && prev != '\\' {
+            return &line[..i];
+        }
+        prev = c;
+    }
+    line
+}
+
+// A field line such as "K:G". In the body, A: to G: followed by a bar line
+// are notes.
+fn field(line: &str, body: bool) -> Option<(char, &str)> {
+    let mut cs = line.chars();
+    let (c, colon) = (cs.next()?, cs.next()?);
+    if !c.is_ascii_alphabetic() || colon != ':' {
+        return None;
+    }
+    let rest = &line[2..];
+    if body && ('A'..='G').contains(&c)
+        && rest.starts_with(['|', ':']) {
+        return None;
+    }
+    Some((c, rest.trim()))
+}
+
+struct Voice {
+    id: String,
+    name: Option<String>,
+    // Bars read so far, and the notes of the one being read.
+    bars: Vec<Vec<Note>>,
+    notes: Vec<Note>,
+    // Notes of the bar before each & in it.
+    overlays: Vec<Vec<Note>>,
+    // Repeats and jumps around the bars by number.
+    flow: Vec<Flow>,
+    // Marks for the bar being read: where it starts (segno, coda) and
+    // where it ends (fine, D.C., To Coda).
+    before: Vec<Flow>,
+    after: Vec<Flow>,
+    // Accidentals written in this bar, by step.
+    sharps: HashMap<i32, i32>,
+    dynamic: f32,
+    // One-shot multiplier for the next note (sfz).
+    accent: f32,
+    // Decorations for the next note.
+    arts: Vec<Articulation>,
+    slurs: usize,
+    // The tuplet being read, and how many notes it still takes.
+    tuplet: Option<(Ratio, i64)>,
+    // Length for the next note, after a broken rhythm (a>b).
+    broken: Option<Ratio>,
+    // The last note or chord: its bar and index, written length and
+    // tuplet.
+    last: Option<(usize, usize, Ratio, Ratio)>,
+    // Tones tied over from the last note, which keep their accidentals.
+    held: Vec<Tone>,
+    codas: usize,
+}
+
+impl Voice {
+    fn new(id: &str, name: Option<String>) -> Self {
+        Self {
+            id: id.to_owned(),
+            name,
+            bars: vec![],
+            notes: vec![],
+            overlays: vec![],
+            flow: vec![],
+            before: vec![],
+            after: vec![],
+            sharps: HashMap::new(),
+            dynamic: 1.,
+            accent: 1.,
+            arts: vec![],
+            slurs: 0,
+            tuplet: None,
+            broken: None,
+            last: None,
+            held: vec![],
+            codas: 0,
+        }
+    }
+
+    // Bars without notes (two bar lines in a row) don't count.
+    fn end_bar(&mut self) {
+        if !self.overlays.is_empty() {
+            let mut lines = mem::take(&mut self.overlays);
+            lines.push(mem::take(&mut self.notes));
+            if lines.iter().any(|l| !l.is_empty()) {
+                self.notes = vec![overlaid(lines)];
+            }
+            self.last = None;
+        }
+        if !self.notes.is_empty() {
+            self.flow.append(&mut self.before);
+            self.flow.push(Flow::System(self.bars.len()));
+            self.flow.append(&mut self.after);
+            self.bars.push(mem::take(&mut self.notes));
+        }
+        self.sharps.clear();
+    }
+
+    // a b & c d: the notes after & start again from the start of the bar.
+    fn overlay(&mut self) {
+        self.overlays.push(mem::take(&mut self.notes));
+        self.tuplet = None;
+        self.broken = None;
+        self.last = None;
+        self.held.clear();
+    }
+
+    fn last_note(&mut self) -> Option<&mut Note> {
+        let (b, k, _, _) = self.last?;
+        if b == self.bars.len() {
+            self.notes.get_mut(k)
+        } else {
+            self.bars.get_mut(b)?.get_mut(k)
+        }
+    }
+
+    fn pos(&self) -> Ratio {
+        self.notes.iter().map(|n| n.dur()).sum()
+    }
+}
+
+struct Tune<'a> {
+    style: &'a Style,
+    repeats: bool,
+    title: Option<String>,
+    composer: Option<String>,
+    body: bool,
+    // The first of each is the sheet's.
+    meter: Option<Meter>,
+    key: Option<Key>,
+    cur_meter: Meter,
+    cur_key: Key,
+    // The unit note length (L:), in whole notes.
+    unit: Option<Ratio>,
+    // Quarters a minute from the start, and later by bar and offset.
+    start_bpm: Option<f64>,
+    tempo: Vec<(usize, Ratio, f64)>,
+    voices: Vec<Voice>,
+    cur: usize,
+    line: usize,
+}
+
+// Tonics of the major keys by fifths from C, and the modes by how far
+// they are from their major.
+const TONICS: [(char, i32); 7] = [
+    ('C', 0), ('G', 1), ('D', 2), ('A', 3), ('E', 4), ('B', 5), ('F', -1),
+];
+const MODES: [(&str, i32); 9] = [
+    ("maj", 0), ("ion", 0), ("mix", -1), ("dor", -2), ("min", -3),
+    ("aeo", -3), ("phr", -4), ("lyd", 1), ("loc", -5),
+];
+
+// Note lengths are up to this many times the unit, or that many times
+// shorter, so that working with them can't overflow.
+const LONGEST: i64 = 1 << 12;
+
+impl<'a> Tune<'a> {
+    fn new(line: usize, opts: &'a ReadOptions) -> Self {
+        Self {
+            style: &opts.style,
+            repeats: opts.repeats,
+            title: None,
+            composer: None,
+            body: false,
+            meter: None,
+            key: None,
+            cur_meter: Meter::default(),
+            cur_key: Key::c_major(),
+            unit: None,
+            start_bpm: None,
+            tempo: vec![],
+            voices: vec![],
+            cur: 0,
+            line,
+        }
+    }
+
+    fn fail<T>(&self, column: usize, expected: &str,
+               found: impl fmt::Display) -> Res<T> {
+        fail(self.line, column, expected, found)
+    }
+
+    fn read_line(&mut self, line: &str, ln: usize) -> Res<()> {
+        self.line = ln;
+        if let Some((c, value)) = field(line, self.body) {
+            return self.read_field(c, value, 3);
+        }
+        if !self.body {
+            return self.fail(1, "a header field such as T: or K:", line);
+        }
+        self.read_music(line)
+    }
+
+    fn read_field(&mut self, c: char, value: &str, col: usize) -> Res<()> {
+        match c {
+            'T' if self.title.is_none() => self.title = Some(value.to_owned()),
+            'C' if self.composer.is_none() => {
+                self.composer = Some(value.to_owned());
+            }
+            'M' => {
+                let m = self.read_meter(value, col)?;
+                self.meter.get_or_insert(m);
+                self.cur_meter = m;
+            }
+            'L' => {
+                let unit = read_fraction(value);
+                match unit {
+                    Some(x) if x > Ratio::zero() => self.unit = Some(x),
+                    _ => return self.fail(col, "a note length like 1/8",
+                                          value),
+                }
+            }
+            'Q' => {
+                if let Some(bpm) = self.read_tempo(value, col)? {
+                    if self.body {
+                        let v = self.voice();
+                        let at = (v.bars.len(), v.pos());
+                        self.tempo.push((at.0, at.1, bpm));
+                    } else {
+                        self.start_bpm = Some(bpm);
+                    }
+                }
+            }
+            'K' => {
+                let k = self.read_key(value, col)?;
+                self.key.get_or_insert(k);
+                self.cur_key = k;
+                if !self.body {
+                    self.body = true;
+                    let m = self.cur_meter;
+                    self.unit.get_or_insert_with(|| {
+                        if (m.beats as f64) / (m.unit as f64) < 0.75 {
+                            Ratio::new(1, 16)
+                        } else {
+                            Ratio::new(1, 8)
+                        }
+                    });
+                }
+            }
+            'V' => {
+                let mut words = value.split_whitespace();
+                let id = match words.next() {
+                    Some(x) => x,
+                    None => return self.fail(col, "a voice name", "none"),
+                };
+                let name = value.split_whitespace()
+                    .find_map(|w| {
+                        let w = w.strip_prefix("name=")
+                            .or_else(|| w.strip_prefix("nm="))?;
+                        Some(w.trim_matches('"').to_owned())
+                    });
+                self.cur = match self.voices.iter().position(|v| v.id == id) {
+                    Some(i) => i,
+                    None => {
+                        self.voices.push(Voice::new(id, name));
+                        self.voices.len() - 1
+                    }
+                };
+            }
+            // Lyrics, notes, parts and the like.
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // 6/8, 2+3/8, C for 4/4 and C| for 2/2.
+    fn read_meter(&self, s: &str, col: usize) -> Res<Meter> {
+        let m = match s {
+            "C" => Some(Meter { beats: 4, unit: 4 }),
+            "C|" => Some(Meter { beats: 2, unit: 2 }),
+            "none" | "" => Some(Meter::default()),
+            _ => (|| {
+                let (beats, unit) = split2(s, '/')?;
+                let beats = beats.split('+')
+                    .map(|x| x.trim().parse::<i32>().ok())
+                    .sum::<Option<i32>>()?;
+                let unit: i32 = unit.trim().parse().ok()?;
+                if beats > 0 && unit > 0 && unit & (unit - 1) == 0 {
+                    Some(Meter { beats, unit })
+                } else {
+                    None
+                }
+            })(),
+        };
+        match m {
+            Some(m) => Ok(m),
+            None => self.fail(col, "a meter like 6/8 or C", s),
+        }
+    }
+
+    // 1/4=120, 3/8=60, or a bare number of unit notes. Text in quotes
+    // (Allegro) is skipped.
+    fn read_tempo(&self, s: &str, col: usize) -> Res<Option<f64>> {
+        let mut rest = String::new();
+        let mut quoted = false;
+        for c in s.chars() {
+            if c == '"' {
+                quoted = !quoted;
+            } else if !quoted {
+                rest.push(c);
+            }
+        }
+        let rest = rest.trim();
+        if rest.is_empty() {
+            return Ok(None);
+        }
+        let (beat, n) = match split2(rest, '=') {
+            Some((beat, n)) => {
+                let beat = beat.split_whitespace()
+                    .map(read_fraction)
+                    .sum::<Option<Ratio>>();
+                (beat, n)
+            }
+            None => (self.unit, rest),
+        };
+        match (beat, n.trim().parse::<f64>()) {
+            (Some(b), Ok(n)) if b > Ratio::zero() && n > 0. => {
+                Ok(Some(n * (b * Ratio::from_int(4)).to_f64()))
+            }
+            _ => self.fail(col, "a tempo like 1/4=120", s),
+        }
+    }
+
+    // G, Gm, G minor, Ador, F#mix, Bb, none; the tonic may be lower case,
+    // as in bm. Clefs and the like after it don't change the pitches.
+    fn read_key(&self, s: &str, col: usize) -> Res<Key> {
+        let s = s.trim();
+        let key = (|| {
+            if s.is_empty() || s.starts_with("none") || s.starts_with("HP") {
+                return Some(Key::c_major());
+            }
+            // Highland pipes: F# and C#.
+            if s.starts_with("Hp") {
+                return Some(Key { fifths: 2, minor: false });
+            }
+            let mut cs = s.chars().peekable();
+            let tonic = cs.next()?.to_ascii_uppercase();
+            let mut fifths = TONICS.iter().find(|x| x.0 == tonic)?.1;
+            match cs.peek() {
+                Some('#') => {
+                    fifths += 7;
+                    cs.next();
+                }
+                Some('b') => {
+                    fifths -= 7;
+                    cs.next();
+                }
+                _ => (),
+            }
+            let rest: String = cs.collect();
+            let mode = rest.split_whitespace().next()
+                .unwrap_or("")
+                .to_lowercase();
+            let (shift, minor) = if mode.is_empty() || mode.contains('=') {
+                (0, false)
+            } else if mode == "m" {
+                (-3, true)
+            } else {
+                let m = MODES.iter().find(|x| mode.starts_with(x.0))?;
+                (m.1, m.0 == "min" || m.0 == "aeo")
+            };
+            let fifths = fifths + shift;
+            if fifths.abs() > 7 {
+                return None;
+            }
+            Some(Key { fifths, minor })
+        })();
+        match key {
+            Some(k) => Ok(k),
+            None => self.fail(col, "a key like G, Ador or Bbm", s),
+        }
+    }
+
+    // The voice being read; the first declared one until V: says.
+    fn voice(&mut self) -> &mut Voice {
+        if self.voices.is_empty() {
+            self.voices.push(Voice::new("", None));
+        }
+        &mut self.voices[self.cur]
+    }
+
+    fn read_music(&mut self, line: &str) -> Res<()> {
+        let cs: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < cs.len() {
+            let c = cs[i];
+            let col = i + 1;
+            match c {
+                ' ' | '\t' | '`' | '$' | 'y' | '\\' => i += 1,
+                '"' => {
+                    // A chord symbol or an annotation.
+                    i = match cs[i + 1..].iter().position(|&x| x == '"') {
+                        Some(k) => i + k + 2,
+                        None => return self.fail(col, "a closing \"", "none"),
+                    };
+                }
+                '!' | '+' => {
+                    let k = match cs[i + 1..].iter().position(|&x| x == c) {
+                        Some(k) => k,
+                        None => return self.fail(col, "the end of a decoration",
+                                                 "none"),
+                    };
+                    let name: String = cs[i + 1..i + 1 + k].iter().collect();
+                    self.decoration(&name);
+                    i += k + 2;
+                }
+                '.' | '~' | 'H' | 'L' | 'M' | 'O' | 'P' | 'S' | 'T' | 'u'
+                | 'v' => {
+                    self.decoration(&c.to_string());
+                    i += 1;
+                }
+                '{' => {
+                    // Grace notes take no time here.
+                    i = match cs[i..].iter().position(|&x| x == '}') {
+                        Some(k) => i + k + 1,
+                        None => return self.fail(col, "a closing }", "none"),
+                    };
+                }
+                '(' if cs.get(i + 1).is_some_and(|x| x.is_ascii_digit()) => {
+                    i = self.read_tuplet(&cs, i + 1)?;
+                }
+                '(' => {
+                    self.voice().slurs += 1;
+                    i += 1;
+                }
+                ')' => {
+                    let gap = self.style.shape(Articulation::Slur).gap;
+                    let v = self.voice();
+                    v.slurs = v.slurs.saturating_sub(1);
+                    if let (Some(gap), Some(n)) = (gap, v.last_note()) {
+                        n.rest_after = gap;
+                    }
+                    i += 1;
+                }
+                '[' if cs.get(i + 2) == Some(&':') && cs.get(i + 1)
+                    .is_some_and(|x| x.is_ascii_alphabetic()) => {
+                    let k = match cs[i..].iter().position(|&x| x == ']') {
+                        Some(k) => k,
+                        None => return self.fail(col, "a closing ]", "none"),
+                    };
+                    let value: String = cs[i + 3..i + k].iter().collect();
+                    self.read_field(cs[i + 1], value.trim(), col + 3)?;
+                    i += k + 1;
+                }
+                '[' if cs.get(i + 1).is_some_and(|x| x.is_ascii_digit()) => {
+                    self.voice().end_bar();
+                    i = self.read_ending(&cs, i + 1)?;
+                }
+                '|' | ':' | '[' if c != '[' || cs.get(i + 1) == Some(&'|') => {
+                    i = self.read_bar_line(&cs, i)?;
+                }
+                '[' => i = self.read_chord(&cs, i)?,
+                '-' => {
+                    let v = self.voice();
+                    let tones = match v.last_note() {
+                        Some(n) => {
+                            n.tie = true;
+                            tones_of(&n.pitch)
+                        }
+                        None => return self.fail(col, "a note to tie", "-"),
+                    };
+                    v.held = tones;
+                    i += 1;
+                }
+                '>' | '<' => i = self.read_broken(&cs, i)?,
+                'z' | 'x' | 'Z' | 'X' => {
+                    let (mult, k) = self.read_length(&cs, i + 1)?;
+                    if c == 'Z' || c == 'X' {
+                        // Whole bars of rest.
+                        let n = if k == i + 1 { Ratio::one() } else { mult };
+                        let len = self.cur_meter.bar_dur() * n;
+                        let v = self.voice();
+                        v.notes.push(mk_rest(Duration::from_len(len)));
+                    } else {
+                        self.push(Pitch::Rest, mult);
+                    }
+                    i = k;
+                }
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let (tone, k) = self.read_tone(&cs, i)?;
+                    let (mult, k) = self.read_length(&cs, k)?;
+                    self.push(Pitch::Single(tone), mult);
+                    i = k;
+                }
+                '&' => {
+                    self.voice().overlay();
+                    i += 1;
+                }
+                _ => return self.fail(col, "a note, a rest or a bar line", c),
+            }
+        }
+        Ok(())
+    }
+
+    fn decoration(&mut self, name: &str) {
+        let v = self.voice();
+        let name = name.to_lowercase();
+        let dynamic = match name.as_str() {
+            "pppp" => "ppp",
+            "ffff" => "fff",
+            x => x,
+        };
+        if let Some(d) = DYNAMICS.iter().find(|d| d.0 == dynamic) {
+            v.dynamic = d.1;
+            return;
+        }
+        let art = match name.as_str() {
+            "." | "staccato" => Some(Articulation::Staccato),
+            "l" | ">" | "accent" | "emphasis" => Some(Articulation::Accent),
+            "tenuto" => Some(Articulation::Tenuto),
+            "^" | "marcato" => Some(Articulation::Marcato),
+            _ => None,
+        };
+        if let Some(a) = art {
+            v.arts.push(a);
+            return;
+        }
+        match name.as_str() {
+            "sfz" | "sf" => v.accent = SFORZANDO,
+            "s" | "segno" => v.before.push(Flow::Segno),
+            // The first is where to leave for the coda, the second the
+            // coda itself.
+            "o" | "coda" => {
+                v.codas += 1;
+                if v.codas == 1 {
+                    v.after.push(Flow::ToCoda);
+                } else {
+                    v.before.push(Flow::Coda);
+                }
+            }
+            "dacoda" => v.after.push(Flow::ToCoda),
+            "fine" => v.after.push(Flow::Fine),
+            x if x.starts_with("d.c.") || x == "dacapo" => {
+                v.after.push(Flow::Jump { segno: false, coda: false });
+            }
+            x if x.starts_with("d.s.") || x == "dalsegno" => {
+                v.after.push(Flow::Jump { segno: true, coda: false });
+            }
+            // Trills, fermatas, bowings, fingerings...
+            _ => (),
+        }
+    }
+
+    // (3abc, or (p:q:r for p notes in the time of q, over the next r.
+    fn read_tuplet(&mut self, cs: &[char], mut i: usize) -> Res<usize> {
+        let mut nums: Vec<Option<i64>> = vec![];
+        loop {
+            let start = i;
+            while i < cs.len() && cs[i].is_ascii_digit() {
+                i += 1;
+            }
+            let s: String = cs[start..i].iter().collect();
+            nums.push(s.parse().ok());
+            if nums.len() < 3 && cs.get(i) == Some(&':') {
+                i += 1;
+            } else {
+                break;
+            }
+        }
+        let p = nums[0].unwrap_or(3);
+        let m = self.cur_meter;
+        let compound = m.unit == 8 && m.beats % 3 == 0 && m.beats > 3;
+        let q = nums.get(1).cloned().flatten().unwrap_or(match p {
+            2 | 4 | 8 => 3,
+            3 | 6 => 2,
+            _ if compound => 3,
+            _ => 2,
+        });
+        let r = nums.get(2).cloned().flatten().unwrap_or(p);
+        if p < 2 || q < 1 || r < 1 {
+            return self.fail(i, "a tuplet like (3 or (3:2:3", "a bad one");
+        }
+        self.voice().tuplet = Some((Ratio::new(q, p), r));
+        Ok(i)
+    }
+
+    // |, ||, |], [|, |:, :|, ::, and an ending after it such as :|2.
+    fn read_bar_line(&mut self, cs: &[char], mut i: usize) -> Res<usize> {
+        let start = i;
+        if cs[i] == '[' {
+            i += 1;
+        }
+        while i < cs.len() && (cs[i] == '|' || cs[i] == ':') {
+            i += 1;
+        }
+        if cs.get(i) == Some(&']') {
+            i += 1;
+        }
+        let token: String = cs[start..i].iter().collect();
+        let v = self.voice();
+        v.end_bar();
+        if token.starts_with(':') {
+            v.flow.push(Flow::RepeatEnd(None));
+        }
+        if token.ends_with(':') && token.contains(|c| c != ':')
+            || token == "::" {
+            v.flow.push(Flow::RepeatStart);
+        }
+        if cs.get(i).is_some_and(|x| x.is_ascii_digit()) {
+            i = self.read_ending(cs, i)?;
+        }
+        Ok(i)
+    }
+
+    // 1, 2, 1,3 or 1-3.
+    fn read_ending(&mut self, cs: &[char], mut i: usize) -> Res<usize> {
+        let start = i;
+        while i < cs.len() && (cs[i].is_ascii_digit() || cs[i] == ','
+                               || cs[i] == '-') {
+            i += 1;
+        }
+        let s: String = cs[start..i].iter().collect();
+        let mut ns = vec![];
+        for part in s.split(',').filter(|x| !x.is_empty()) {
+            let range = match split2(part, '-') {
+                Some((a, b)) => a.parse::<u32>().ok().zip(b.parse().ok()),
+                None => part.parse().ok().map(|a| (a, a)),
+            };
+            match range {
+                Some((a, b)) if a >= 1 && a <= b => ns.extend(a..=b),
+                _ => return self.fail(start + 1, "an ending like 1 or 1,2",
+                                      &s),
+            }
+        }
+        self.voice().flow.push(Flow::Ending(ns));
+        Ok(i)
+    }
+
+    // [CEG]2: the length of the first note, times that of the chord.
+    fn read_chord(&mut self, cs: &[char], mut i: usize) -> Res<usize> {
+        let start = i;
+        i += 1;
+        let mut tones = vec![];
+        let mut first = None;
+        let mut tie = false;
+        loop {
+            match cs.get(i) {
+                Some(']') => break,
+                Some(' ') => i += 1,
+                Some('-') => {
+                    tie = true;
+                    i += 1;
+                }
+                Some(_) => {
+                    let (tone, k) = self.read_tone(cs, i)?;
+                    let (mult, k) = self.read_length(cs, k)?;
+                    first.get_or_insert(mult);
+                    if !tones.contains(&tone) {
+                        tones.push(tone);
+                    }
+                    i = k;
+                }
+                None => return self.fail(start + 1, "a closing ]", "none"),
+            }
+        }
+        let (mult, i) = self.read_length(cs, i + 1)?;
+        let pitch = match &tones[..] {
+            [] => return self.fail(start + 1, "notes in the chord", "none"),
+            [t] => Pitch::Single(*t),
+            _ => Pitch::Chord(tones),
+        };
+        let held = if tie { tones_of(&pitch) } else { vec![] };
+        self.push(pitch, first.unwrap_or_else(Ratio::one) * mult);
+        if tie {
+            let v = self.voice();
+            if let Some(n) = v.last_note() {
+                n.tie = true;
+            }
+            v.held = held;
+        }
+        Ok(i)
+    }
+
+    // a>b is a dotted a and a shorter b; a<b the other way round, and the
+    // more signs the more so.
+    fn read_broken(&mut self, cs: &[char], mut i: usize) -> Res<usize> {
+        let c = cs[i];
+        let mut n = 0;
+        while cs.get(i) == Some(&c) && n < 3 {
+            i += 1;
+            n += 1;
+        }
+        let short = Ratio::new(1, 1 << n);
+        let long = Ratio::from_int(2) - short;
+        let (first, second) = if c == '>' {
+            (long, short)
+        } else {
+            (short, long)
+        };
+        let v = self.voice();
+        let (b, k, len, scale) = match v.last {
+            Some(x) => x,
+            None => return self.fail(i, "a note before the broken rhythm", c),
+        };
+        if b != v.bars.len() || k + 1 != v.notes.len() {
+            return self.fail(i, "a note before the broken rhythm", c);
+        }
+        let n = &mut v.notes[k];
+        let d = Duration::from_len(len * first * Ratio::from_int(2));
+        n.duration = d.scaled(scale);
+        v.last = Some((b, k, len * first, scale));
+        v.broken = Some(second);
+        Ok(i)
+    }
+
+    // ^^c, _B, =e, C,, or c': steps from C5 and the accidental, if any.
+    fn read_tone(&mut self, cs: &[char], mut i: usize) -> Res<(Tone, usize)> {
+        let mut acc = None;
+        let mut k = i;
+        while k < cs.len() && (cs[k] == '^' || cs[k] == '_' || cs[k] == '=') {
+            k += 1;
+        }
+        let sign: String = cs[i..k].iter().collect();
+        if !sign.is_empty() {
+            acc = match sign.as_str() {
+                "^" => Some(1),
+                "^^" => Some(2),
+                "_" => Some(-1),
+                "__" => Some(-2),
+                "=" => Some(0),
+                _ => return self.fail(i + 1, "an accidental", sign),
+            };
+        }
+        i = k;
+        let c = match cs.get(i) {
+            Some(&c) if "ABCDEFGabcdefg".contains(c) => c,
+            Some(c) => return self.fail(i + 1, "a note name", c),
+            None => return self.fail(i + 1, "a note name", "the end"),
+        };
+        // C is middle C, c the one above.
+        let degree = "CDEFGAB".find(c.to_ascii_uppercase()).unwrap() as i32;
+        let mut step = if c.is_ascii_uppercase() { degree - 7 } else { degree };
+        i += 1;
+        while let Some(&c) = cs.get(i) {
+            match c {
+                '\'' => step += 7,
+                ',' => step -= 7,
+                _ => break,
+            }
+            i += 1;
+        }
+        let key = self.cur_key;
+        let v = self.voice();
+        let sharp = match acc {
+            Some(n) => {
+                v.sharps.insert(step, n);
+                n
+            }
+            None => v.sharps.get(&step).cloned()
+                .or_else(|| v.held.iter().find(|t| t.step == step)
+                         .map(|t| t.sharp))
+                .unwrap_or_else(|| key.sharps_for(step.rem_euclid(7))),
+        };
+        Ok((Tone::new(step, sharp), i))
+    }
+
+    // 3, /, //, /4, 3/2: times the unit note length.
+    fn read_length(&self, cs: &[char], mut i: usize) -> Res<(Ratio, usize)> {
+        let digits = |i: &mut usize| -> Option<i64> {
+            let start = *i;
+            while *i < cs.len() && cs[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            cs[start..*i].iter().collect::<String>().parse().ok()
+        };
+        let start = i;
+        let num = digits(&mut i).unwrap_or(1);
+        let mut den: i64 = 1;
+        while cs.get(i) == Some(&'/') {
+            i += 1;
+            match den.checked_mul(digits(&mut i).unwrap_or(2)) {
+                Some(x) if x <= LONGEST => den = x,
+                _ => return self.fail(start + 1, "a note length",
+                                      "one too short"),
+            }
+        }
+        if num == 0 || den == 0 {
+            return self.fail(start + 1, "a note length", "zero");
+        }
+        if num > LONGEST {
+            return self.fail(start + 1, "a note length", "one too long");
+        }
+        Ok((Ratio::new(num, den), i))
+    }
+
+    fn push(&mut self, pitch: Pitch, mult: Ratio) {
+        let unit = self.unit.unwrap_or_else(|| Ratio::new(1, 8));
+        let style = self.style;
+        let v = self.voice();
+        let mut len = unit * mult;
+        if let Some(f) = v.broken.take() {
+            len = len * f;
+        }
+        let mut scale = Ratio::one();
+        if let Some((s, left)) = v.tuplet {
+            scale = s;
+            v.tuplet = if left > 1 { Some((s, left - 1)) } else { None };
+        }
+        let dur = Duration::from_len(len * Ratio::from_int(2)).scaled(scale);
+        let rest = pitch == Pitch::Rest;
+        let mut n = if rest { mk_rest(dur) } else { mk_note(dur, pitch) };
+        let arts = mem::take(&mut v.arts);
+        if !rest {
+            n.amp = v.dynamic * v.accent;
+            v.accent = 1.;
+            if v.slurs > 0 {
+                shape_note(&mut n, style.shape(Articulation::Slur), false);
+            }
+            for a in arts {
+                shape_note(&mut n, style.shape(a), true);
+            }
+        }
+        v.held.clear();
+        v.notes.push(n);
+        v.last = Some((v.bars.len(), v.notes.len() - 1, len, scale));
+    }
+
+    fn finish(mut self, score: &mut Score) -> Res<()> {
+        if self.voices.iter().all(|v| v.bars.is_empty() && v.notes.is_empty()) {
+            return self.fail(1, "notes after K:", "none");
+        }
+        for v in &mut self.voices {
+            v.end_bar();
+            v.flow.append(&mut v.before);
+            v.flow.append(&mut v.after);
+        }
+        // The first voice has the say on repeats; bars only the others have
+        // are played once, after its own.
+        let count = self.voices.iter().map(|v| v.bars.len()).max().unwrap();
+        let mut flow = mem::take(&mut self.voices[0].flow);
+        flow.extend((self.voices[0].bars.len()..count).map(Flow::System));
+        let al_coda = flow.contains(&Flow::ToCoda);
+        for f in &mut flow {
+            if let Flow::Jump { coda, .. } = f {
+                *coda = al_coda;
+            }
+        }
+        let order = repeats::expand(&flow, self.repeats);
+
+        // Voices are padded to the longest in each bar.
+        let len = |ns: &[Note]| ns.iter().map(|n| n.dur()).sum::<Ratio>();
+        let lens: Vec<Ratio> = (0..count).map(|i| {
+            self.voices.iter()
+                .filter_map(|v| v.bars.get(i))
+                .map(|b| len(b))
+                .max()
+                .unwrap_or_default()
+        }).collect();
+        let mut starts = HashMap::new();
+        let mut at = Ratio::zero();
+        for &i in &order {
+            starts.entry(i).or_insert(at);
+            at += lens[i];
+        }
+
+        let mut tracks = vec![];
+        for v in &self.voices {
+            let mut notes = vec![];
+            for &i in &order {
+                let bar = v.bars.get(i).map_or(&[][..], |b| &b[..]);
+                notes.extend(bar.iter().cloned());
+                let short = lens[i] - len(bar);
+                if short > Ratio::zero() {
+                    notes.push(mk_rest(Duration::from_len(short)));
+                }
+            }
+            // Only ties between the same pitches make a longer note.
+            for i in 0..notes.len() {
+                let held = notes.get(i + 1)
+                    .is_some_and(|n| n.pitch == notes[i].pitch);
+                notes[i].tie &= held;
+            }
+            let name = match (&v.name, v.id.as_str()) {
+                (Some(name), _) => name.clone(),
+                (None, "") => "melody".to_owned(),
+                (None, id) => id.to_owned(),
+            };
+            tracks.push(Track {
+                name,
+                instrument: Instrument::Piano,
+                notes: notation::merge_ties(notes),
+                pedal: vec![],
+            });
+        }
+
+        let mut points = vec![];
+        if let Some(bpm) = self.start_bpm {
+            points.push(Tempo { at: 0., bpm, ramp: false });
+        }
+        let mut tempo: Vec<(Ratio, f64)> = self.tempo.iter()
+            .filter_map(|&(b, off, bpm)| Some((*starts.get(&b)? + off, bpm)))
+            .collect();
+        tempo.sort_by_key(|x| x.0);
+        for (at, bpm) in tempo {
+            points.retain(|p: &Tempo| p.at != at.to_f64());
+            points.push(Tempo { at: at.to_f64(), bpm, ramp: false });
+        }
+
+        if score.composer.is_none() {
+            score.composer = self.composer.take();
+        }
+        score.movements.push(Movement {
+            name: self.title.take(),
+            sheet: Sheet {
+                tracks,
+                tempo: TempoMap { points },
+                meter: self.meter.unwrap_or_default(),
+                key: self.key.unwrap_or_default(),
+            },
+        });
+        Ok(())
+    }
+}
+
+// The lines of an overlaid bar as one note of voices, as the reader of .ss
+// files has them. They are padded to the longest, and what is tied at the
+// end of one is let go there.
+fn overlaid(lines: Vec<Vec<Note>>) -> Note {
+    let len = |ns: &[Note]| ns.iter().map(|n| n.dur()).sum::<Ratio>();
+    let most = lines.iter().map(|l| len(l)).max().unwrap_or_default();
+    let lines = lines.into_iter().map(|mut l| {
+        if let Some(n) = l.last_mut() {
+            n.tie = false;
+        }
+        let short = most - len(&l);
+        if short > Ratio::zero() {
+            l.push(mk_rest(Duration::from_len(short)));
+        }
+        l
+    }).collect();
+    mk_note(Duration::exact(most), Pitch::Voices(lines))
+}
+
+fn tones_of(p: &Pitch) -> Vec<Tone> {
+    match p {
+        Pitch::Single(t) => vec![*t],
+        Pitch::Chord(ts) => ts.clone(),
+        _ => vec![],
+    }
+}
+
+fn split2(s: &str, sep: char) -> Option<(&str, &str)> {
+    let i = s.find(sep)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+// 1/8, 3/8 or 1, in whole notes.
+fn read_fraction(s: &str) -> Option<Ratio> {
+    let s = s.trim();
+    let (n, d) = split2(s, '/').unwrap_or((s, "1"));
+    let (n, d): (i64, i64) = (n.trim().parse().ok()?, d.trim().parse().ok()?);
+    if d == 0 {
+        None
+    } else {
+        Some(Ratio::new(n, d))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(src: &str) -> Sheet {
+        let opts = ReadOptions::default();
+        read_abc(src.as_bytes(), &opts).unwrap().movements.remove(0).sheet
+    }
+
+    fn len(ns: &[Note]) -> Ratio {
+        ns.iter().map(|n| n.dur()).sum()
+    }
+
+    // Of the notes that aren't rests, from C5.
+    fn steps(ns: &[Note]) -> Vec<i32> {
+        ns.iter().filter_map(|n| match n.pitch {
+            Pitch::Single(t) => Some(t.step),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn plays_repeats_and_endings() {
+        let sh = sheet("X:1\nT:Speed the Plough\nM:4/4\nL:1/8\nK:G\n\
+            |:GABc dedB|dedB dedB|c2ec B2dB|c2A2 A2BA|\n\
+            GABc dedB|dedB dedB|c2ec B2dB|1 A2F2 G4:|2 A2F2 G2z2||\n");
+        assert_eq!(sh.tracks.len(), 1);
+        // Seven bars and an ending, twice over.
+        assert!(len(&sh.tracks[0].notes) == Ratio::from_int(32));
+        assert!(sh.key == Key { fifths: 1, minor: false });
+    }
+
+    #[test]
+    fn keeps_bars_past_the_first_voice() {
+        let sh = sheet("X:1\nL:1/4\nK:C\nV:1\n|:CDEF:|\nV:2\nC4|D4|\n");
+        let (one, two) = (&sh.tracks[0].notes, &sh.tracks[1].notes);
+        assert!(len(one) == Ratio::from_int(6));
+        assert!(len(two) == Ratio::from_int(6));
+        assert_eq!(steps(two), vec![-7, -7, -6]);
+        assert!(one.last().unwrap().is_rest());
+    }
+
+    #[test]
+    fn overlays_start_from_the_bar() {
+        let sh = sheet("X:1\nL:1/4\nK:C\nCDEF & c2 d|G4|\n");
+        let notes = &sh.tracks[0].notes;
+        let vs = notes[0].as_voices().unwrap();
+        assert_eq!(vs.len(), 2);
+        assert_eq!(steps(&vs[0]), vec![-7, -6, -5, -4]);
+        assert_eq!(steps(&vs[1]), vec![0, 1]);
+        // Padded to the bar.
+        assert!(len(&vs[1]) == Ratio::from_int(2));
+        assert!(len(notes) == Ratio::from_int(4));
+    }
+
+    #[test]
+    fn reads_lower_case_tonics() {
+        let sh = sheet("X:1\nK:bm\nB|\n");
+        assert!(sh.key == Key { fifths: 2, minor: true });
+    }
+
+    #[test]
+    fn stops_at_lengths_too_short() {
+        let src = format!("X:1\nK:C\nC{}|\n", "/".repeat(64));
+        let e = read_abc(src.as_bytes(), &ReadOptions::default())
+            .err().unwrap();
+        assert_eq!((e.line, e.found.as_str()), (3, "one too short"));
+    }
+}
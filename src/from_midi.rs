@@ -0,0 +1,630 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::io::Read;
+use crate::notes::*;
+use crate::ratio::Ratio;
+use crate::notation::{mk_note, mk_rest};
+use crate::to_midi::{self, MF_VELOCITY, VAR_LEN_BYTES};
+
+// Standard MIDI Files (type 0 or 1) into a Sheet, with a staff for each
+// channel of each MIDI track that has notes. Positions within a 128th of a
+// 32nd or a sixteenth triplet are taken to be on it; the others stay as
+// they are, and so do lengths that make no single written value.
+
+#[derive(Debug)]
+pub struct MidiError {
+    // Offset in the file.
+    pub at: usize,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: expected {}, found {}",
+               self.at, self.expected, self.found)
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+type Res<T> = Result<T, MidiError>;
+
+fn fail<T>(at: usize, expected: &str, found: impl fmt::Display) -> Res<T> {
+    Err(MidiError {
+        at,
+        expected: expected.to_owned(),
+        found: found.to_string(),
+    })
+}
+
+pub fn load(path: impl AsRef<Path>) -> Res<Sheet> {
+    match fs::read(path) {
+        Ok(data) => read(&data),
+        Err(e) => fail(0, "a readable file", e),
+    }
+}
+
+pub fn read_smf(mut r: impl Read) -> Res<Sheet> {
+    let mut data = vec![];
+    if let Err(e) = r.read_to_end(&mut data) {
+        return fail(0, "a readable file", e);
+    }
+    read(&data)
+}
+
+struct Bytes<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, n: usize, expected: &str) -> Res<&'a [u8]> {
+        if self.data.len() - self.at < n {
+            return fail(self.data.len(), expected, "the end of the file");
+        }
+        self.at += n;
+        Ok(&self.data[self.at - n..self.at])
+    }
+
+    fn byte(&mut self, expected: &str) -> Res<u8> {
+        Ok(self.take(1, expected)?[0])
+    }
+
+    fn u16(&mut self, expected: &str) -> Res<u16> {
+        let b = self.take(2, expected)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self, expected: &str) -> Res<u32> {
+        let b = self.take(4, expected)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn var_len(&mut self) -> Res<u32> {
+        let rest = &self.data[self.at..];
+        match to_midi::read_var_len(rest) {
+            Some((x, n)) => {
+                self.at += n;
+                Ok(x)
+            }
+            None if rest.len() < VAR_LEN_BYTES => {
+                fail(self.data.len(), "a variable-length number",
+                     "the end of the file")
+            }
+            None => {
+                self.at += VAR_LEN_BYTES;
+                fail(self.at, "a variable-length number of up to 4 bytes",
+                     "more")
+            }
+        }
+    }
+
+    // The kind and the data of the next chunk.
+    fn chunk(&mut self) -> Res<(&'a [u8], Bytes<'a>)> {
+        let kind = self.take(4, "a chunk")?;
+        let len = self.u32("a chunk length")? as usize;
+        let start = self.at;
+        let data = self.take(len, "the rest of the chunk")?;
+        Ok((kind, Bytes { data: &self.data[..start + data.len()], at: start }))
+    }
+}
+
+// Everything but the notes is in ticks.
+#[derive(Default)]
+struct MidiTrack {
+    name: Option<String>,
+    notes: Vec<MidiNote>,
+    // The first program of each channel.
+    programs: HashMap<u8, u8>,
+    // Channel, tick, controller and value of the pedals.
+    pedals: Vec<(u8, i64, u8, u8)>,
+}
+
+struct MidiNote {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    on: i64,
+    off: i64,
+}
+
+// Tempo changes (microseconds a quarter), meters and keys, from all tracks.
+#[derive(Default)]
+struct Conductor {
+    tempo: Vec<(i64, u32)>,
+    meters: Vec<(i64, Meter)>,
+    keys: Vec<(i64, Key)>,
+}
+
+fn read(data: &[u8]) -> Res<Sheet> {
+    if !data.starts_with(b"MThd") {
+        let kind = &data[..data.len().min(4)];
+        return fail(0, "a MIDI file (MThd)", String::from_utf8_lossy(kind));
+    }
+    let mut b = Bytes { data, at: 0 };
+    let (_, mut h) = b.chunk()?;
+    let format = h.u16("the file format")?;
+    if format > 1 {
+        return fail(h.at - 2, "a type 0 or 1 file", format!("type {}", format));
+    }
+    let count = h.u16("the number of tracks")?;
+    let division = h.u16("the division")?;
+    // Ticks in a half note, which is 1 (see Duration::dur).
+    let per_unit = if division & 0x8000 != 0 {
+        // Frames a second and ticks a frame. With no tempo to go by, a
+        // second is a half note at 120.
+        let fps = -((division >> 8) as u8 as i8) as i64;
+        fps * (division & 0xff) as i64
+    } else {
+        2 * division as i64
+    };
+    if per_unit <= 0 {
+        return fail(h.at - 2, "a positive division", division);
+    }
+    let smpte = division & 0x8000 != 0;
+
+    let mut tracks = vec![];
+    let mut cond = Conductor::default();
+    while tracks.len() < count as usize && b.at < data.len() {
+        let (kind, mut t) = b.chunk()?;
+        // Chunks of other kinds are to be skipped.
+        if kind == b"MTrk" {
+            tracks.push(read_track(&mut t, &mut cond, smpte)?);
+        }
+    }
+
+    let pos = |tick: i64| snap(Ratio::new(tick, per_unit));
+    cond.tempo.sort_by_key(|x| x.0);
+    let mut points: Vec<Tempo> = vec![];
+    for &(tick, usecs) in &cond.tempo {
+        let at = pos(tick).to_f64();
+        if points.last().is_some_and(|p| p.at == at) {
+            points.pop();
+        }
+        points.push(Tempo { at, bpm: bpm_of(usecs), ramp: false });
+    }
+    cond.meters.sort_by_key(|x| x.0);
+    cond.keys.sort_by_key(|x| x.0);
+    let meter = cond.meters.first().filter(|x| x.0 == 0)
+        .map_or(Meter::default(), |x| x.1);
+    let key = cond.keys.first().filter(|x| x.0 == 0)
+        .map_or(Key::default(), |x| x.1);
+    let key_at = |tick| {
+        cond.keys.iter().take_while(|x| x.0 <= tick).last().map_or(key, |x| x.1)
+    };
+
+    let mut staves = vec![];
+    for (i, t) in tracks.iter().enumerate() {
+        let mut channels: Vec<u8> = t.notes.iter().map(|n| n.channel).collect();
+        channels.sort();
+        channels.dedup();
+        let name = t.name.clone().unwrap_or_else(|| format!("track {}", i + 1));
+        for &ch in &channels {
+            // Drums have no pitch to play.
+            if ch == 9 {
+                continue;
+            }
+            let spans: Vec<Span> = t.notes.iter()
+                .filter(|n| n.channel == ch)
+                .map(|n| {
+                    // Only where they start is on the beat.
+                    let on = pos(n.on);
+                    let off = Ratio::new(n.off, per_unit)
+                        .max(on + Ratio::new(1, 64));
+                    Span {
+                        on,
+                        off,
+                        tones: vec![spell(n.key, key_at(n.on))],
+                        amp: velocity_amp(n.velocity),
+                    }
+                })
+                .collect();
+            let mut pedal: Vec<Pedal> = vec![];
+            let (mut sustain, mut una_corda) = (0., false);
+            for &(_, tick, cc, value) in t.pedals.iter().filter(|p| p.0 == ch) {
+                match cc {
+                    64 => sustain = value as f32 / 127.,
+                    _ => una_corda = value >= 64,
+                }
+                let p = Pedal { at: pos(tick), sustain, una_corda };
+                match pedal.last_mut() {
+                    Some(last) if last.sustain == sustain
+                        && last.una_corda == una_corda => (),
+                    Some(last) if last.at == p.at => *last = p,
+                    _ => pedal.push(p),
+                }
+            }
+            let instrument = match t.programs.get(&ch) {
+                Some(40) => Instrument::Violin,
+                Some(41) => Instrument::Viola,
+                Some(42) => Instrument::Cello,
+                // Choir aahs, voice oohs and synth voice.
+                Some(52..=54) => Instrument::Voice,
+                _ => Instrument::Piano,
+            };
+            let name = if channels.len() > 1 {
+                format!("{} channel {}", name, ch + 1)
+            } else {
+                name.clone()
+            };
+            staves.push((name, instrument, group(spans), pedal));
+        }
+    }
+    if staves.is_empty() {
+        return fail(data.len(), "notes", "none");
+    }
+
+    // Staves go on to the end of the last bar together.
+    let last = staves.iter()
+        .flat_map(|s| s.2.iter().map(|x| x.off))
+        .max()
+        .unwrap_or_default();
+    let bars = last / meter.bar_dur();
+    let bars = (bars.numer() + bars.denom() - 1) / bars.denom();
+    let end = meter.bar_dur() * Ratio::from_int(bars);
+    let tracks = staves.into_iter().map(|(name, instrument, spans, pedal)| {
+        Track { name, instrument, notes: spans_to_notes(spans, end), pedal }
+    }).collect();
+    Ok(Sheet { tracks, tempo: TempoMap { points }, meter, key })
+}
+
+fn read_track(b: &mut Bytes, cond: &mut Conductor, smpte: bool)
+    -> Res<MidiTrack> {
+
+    let mut out = MidiTrack::default();
+    // Notes still sounding, by channel and key, the earliest first.
+    let mut open: HashMap<(u8, u8), VecDeque<(i64, u8)>> = HashMap::new();
+    let mut tick = 0;
+    let mut status = None;
+    while b.at < b.data.len() {
+        tick += b.var_len()? as i64;
+        let at = b.at;
+        let first = b.byte("an event")?;
+        let st = if first & 0x80 != 0 {
+            first
+        } else {
+            // Running status: the data of another event like the last.
+            b.at -= 1;
+            match status {
+                Some(st) => st,
+                None => return fail(at, "a status byte", first),
+            }
+        };
+
+        if st == 0xff {
+            status = None;
+            let kind = b.byte("a meta event")?;
+            let len = b.var_len()? as usize;
+            let data = b.take(len, "the meta event's data")?;
+            match (kind, data) {
+                (0x2f, _) => break,
+                (0x03, _) => if out.name.is_none() {
+                    out.name = Some(String::from_utf8_lossy(data).into_owned());
+                },
+                (0x51, &[x, y, z]) => if !smpte {
+                    let usecs = u32::from_be_bytes([0, x, y, z]);
+                    if usecs > 0 {
+                        cond.tempo.push((tick, usecs));
+                    }
+                },
+                (0x58, &[beats, unit, ..]) if beats > 0 && unit < 7 => {
+                    let meter = Meter { beats: beats as i32, unit: 1 << unit };
+                    cond.meters.push((tick, meter));
+                }
+                (0x59, &[fifths, minor]) => {
+                    let fifths = (fifths as i8).clamp(-7, 7) as i32;
+                    cond.keys.push((tick, Key { fifths, minor: minor == 1 }));
+                }
+                _ => (),
+            }
+            continue;
+        }
+        if st == 0xf0 || st == 0xf7 {
+            status = None;
+            let len = b.var_len()? as usize;
+            b.take(len, "the system exclusive data")?;
+            continue;
+        }
+        status = Some(st);
+
+        let ch = st & 0x0f;
+        match st >> 4 {
+            0x8 | 0x9 => {
+                let key = b.byte("a key")?;
+                let velocity = b.byte("a velocity")?;
+                let notes = open.entry((ch, key)).or_default();
+                if st >> 4 == 0x9 && velocity > 0 {
+                    notes.push_back((tick, velocity));
+                } else if let Some((on, velocity)) = notes.pop_front() {
+                    out.notes.push(MidiNote {
+                        channel: ch,
+                        key,
+                        velocity,
+                        on,
+                        off: tick,
+                    });
+                }
+            }
+            0xb => {
+                let cc = b.byte("a controller")?;
+                let value = b.byte("a controller value")?;
+                // Sustain and soft pedals.
+                if cc == 64 || cc == 67 {
+                    out.pedals.push((ch, tick, cc, value));
+                }
+            }
+            0xc => {
+                let program = b.byte("a program")?;
+                out.programs.entry(ch).or_insert(program);
+            }
+            0xa | 0xe => {
+                b.take(2, "the event's data")?;
+            }
+            0xd => {
+                b.byte("a pressure")?;
+            }
+            _ => return fail(at, "an event", format!("{:#x}", st)),
+        }
+    }
+    // Whatever is left sounding ends with the track.
+    for ((ch, key), notes) in open {
+        for (on, velocity) in notes {
+            let off = tick;
+            out.notes.push(MidiNote { channel: ch, key, velocity, on, off });
+        }
+    }
+    out.notes.sort_by_key(|n| (n.on, n.key));
+    Ok(out)
+}
+
+// To the nearest 32nd or sixteenth triplet, if within a 128th of it. What
+// is already on a 128th or a 64th triplet was written that way.
+fn snap(x: Ratio) -> Ratio {
+    if (x * Ratio::from_int(64)).denom() == 1
+        || (x * Ratio::from_int(48)).denom() == 1 {
+        return x;
+    }
+    let near = |grid: Ratio| {
+        let n = (x / grid).to_f64().round() as i64;
+        grid * Ratio::from_int(n)
+    };
+    let dist = |y: Ratio| if y > x { y - x } else { x - y };
+    let best = [near(Ratio::new(1, 16)), near(Ratio::new(1, 12))]
+        .iter()
+        .cloned()
+        .min_by_key(|&y| dist(y))
+        .unwrap();
+    if dist(best) <= Ratio::new(1, 64) {
+        best
+    } else {
+        x
+    }
+}
+
+// The roundest tempo that comes to the same microseconds a quarter.
+fn bpm_of(usecs: u32) -> f64 {
+    let bpm = 60_000_000. / usecs as f64;
+    for &scale in &[1., 10., 100., 1000.] {
+        let x = (bpm * scale).round() / scale;
+        if (60_000_000. / x).round() as u32 == usecs {
+            return x;
+        }
+    }
+    bpm
+}
+
+fn velocity_amp(velocity: u8) -> f32 {
+    velocity as f32 / MF_VELOCITY
+}
+
+// The spelling the key would use: its own sharps or flats, then naturals,
+// then sharps in sharp keys and flats in flat ones.
+fn spell(key: u8, k: Key) -> Tone {
+    const SEMITONES: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+    let key = key as i32;
+    let mut cands = vec![];
+    for degree in 0..7 {
+        for sharp in -1..=1 {
+            if (SEMITONES[degree as usize] + sharp - key).rem_euclid(12) == 0 {
+                cands.push((degree, sharp));
+            }
+        }
+    }
+    let leaning = if k.fifths < 0 { -1 } else { 1 };
+    let (degree, sharp) = cands.iter()
+        .find(|x| x.1 != 0 && k.sharps_for(x.0) == x.1)
+        .or_else(|| cands.iter().find(|x| x.1 == 0 && k.sharps_for(x.0) == 0))
+        .or_else(|| cands.iter().find(|x| x.1 == leaning))
+        .cloned()
+        .unwrap_or(cands[0]);
+    // C5 is 72.
+    let octave = (key - sharp - SEMITONES[degree as usize] - 72).div_euclid(12);
+    Tone::new(octave * 7 + degree, sharp)
+}
+
+// Notes starting together, in the unit of Duration::dur.
+struct Span {
+    on: Ratio,
+    off: Ratio,
+    tones: Vec<Tone>,
+    amp: f32,
+}
+
+// Notes that start and end together make a chord.
+fn group(mut spans: Vec<Span>) -> Vec<Span> {
+    spans.sort_by(|x, y| (x.on, y.off).cmp(&(y.on, x.off)));
+    let mut out: Vec<Span> = vec![];
+    // Notes so far in the last chord, for the mean amplitude.
+    let mut count = 1.;
+    for s in spans {
+        match out.last_mut() {
+            Some(last) if last.on == s.on && last.off == s.off => {
+                if !last.tones.contains(&s.tones[0]) {
+                    last.tones.push(s.tones[0]);
+                }
+                last.amp = (last.amp * count + s.amp) / (count + 1.);
+                count += 1.;
+            }
+            _ => {
+                out.push(s);
+                count = 1.;
+            }
+        }
+    }
+    out
+}
+
+// Where nothing is held over, the notes go one after another. Elsewhere
+// they are split into voices, each taking the first that's free.
+fn spans_to_notes(spans: Vec<Span>, end: Ratio) -> Vec<Note> {
+    let mut out = vec![];
+    let first = spans.first().map_or(end, |s| s.on);
+    if first > Ratio::zero() {
+        out.push(mk_rest(Duration::from_len(first)));
+    }
+    let mut spans = spans.into_iter().peekable();
+    while let Some(s) = spans.next() {
+        let start = s.on;
+        let mut reach = s.off;
+        let mut part = vec![s];
+        while let Some(next) = spans.peek() {
+            if next.on >= reach {
+                break;
+            }
+            reach = reach.max(next.off);
+            part.push(spans.next().unwrap());
+        }
+        let stop = spans.peek().map_or(end, |s| s.on);
+
+        let mut lines: Vec<Vec<Span>> = vec![];
+        for s in part {
+            let free = lines.iter().position(|l| l.last().unwrap().off <= s.on);
+            match free {
+                Some(i) => lines[i].push(s),
+                None => lines.push(vec![s]),
+            }
+        }
+        if lines.len() == 1 {
+            out.extend(line_notes(lines.pop().unwrap(), start, stop));
+        } else {
+            let vs = lines.into_iter()
+                .map(|l| line_notes(l, start, stop))
+                .collect();
+            let d = Duration::from_len(stop - start);
+            out.push(mk_note(d, Pitch::Voices(vs)));
+        }
+    }
+    out
+}
+
+// Spans that don't overlap, from start to stop. A note lasts until the next
+// one, unless it's let go before half of that: then it is the shortest
+// power of two wholes, halves, quarters and so on that holds it, and a
+// rest.
+fn line_notes(spans: Vec<Span>, start: Ratio, stop: Ratio) -> Vec<Note> {
+    let mut out = vec![];
+    let mut at = start;
+    for i in 0..spans.len() {
+        let s = &spans[i];
+        if s.on > at {
+            out.push(mk_rest(Duration::from_len(s.on - at)));
+        }
+        let next = spans.get(i + 1).map_or(stop, |x| x.on);
+        let len = next - s.on;
+        let sounding = s.off - s.on;
+        let two = Ratio::from_int(2);
+        let mut written = len;
+        if sounding * two <= len {
+            // Less than twice the sounding part, so still within len.
+            written = two;
+            while written < sounding {
+                written = written * two;
+            }
+            while sounding * two <= written {
+                written = written / two;
+            }
+        }
+        let pitch = match &s.tones[..] {
+            [t] => Pitch::Single(*t),
+            ts => Pitch::Chord(ts.to_vec()),
+        };
+        let mut n = mk_note(Duration::from_len(written), pitch);
+        n.amp = s.amp;
+        n.rest_after = ((written - sounding) / written).to_f64();
+        out.push(n);
+        if written < len {
+            out.push(mk_rest(Duration::from_len(len - written)));
+        }
+        at = next;
+    }
+    if at < stop {
+        out.push(mk_rest(Duration::from_len(stop - at)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Type 0, 96 ticks a quarter: C4 and D4 as quarters, the second
+    // note on in running status and each note off as a velocity of 0.
+    const TWO_QUARTERS: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 24,
+        0, 0xff, 0x51, 3, 0x07, 0xa1, 0x20,
+        0, 0x90, 60, 64,
+        96, 60, 0,
+        0, 62, 64,
+        96, 62, 0,
+        0, 0xff, 0x2f, 0,
+    ];
+
+    #[test]
+    fn reads_running_status() {
+        let sh = read(TWO_QUARTERS).unwrap();
+        assert_eq!(sh.tracks.len(), 1);
+        let notes: Vec<_> = sh.tracks[0].notes.iter()
+            .filter(|n| !n.is_rest())
+            .map(|n| match n.pitch {
+                Pitch::Single(t) => (t.midi_key(), n.dur()),
+                _ => panic!("a single tone"),
+            })
+            .collect();
+        let quarter = Ratio::new(1, 2);
+        assert_eq!(notes, vec![(60, quarter), (62, quarter)]);
+        assert_eq!(sh.tempo.points[0].bpm, 120.);
+    }
+
+    #[test]
+    fn holds_a_note_over_two_bars() {
+        // C4 held five quarters, D4 twelve quarters after it starts.
+        let midi = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 26,
+            0, 0xff, 0x51, 3, 0x07, 0xa1, 0x20,
+            0, 0x90, 60, 64,
+            0x83, 0x60, 60, 0,
+            0x85, 0x20, 62, 64,
+            96, 62, 0,
+            0, 0xff, 0x2f, 0,
+        ];
+        let sh = read(&midi).unwrap();
+        let notes = &sh.tracks[0].notes;
+        let c = &notes[0];
+        assert_eq!(c.dur(), Ratio::from_int(4));
+        assert_eq!(c.rest_after, 0.375);
+        let total = notes.iter().fold(Ratio::zero(), |a, n| a + n.dur());
+        // Four bars, the last filled out after D4.
+        assert_eq!(total, Ratio::from_int(8));
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_file() {
+        let e = read(&TWO_QUARTERS[..30]).err().unwrap();
+        assert_eq!(e.at, 30);
+    }
+}
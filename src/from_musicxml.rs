@@ -0,0 +1,1540 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+use roxmltree::{Document, Node, ParsingOptions};
+use crate::notes::*;
+use crate::ratio::Ratio;
+use crate::notation::{self, mk_note, mk_rest, shape_note, ReadOptions};
+use crate::notation::{DYNAMICS, SFORZANDO};
+use crate::repeats::{self, Flow};
+use crate::style::{Articulation, Style};
+
+// MusicXML scores (partwise, plain or compressed as .mxl) into a Sheet,
+// with a track for each staff of each part. Repeats are played out as the
+// options say. What a Sheet has no place for is left out and listed, once
+// a part, with the first measure it's in.
+
+#[derive(Debug)]
+pub struct XmlError {
+    pub part: Option<String>,
+    // The measure number as written.
+    pub measure: Option<String>,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.measure, &self.part) {
+            (Some(m), Some(p)) => write!(f, "measure {} ({}): ", m, p)?,
+            (None, Some(p)) => write!(f, "{}: ", p)?,
+            _ => (),
+        }
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for XmlError {}
+
+// Something in the score that was left out, such as lyrics or a fermata.
+pub struct Unsupported {
+    pub part: String,
+    pub measure: String,
+    pub what: String,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "measure {} ({}): left out {}",
+               self.measure, self.part, self.what)
+    }
+}
+
+pub struct Imported {
+    pub sheet: Sheet,
+    pub unsupported: Vec<Unsupported>,
+}
+
+type Res<T> = Result<T, XmlError>;
+
+fn fail<T>(expected: &str, found: impl fmt::Display) -> Res<T> {
+    Err(XmlError {
+        part: None,
+        measure: None,
+        expected: expected.to_owned(),
+        found: found.to_string(),
+    })
+}
+
+pub fn load(path: impl AsRef<Path>, opts: &ReadOptions) -> Res<Imported> {
+    match fs::read(path) {
+        Ok(data) => read(data, opts),
+        Err(e) => fail("a readable file", e),
+    }
+}
+
+pub fn read_musicxml(mut r: impl Read, opts: &ReadOptions)
+    -> Res<Imported> {
+    let mut data = vec![];
+    if let Err(e) = r.read_to_end(&mut data) {
+        return fail("a readable file", e);
+    }
+    read(data, opts)
+}
+
+fn read(data: Vec<u8>, opts: &ReadOptions) -> Res<Imported> {
+    // .mxl files are zip archives.
+    let text = if data.starts_with(b"PK") {
+        unzip(&data)?
+    } else {
+        text(data)?
+    };
+    let xml_opts = ParsingOptions { allow_dtd: true, ..Default::default() };
+    match Document::parse_with_options(&text, xml_opts) {
+        Ok(doc) => read_score(doc.root_element(), opts),
+        Err(e) => fail("well-formed XML", e),
+    }
+}
+
+// UTF-8, or UTF-16 with a byte order mark.
+fn text(data: Vec<u8>) -> Res<String> {
+    let utf16 = |be: bool| {
+        let units: Vec<u16> = data[2..].chunks(2)
+            .filter(|x| x.len() == 2)
+            .map(|x| if be {
+                u16::from_be_bytes([x[0], x[1]])
+            } else {
+                u16::from_le_bytes([x[0], x[1]])
+            })
+            .collect();
+        String::from_utf16(&units).ok()
+    };
+    let s = match data.get(..2) {
+        Some(&[0xfe, 0xff]) => utf16(true),
+        Some(&[0xff, 0xfe]) => utf16(false),
+        _ => String::from_utf8(data).ok(),
+    };
+    match s {
+        Some(s) => Ok(s.trim_start_matches('\u{feff}').to_owned()),
+        None => fail("UTF-8 or UTF-16 text", "other bytes"),
+    }
+}
+
+type Archive<'a> = zip::ZipArchive<Cursor<&'a [u8]>>;
+
+// The score META-INF/container.xml points to, or else the first XML file
+// outside META-INF.
+fn unzip(data: &[u8]) -> Res<String> {
+    let mut zip = match zip::ZipArchive::new(Cursor::new(data)) {
+        Ok(zip) => zip,
+        Err(e) => return fail("an .mxl archive", e),
+    };
+    let mut path = zip.file_names()
+        .find(|x| !x.starts_with("META-INF/")
+              && (x.ends_with(".xml") || x.ends_with(".musicxml")))
+        .map(|x| x.to_owned());
+    if let Ok(container) = read_entry(&mut zip, "META-INF/container.xml") {
+        if let Ok(doc) = Document::parse(&container) {
+            let root = doc.descendants()
+                .find(|x| x.has_tag_name("rootfile"))
+                .and_then(|x| x.attribute("full-path"));
+            if let Some(root) = root {
+                path = Some(root.to_owned());
+            }
+        }
+    }
+    match path {
+        Some(path) => read_entry(&mut zip, &path),
+        None => fail("a score in the .mxl archive", "none"),
+    }
+}
+
+fn read_entry(zip: &mut Archive, name: &str) -> Res<String> {
+    let mut data = vec![];
+    let read = match zip.by_name(name) {
+        Ok(mut f) => f.read_to_end(&mut data).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = read {
+        return fail(&format!("{} in the .mxl archive", name), e);
+    }
+    text(data)
+}
+
+fn child<'a, 'i>(n: Node<'a, 'i>, tag: &str) -> Option<Node<'a, 'i>> {
+    n.children().find(|x| x.has_tag_name(tag))
+}
+
+fn children<'a, 'i: 'a>(n: Node<'a, 'i>, tag: &'a str)
+    -> impl Iterator<Item = Node<'a, 'i>> {
+    n.children().filter(move |x| x.has_tag_name(tag))
+}
+
+fn elements<'a, 'i: 'a>(n: Node<'a, 'i>)
+    -> impl Iterator<Item = Node<'a, 'i>> {
+    n.children().filter(|x| x.is_element())
+}
+
+fn text_of<'a>(n: Node<'a, '_>, tag: &str) -> Option<&'a str> {
+    child(n, tag).and_then(|x| x.text()).map(|x| x.trim())
+}
+
+fn number<T: FromStr>(n: Node, tag: &str) -> Res<Option<T>> {
+    match text_of(n, tag) {
+        None => Ok(None),
+        Some(s) => match s.parse() {
+            Ok(x) => Ok(Some(x)),
+            Err(_) => fail(&format!("a number in <{}>", tag), s),
+        },
+    }
+}
+
+// From the part list.
+struct PartInfo {
+    name: Option<String>,
+    instrument: Option<Instrument>,
+}
+
+fn read_part_info(sp: Node) -> PartInfo {
+    // General MIDI programs, from 1.
+    let program = sp.descendants()
+        .find(|x| x.has_tag_name("midi-program"))
+        .and_then(|x| x.text())
+        .and_then(|x| x.trim().parse::<u8>().ok());
+    let name = text_of(sp, "part-name")
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_owned());
+    let mut names: Vec<String> = sp.descendants()
+        .filter(|x| x.has_tag_name("instrument-name"))
+        .filter_map(|x| x.text())
+        .map(|x| x.to_lowercase())
+        .collect();
+    names.extend(name.iter().map(|x| x.to_lowercase()));
+    let named = |words: &[&str]| {
+        names.iter().any(|x| words.iter().any(|w| x.contains(w)))
+    };
+    let instrument = match program {
+        Some(1..=8) => Some(Instrument::Piano),
+        Some(41) => Some(Instrument::Violin),
+        Some(42) => Some(Instrument::Viola),
+        Some(43) => Some(Instrument::Cello),
+        Some(53..=55) => Some(Instrument::Voice),
+        _ if named(&["piano", "keyboard"]) => Some(Instrument::Piano),
+        _ if named(&["violin"]) => Some(Instrument::Violin),
+        _ if named(&["viola"]) => Some(Instrument::Viola),
+        _ if named(&["cello"]) => Some(Instrument::Cello),
+        _ if named(&["voice", "soprano", "mezzo", "alto", "tenor",
+                       "baritone", "choir"]) => Some(Instrument::Voice),
+        _ => None,
+    };
+    PartInfo { name, instrument }
+}
+
+// What the parts have in common.
+struct Shared {
+    meter: Option<Meter>,
+    key: Option<Key>,
+    tempo: Vec<Tempo>,
+    unsupported: Vec<Unsupported>,
+}
+
+fn read_score(root: Node, opts: &ReadOptions) -> Res<Imported> {
+    match root.tag_name().name() {
+        "score-partwise" => (),
+        "score-timewise" => return fail("a partwise score", "a timewise one"),
+        other => return fail("a MusicXML score", format!("<{}>", other)),
+    }
+    let mut infos = HashMap::new();
+    for list in children(root, "part-list") {
+        for sp in children(list, "score-part") {
+            if let Some(id) = sp.attribute("id") {
+                infos.insert(id, read_part_info(sp));
+            }
+        }
+    }
+    let parts: Vec<Node> = children(root, "part").collect();
+    if parts.is_empty() {
+        return fail("a <part>", "none");
+    }
+    // The repeats of the first part are those of the others too.
+    let first: Vec<Node> = children(parts[0], "measure").collect();
+    let order = repeats::expand(&flow_of(&first), opts.repeats);
+
+    let mut shared = Shared {
+        meter: None,
+        key: None,
+        tempo: vec![],
+        unsupported: vec![],
+    };
+    let mut tracks = vec![];
+    for part in &parts {
+        let id = part.attribute("id").unwrap_or("");
+        let info = infos.get(id);
+        let name = info.and_then(|x| x.name.clone())
+            .unwrap_or_else(|| id.to_owned());
+        let measures: Vec<Node> = children(*part, "measure").collect();
+        let mut p = PartReader::new(name, &opts.style, &mut shared);
+        let locate = |mut e: XmlError, p: &PartReader| {
+            e.part = Some(p.name.clone());
+            if !p.measure.is_empty() {
+                e.measure = Some(p.measure.clone());
+            }
+            e
+        };
+        if measures.len() != first.len() {
+            let e = XmlError {
+                part: None,
+                measure: None,
+                expected: format!("{} measures, as in the first part",
+                                  first.len()),
+                found: measures.len().to_string(),
+            };
+            return Err(locate(e, &p));
+        }
+        match info.and_then(|x| x.instrument) {
+            Some(x) => p.instrument = x,
+            None => {
+                p.measure = first.first()
+                    .and_then(|m| m.attribute("number"))
+                    .unwrap_or("1")
+                    .to_owned();
+                p.skip("the instrument, played as a piano");
+            }
+        }
+        for &i in &order {
+            if let Err(e) = p.read_measure(measures[i]) {
+                return Err(locate(e, &p));
+            }
+        }
+        let only = parts.len() == 1;
+        tracks.extend(p.into_tracks(only));
+    }
+
+    // Parts that stop early rest until the end.
+    let len = |t: &Track| t.notes.iter().map(|n| n.dur()).sum::<Ratio>();
+    let end = tracks.iter().map(len).max().unwrap_or_default();
+    for t in &mut tracks {
+        let short = end - len(t);
+        if short > Ratio::zero() {
+            t.notes.push(mk_rest(Duration::from_len(short)));
+        }
+    }
+
+    shared.tempo.sort_by(|x, y| x.at.partial_cmp(&y.at).unwrap());
+    let mut points: Vec<Tempo> = vec![];
+    for t in shared.tempo {
+        match points.last() {
+            Some(last) if last.at == t.at || last.bpm == t.bpm => (),
+            _ => points.push(t),
+        }
+    }
+    let sheet = Sheet {
+        tracks,
+        tempo: TempoMap { points },
+        meter: shared.meter.unwrap_or_default(),
+        key: shared.key.unwrap_or_default(),
+    };
+    Ok(Imported { sheet, unsupported: shared.unsupported })
+}
+
+// Repeats, voltas and jumps, around the measures by number from 0.
+fn flow_of(measures: &[Node]) -> Vec<Flow> {
+    let mut flow = vec![];
+    for (i, m) in measures.iter().enumerate() {
+        let mut after = vec![];
+        for b in children(*m, "barline") {
+            if let Some(r) = child(b, "repeat") {
+                match r.attribute("direction") {
+                    Some("forward") => flow.push(Flow::RepeatStart),
+                    Some("backward") => {
+                        let times = r.attribute("times")
+                            .and_then(|x| x.parse().ok());
+                        after.push(Flow::RepeatEnd(times));
+                    }
+                    _ => (),
+                }
+            }
+            let ending = child(b, "ending")
+                .filter(|x| x.attribute("type") == Some("start"));
+            if let Some(e) = ending {
+                // Such as "1" or "1, 2".
+                let ns: Vec<u32> = e.attribute("number").unwrap_or("1")
+                    .split([',', ' '])
+                    .filter_map(|x| x.trim().parse().ok())
+                    .collect();
+                if !ns.is_empty() {
+                    flow.push(Flow::Ending(ns));
+                }
+            }
+        }
+        for s in m.descendants().filter(|x| x.has_tag_name("sound")) {
+            let has = |attr| s.attribute(attr).is_some();
+            if has("segno") {
+                flow.push(Flow::Segno);
+            }
+            if has("coda") {
+                flow.push(Flow::Coda);
+            }
+            if has("tocoda") {
+                after.push(Flow::ToCoda);
+            }
+            if has("fine") {
+                after.push(Flow::Fine);
+            }
+            if s.attribute("dacapo") == Some("yes") {
+                after.push(Flow::Jump { segno: false, coda: false });
+            }
+            if has("dalsegno") {
+                after.push(Flow::Jump { segno: true, coda: false });
+            }
+        }
+        flow.push(Flow::System(i));
+        flow.extend(after);
+    }
+    // Al coda if there is a coda to go to.
+    let al_coda = flow.contains(&Flow::ToCoda);
+    for f in &mut flow {
+        if let Flow::Jump { coda, .. } = f {
+            *coda = al_coda;
+        }
+    }
+    flow
+}
+
+// A note or a chord of one voice, from `on` in the whole part.
+struct Entry {
+    on: Ratio,
+    note: Note,
+    arts: Vec<Articulation>,
+    // Under a slur, and whether the slur ends on it.
+    slur: Option<bool>,
+    orn: Option<Ornament>,
+}
+
+// What the reader of .ss files plays as several notes.
+enum Ornament {
+    // The upper note, and how many notes are played.
+    Trill(Tone, usize),
+    // Whether they are slashed, and the notes as written.
+    Graces(bool, Vec<Note>),
+}
+
+#[derive(Default)]
+struct StaffNotes {
+    // For each measure read, the notes of each voice in it.
+    bars: Vec<Vec<(String, Vec<Entry>)>>,
+    // Amplitudes by position, in the order written.
+    dynamics: Vec<(Ratio, f32)>,
+    // One-shot multipliers for the next note (sf, fp).
+    accents: Vec<(Ratio, f32)>,
+    // Hairpins as start, stop and whether they go up, and one still open.
+    hairpins: Vec<(Ratio, Ratio, bool)>,
+    wedge: Option<(Ratio, bool)>,
+    pedal: Vec<Pedal>,
+    sustain: f32,
+    una_corda: bool,
+}
+
+// Articulations, the slur as an Entry has it, and a trill: how many notes
+// are played, and the alter of its upper note if marked.
+type Notations =
+    (Vec<Articulation>, Option<bool>, Option<(usize, Option<i32>)>);
+
+// The dynamic in force at a position, from those sorted by position.
+fn level_at(dynamics: &[(Ratio, f32)], at: Ratio) -> f32 {
+    dynamics.iter()
+        .take_while(|x| x.0 <= at)
+        .last()
+        .map_or(1., |x| x.1)
+}
+
+struct PartReader<'a> {
+    name: String,
+    instrument: Instrument,
+    style: &'a Style,
+    shared: &'a mut Shared,
+    // The measure being read, as numbered in the file.
+    measure: String,
+    // Start and length of each measure read, in the unit of Duration::dur.
+    bars: Vec<(Ratio, Ratio)>,
+    // Their numbers, for what's left out once they are read.
+    numbers: Vec<String>,
+    staves: Vec<StaffNotes>,
+    // Per quarter.
+    divisions: i64,
+    meter: Option<Meter>,
+    key: Option<Key>,
+    // Slurs still open, by staff and voice.
+    slurs: HashMap<(usize, String), Vec<String>>,
+    // Accidentals so far in the measure, by staff and step.
+    sharps: HashMap<(usize, i32), i32>,
+    // Grace notes waiting for their note, by staff and voice.
+    graces: HashMap<(usize, String), (bool, Vec<Note>)>,
+}
+
+// Elements of a note that only say how it looks, or that are read with
+// others.
+const NOTE_LOOKS: [&str; 20] = [
+    "pitch", "rest", "unpitched", "duration", "tie", "voice", "type", "dot",
+    "accidental", "time-modification", "stem", "notehead", "notehead-text",
+    "staff", "beam", "notations", "chord", "instrument", "footnote", "level",
+];
+
+impl<'a> PartReader<'a> {
+    fn new(name: String, style: &'a Style, shared: &'a mut Shared) -> Self {
+        Self {
+            name,
+            instrument: Instrument::Piano,
+            style,
+            shared,
+            measure: String::new(),
+            bars: vec![],
+            numbers: vec![],
+            staves: vec![StaffNotes::default()],
+            divisions: 1,
+            meter: None,
+            key: None,
+            slurs: HashMap::new(),
+            sharps: HashMap::new(),
+            graces: HashMap::new(),
+        }
+    }
+
+    fn skip(&mut self, what: impl Into<String>) {
+        let what = what.into();
+        let name = &self.name;
+        if !self.shared.unsupported.iter()
+            .any(|x| &x.part == name && x.what == what) {
+            self.shared.unsupported.push(Unsupported {
+                part: self.name.clone(),
+                measure: self.measure.clone(),
+                what,
+            });
+        }
+    }
+
+    fn start(&self) -> Ratio {
+        self.bars.last().map_or(Ratio::zero(), |x| x.0 + x.1)
+    }
+
+    // The measure of a position, or of the end of one.
+    fn measure_at(&mut self, pos: Ratio) {
+        let i = self.bars.iter().position(|x| pos <= x.0 + x.1);
+        if let Some(i) = i {
+            self.measure = self.numbers[i].clone();
+        }
+    }
+
+    // Staves from 0, for <staff> from 1.
+    fn staff(&mut self, n: usize) -> usize {
+        while self.staves.len() < n {
+            let bars = self.staves[0].bars.iter().map(|_| vec![]).collect();
+            self.staves.push(StaffNotes { bars, ..Default::default() });
+        }
+        n.max(1) - 1
+    }
+
+    fn len_of(&self, n: Node) -> Res<Ratio> {
+        match number::<i64>(n, "duration")? {
+            Some(d) if d >= 0 => Ok(Ratio::new(d, 2 * self.divisions)),
+            Some(d) => fail("a duration of 0 or more", d),
+            None => fail("a <duration>", "none"),
+        }
+    }
+
+    fn read_measure(&mut self, m: Node) -> Res<()> {
+        self.measure = m.attribute("number").unwrap_or("?").to_owned();
+        for s in &mut self.staves {
+            s.bars.push(vec![]);
+        }
+        self.sharps.clear();
+        let start = self.start();
+        let mut at = Ratio::zero();
+        let mut len = Ratio::zero();
+        // Staff and voice of the last note, which a chord adds to.
+        let mut last = None;
+        for c in elements(m) {
+            match c.tag_name().name() {
+                "note" => self.read_note(c, start + at, &mut at, &mut last)?,
+                "backup" => {
+                    let back = self.len_of(c)?;
+                    at = if back > at { Ratio::zero() } else { at - back };
+                }
+                "forward" => at += self.len_of(c)?,
+                "attributes" => self.read_attributes(c)?,
+                "direction" => self.read_direction(c, start + at)?,
+                "sound" => self.read_sound(c, start + at)?,
+                "barline" => {
+                    if child(c, "fermata").is_some() {
+                        self.skip("fermatas");
+                    }
+                }
+                "print" | "bookmark" | "link" | "grouping" => (),
+                other => self.skip(format!("<{}>", other)),
+            }
+            len = len.max(at);
+        }
+        if len.is_zero() {
+            len = self.meter.unwrap_or_default().bar_dur();
+        }
+        self.bars.push((start, len));
+        self.numbers.push(self.measure.clone());
+        Ok(())
+    }
+
+    fn read_note(&mut self, c: Node, on: Ratio, at: &mut Ratio,
+                 last: &mut Option<(usize, usize)>) -> Res<()> {
+        if child(c, "cue").is_some() {
+            self.skip("cue notes");
+            return Ok(());
+        }
+        for x in elements(c) {
+            match x.tag_name().name() {
+                name if NOTE_LOOKS.contains(&name) => (),
+                "grace" => (),
+                "lyric" => self.skip("lyrics"),
+                other => self.skip(format!("<{}>", other)),
+            }
+        }
+        let n = number::<usize>(c, "staff")?.unwrap_or(1);
+        let staff = self.staff(n);
+        let voice = text_of(c, "voice").unwrap_or("1").to_owned();
+        let tone = self.read_tone(c)?;
+        if let Some(t) = tone {
+            self.sharps.insert((staff, t.step), t.sharp);
+        }
+        if let Some(g) = child(c, "grace") {
+            return self.read_grace(c, g, (staff, voice), tone);
+        }
+        let len = self.len_of(c)?;
+        if len.is_zero() {
+            self.skip("notes of no length");
+            return Ok(());
+        }
+        let tied = children(c, "tie")
+            .chain(c.descendants().filter(|x| x.has_tag_name("tied")))
+            .any(|x| x.attribute("type") == Some("start"));
+
+        let chord = child(c, "chord").is_some();
+        let on = match *last {
+            Some((s, v)) if chord && s == staff => {
+                let bar = self.staves[s].bars.last().unwrap();
+                bar[v].1.last().unwrap().on
+            }
+            _ => {
+                *at += len;
+                on
+            }
+        };
+        let (arts, slur, trill) = self.read_notations(c, staff, &voice, on)?;
+        let written = self.written(c, len)?;
+        let trill = match (trill, tone) {
+            (Some((k, mark)), Some(t)) => {
+                let step = t.step + 1;
+                let key = self.key.unwrap_or_default();
+                let sharp = mark
+                    .or_else(|| self.sharps.get(&(staff, step)).cloned())
+                    .unwrap_or_else(|| key.sharps_for(step.rem_euclid(7)));
+                Some((Tone::new(step, sharp), k))
+            }
+            (Some(_), None) => {
+                self.skip("trills on rests");
+                None
+            }
+            _ => None,
+        };
+        let graces = if chord {
+            None
+        } else {
+            self.graces.remove(&(staff, voice.clone()))
+        };
+        let orn = match (graces, trill) {
+            (Some(_), _) if tone.is_none() => {
+                self.skip("grace notes before rests");
+                None
+            }
+            (_, Some(_)) if chord => {
+                self.skip("ornaments on chords");
+                None
+            }
+            (Some((slash, gs)), trill) => {
+                if trill.is_some() {
+                    self.skip("trills after grace notes");
+                }
+                Some(Ornament::Graces(slash, gs))
+            }
+            (None, Some((upper, k))) => Some(Ornament::Trill(upper, k)),
+            (None, None) => None,
+        };
+
+        let bar = self.staves[staff].bars.last_mut().unwrap();
+        let v = match bar.iter().position(|x| x.0 == voice) {
+            Some(v) => v,
+            None => {
+                bar.push((voice, vec![]));
+                bar.len() - 1
+            }
+        };
+        let notes = &mut bar[v].1;
+        let adding = chord && *last == Some((staff, v));
+        let mut mixed = false;
+        match notes.last_mut() {
+            Some(e) if adding && tone.is_some() && !e.note.is_rest() => {
+                let tone = tone.unwrap();
+                let mut tones = match &e.note.pitch {
+                    Pitch::Single(t) => vec![*t],
+                    Pitch::Chord(ts) => ts.clone(),
+                    _ => vec![],
+                };
+                if !tones.contains(&tone) {
+                    tones.push(tone);
+                }
+                e.note.pitch = Pitch::Chord(tones);
+                mixed = e.note.tie != tied;
+                e.note.tie &= tied;
+                for a in arts {
+                    if !e.arts.contains(&a) {
+                        e.arts.push(a);
+                    }
+                }
+                e.slur = match (e.slur, slur) {
+                    (Some(x), Some(y)) => Some(x || y),
+                    (x, y) => x.or(y),
+                };
+            }
+            _ => {
+                let pitch = tone.map_or(Pitch::Rest, Pitch::Single);
+                let mut note = mk_note(written, pitch);
+                note.tie = tied && !note.is_rest();
+                notes.push(Entry { on, note, arts, slur, orn });
+                *last = Some((staff, v));
+            }
+        }
+        if mixed {
+            self.skip("ties on some notes of a chord only");
+        }
+        Ok(())
+    }
+
+    // None for a rest.
+    fn read_tone(&mut self, c: Node) -> Res<Option<Tone>> {
+        let p = match child(c, "pitch") {
+            Some(p) => p,
+            None => {
+                if child(c, "unpitched").is_some() {
+                    self.skip("unpitched notes");
+                }
+                return Ok(None);
+            }
+        };
+        let degree = match text_of(p, "step") {
+            Some("C") => 0,
+            Some("D") => 1,
+            Some("E") => 2,
+            Some("F") => 3,
+            Some("G") => 4,
+            Some("A") => 5,
+            Some("B") => 6,
+            other => return fail("a step from A to G", other.unwrap_or("none")),
+        };
+        let alter: f64 = number(p, "alter")?.unwrap_or(0.);
+        // Sheets go as far as double sharps and flats.
+        if alter.round().abs() > 2. {
+            return fail("an <alter> from -2 to 2", alter);
+        }
+        if alter.fract() != 0. {
+            self.skip("microtones");
+        }
+        let octave: i32 = match number(p, "octave")? {
+            Some(x) => x,
+            None => return fail("an <octave>", "none"),
+        };
+        // Octave 4 starts at middle C, so C5 is the same.
+        Ok(Some(Tone::new((octave - 5) * 7 + degree, alter.round() as i32)))
+    }
+
+    // Grace notes wait for the note they lead to, in the same voice.
+    fn read_grace(&mut self, c: Node, g: Node, voice: (usize, String),
+                  tone: Option<Tone>) -> Res<()> {
+        let (t, d) = match (tone, self.value(c)?) {
+            (Some(t), Some(d)) => (t, d),
+            _ => {
+                self.skip("grace notes without a pitch or a <type>");
+                return Ok(());
+            }
+        };
+        let (slash, notes) = self.graces.entry(voice).or_default();
+        *slash |= g.attribute("slash") == Some("yes");
+        match notes.last_mut() {
+            Some(n) if child(c, "chord").is_some() => {
+                let mut tones = match &n.pitch {
+                    Pitch::Single(x) => vec![*x],
+                    Pitch::Chord(xs) => xs.clone(),
+                    _ => vec![],
+                };
+                tones.push(t);
+                n.pitch = Pitch::Chord(tones);
+            }
+            _ => notes.push(mk_note(d, Pitch::Single(t))),
+        }
+        Ok(())
+    }
+
+    // The value as written, if it lasts as long as the note does.
+    fn written(&self, c: Node, len: Ratio) -> Res<Duration> {
+        Ok(match self.value(c)? {
+            Some(d) if d.dur() == len => d,
+            _ => Duration::from_len(len),
+        })
+    }
+
+    // The value as its <type> has it.
+    fn value(&self, c: Node) -> Res<Option<Duration>> {
+        let klass = match text_of(c, "type") {
+            Some("whole") => 1,
+            Some("half") => 2,
+            Some("quarter") => 4,
+            Some("eighth") => 8,
+            Some("16th") => 16,
+            Some("32nd") => 32,
+            Some("64th") => 64,
+            Some("128th") => 128,
+            Some("256th") => 256,
+            _ => return Ok(None),
+        };
+        let dots = children(c, "dot").count() as i8;
+        let mut scale = Ratio::one();
+        if let Some(tm) = child(c, "time-modification") {
+            let actual: i64 = number(tm, "actual-notes")?.unwrap_or(1);
+            let normal: i64 = number(tm, "normal-notes")?.unwrap_or(1);
+            if actual > 0 && normal > 0 {
+                scale = Ratio::new(normal, actual);
+            }
+        }
+        Ok(Some(Duration { klass, dots, scale }))
+    }
+
+    fn read_notations(&mut self, c: Node, staff: usize, voice: &str,
+                      on: Ratio) -> Res<Notations> {
+        let mut arts = vec![];
+        let mut trill = None;
+        let (mut starts, mut stops) = (vec![], vec![]);
+        for ns in children(c, "notations") {
+            for x in elements(ns) {
+                match x.tag_name().name() {
+                    // Read with the <tie>s, and shown by time-modification.
+                    "tied" | "tuplet" => (),
+                    "slur" => {
+                        let n = x.attribute("number").unwrap_or("1");
+                        match x.attribute("type") {
+                            Some("start") => starts.push(n.to_owned()),
+                            Some("stop") => stops.push(n.to_owned()),
+                            _ => (),
+                        }
+                    }
+                    "articulations" => for a in elements(x) {
+                        let name = a.tag_name().name();
+                        match articulation(name) {
+                            Some(art) => arts.push(art),
+                            None => self.skip(format!("{} marks", name)),
+                        }
+                    },
+                    "dynamics" => {
+                        self.read_dynamics(x, &[staff], on);
+                    }
+                    "ornaments" => for o in elements(x) {
+                        match o.tag_name().name() {
+                            "trill-mark" => {
+                                // As many as (tr n) plays, unless written.
+                                let beats = o.attribute("beats")
+                                    .and_then(|x| usize::from_str(x).ok());
+                                trill = Some((beats.unwrap_or(4).max(2), None));
+                            }
+                            "accidental-mark" => if let Some(t) = &mut trill {
+                                t.1 = o.text().and_then(alter_of);
+                            },
+                            // How the trill is drawn.
+                            "wavy-line" => (),
+                            name => self.skip(format!("{} ornaments", name)),
+                        }
+                    },
+                    "technical" => for t in elements(x) {
+                        match t.tag_name().name() {
+                            "fingering" => (),
+                            name => self.skip(format!("{} marks", name)),
+                        }
+                    },
+                    "fermata" => self.skip("fermatas"),
+                    "arpeggiate" | "non-arpeggiate" => self.skip("arpeggios"),
+                    other => self.skip(format!("<{}>", other)),
+                }
+            }
+        }
+
+        let open = self.slurs.entry((staff, voice.to_owned()))
+            .or_default();
+        let under = !open.is_empty() || !starts.is_empty();
+        open.extend(starts);
+        let ends = !stops.is_empty();
+        open.retain(|x| !stops.contains(x));
+        let slur = if under { Some(ends && open.is_empty()) } else { None };
+        Ok((arts, slur, trill))
+    }
+
+    fn read_dynamics(&mut self, d: Node, staves: &[usize], at: Ratio) {
+        let forte = DYNAMICS.iter().find(|x| x.0 == "f").unwrap().1;
+        for x in elements(d) {
+            let name = x.tag_name().name();
+            let (amp, accent) = match name {
+                "sf" | "sfz" | "sffz" | "fz" => (None, Some(SFORZANDO)),
+                // f for the note, p from there on.
+                "fp" => {
+                    let p = DYNAMICS.iter().find(|x| x.0 == "p").unwrap().1;
+                    (Some(p), Some(forte / p))
+                }
+                _ => match DYNAMICS.iter().find(|x| x.0 == name) {
+                    Some(x) => (Some(x.1), None),
+                    None => {
+                        self.skip(format!("the dynamic {}", name));
+                        continue;
+                    }
+                },
+            };
+            for &s in staves {
+                let s = &mut self.staves[s];
+                if let Some(amp) = amp {
+                    s.dynamics.push((at, amp));
+                }
+                if let Some(accent) = accent {
+                    s.accents.push((at, accent));
+                }
+            }
+        }
+    }
+
+    fn read_direction(&mut self, d: Node, at: Ratio) -> Res<()> {
+        let at = match number::<i64>(d, "offset")? {
+            Some(x) => at + Ratio::new(x, 2 * self.divisions),
+            None => at,
+        }.max(Ratio::zero());
+        let staves: Vec<usize> = match number::<usize>(d, "staff")? {
+            Some(n) => vec![self.staff(n)],
+            None => (0..self.staves.len()).collect(),
+        };
+        let sound = child(d, "sound");
+        let sound_tempo = sound.and_then(|s| s.attribute("tempo")).is_some();
+        for t in children(d, "direction-type") {
+            for x in elements(t) {
+                match x.tag_name().name() {
+                    "dynamics" => self.read_dynamics(x, &staves, at),
+                    "metronome" => if !sound_tempo {
+                        match metronome(x) {
+                            Some(bpm) => self.shared.tempo.push(Tempo {
+                                at: at.to_f64(),
+                                bpm,
+                                ramp: false,
+                            }),
+                            None => self.skip("metronome marks other than \
+                                               a value per minute"),
+                        }
+                    },
+                    "pedal" => match x.attribute("type") {
+                        Some("start") | Some("change") =>
+                            self.pedal(at, Some(1.), None),
+                        Some("stop") => self.pedal(at, Some(0.), None),
+                        _ => (),
+                    },
+                    "words" => {
+                        let words = x.text().unwrap_or("").trim();
+                        if !words.is_empty() {
+                            self.skip(format!("the words \"{}\"", words));
+                        }
+                    }
+                    "wedge" => for &s in &staves {
+                        let s = &mut self.staves[s];
+                        match x.attribute("type") {
+                            Some("crescendo") => s.wedge = Some((at, true)),
+                            Some("diminuendo") => s.wedge = Some((at, false)),
+                            Some("stop") => {
+                                if let Some((start, up)) = s.wedge.take() {
+                                    s.hairpins.push((start, at, up));
+                                }
+                            }
+                            _ => (),
+                        }
+                    },
+                    "rehearsal" | "segno" | "coda" | "octave-shift"
+                        | "bracket" | "dashes" | "image" => (),
+                    other => self.skip(format!("<{}>", other)),
+                }
+            }
+        }
+        if let Some(s) = sound {
+            self.read_sound(s, at)?;
+        }
+        Ok(())
+    }
+
+    // Tempo and pedals. The repeats and jumps are read by flow_of.
+    fn read_sound(&mut self, s: Node, at: Ratio) -> Res<()> {
+        if let Some(x) = s.attribute("tempo") {
+            match x.trim().parse::<f64>() {
+                Ok(bpm) if bpm > 0. => self.shared.tempo.push(Tempo {
+                    at: at.to_f64(),
+                    bpm,
+                    ramp: false,
+                }),
+                _ => return fail("a tempo in quarters per minute", x),
+            }
+        }
+        // "yes", "no" or how far down, out of 100.
+        let down = |attr| s.attribute(attr).map(|x| match x {
+            "yes" => 1.,
+            "no" => 0.,
+            x => x.parse::<f32>().unwrap_or(0.).clamp(0., 100.) / 100.,
+        });
+        let sustain = down("damper-pedal");
+        let una_corda = down("soft-pedal").map(|x| x > 0.);
+        if sustain.is_some() || una_corda.is_some() {
+            self.pedal(at, sustain, una_corda);
+        }
+        Ok(())
+    }
+
+    // The pedals are the instrument's, so they hold every staff of it,
+    // whichever one they are written under.
+    fn pedal(&mut self, at: Ratio, sustain: Option<f32>,
+             una_corda: Option<bool>) {
+        for s in &mut self.staves {
+            if let Some(x) = sustain {
+                s.sustain = x;
+            }
+            if let Some(x) = una_corda {
+                s.una_corda = x;
+            }
+            s.pedal.push(Pedal {
+                at,
+                sustain: s.sustain,
+                una_corda: s.una_corda,
+            });
+        }
+    }
+
+    fn read_attributes(&mut self, a: Node) -> Res<()> {
+        let at_start = self.bars.is_empty();
+        for x in elements(a) {
+            match x.tag_name().name() {
+                "divisions" => match number::<i64>(a, "divisions")? {
+                    Some(d) if d > 0 => self.divisions = d,
+                    d => return fail("positive divisions", d.unwrap_or(0)),
+                },
+                "key" => {
+                    let fifths = match number::<i32>(x, "fifths")? {
+                        Some(x) => x.clamp(-7, 7),
+                        None => {
+                            self.skip("keys other than major and minor");
+                            continue;
+                        }
+                    };
+                    let minor = text_of(x, "mode") == Some("minor");
+                    let key = Key { fifths, minor };
+                    match self.key {
+                        None if at_start => {
+                            self.key = Some(key);
+                            if self.shared.key.is_none() {
+                                self.shared.key = Some(key);
+                            }
+                        }
+                        Some(k) if k == key => (),
+                        _ => self.skip("changes of key"),
+                    }
+                }
+                "time" => {
+                    let beats = number::<i32>(x, "beats").ok().flatten();
+                    let unit = number::<i32>(x, "beat-type").ok().flatten();
+                    let meter = match (beats, unit) {
+                        (Some(beats), Some(unit)) if beats > 0 && unit > 0
+                            && unit & (unit - 1) == 0 => Meter { beats, unit },
+                        _ => {
+                            self.skip("meters other than beats over a unit");
+                            continue;
+                        }
+                    };
+                    match self.meter {
+                        None if at_start => {
+                            self.meter = Some(meter);
+                            if self.shared.meter.is_none() {
+                                self.shared.meter = Some(meter);
+                            }
+                        }
+                        Some(m) if m == meter => (),
+                        _ => {
+                            self.meter = Some(meter);
+                            self.skip("changes of meter");
+                        }
+                    }
+                }
+                "staves" => if let Some(n) = number::<usize>(a, "staves")? {
+                    self.staff(n);
+                },
+                "transpose" => self.skip("transposition"),
+                "clef" | "staff-details" | "measure-style" | "part-symbol"
+                    | "instruments" | "footnote" | "level" => (),
+                other => self.skip(format!("<{}>", other)),
+            }
+        }
+        Ok(())
+    }
+
+    fn into_tracks(mut self, only_part: bool) -> Vec<Track> {
+        let staves = mem::take(&mut self.staves);
+        let count = staves.len();
+        let mut out = vec![];
+        for (i, mut s) in staves.into_iter().enumerate() {
+            self.play_ornaments(&mut s);
+            self.shape(&mut s);
+            let notes = self.staff_notes(&mut s);
+            let name = if count == 1 {
+                self.name.clone()
+            } else if only_part && count == 2
+                && self.instrument == Instrument::Piano {
+                // As a (piano ...) sheet has them.
+                ["treble", "bass"][i].to_owned()
+            } else {
+                format!("{} {}", self.name, i + 1)
+            };
+            let mut pedal: Vec<Pedal> = vec![];
+            s.pedal.sort_by_key(|p| p.at);
+            for p in s.pedal {
+                match pedal.last_mut() {
+                    Some(last) if last.at == p.at => *last = p,
+                    _ => pedal.push(p),
+                }
+            }
+            out.push(Track {
+                name,
+                instrument: self.instrument,
+                notes: notation::merge_ties(notes),
+                pedal,
+            });
+        }
+        out
+    }
+
+    // Each ornament as the notes the reader of .ss files plays for it.
+    fn play_ornaments(&mut self, s: &mut StaffNotes) {
+        for (_, es) in s.bars.iter_mut().flat_map(|b| b.iter_mut()) {
+            for e in mem::take(es) {
+                es.extend(self.ornament_notes(e));
+            }
+        }
+    }
+
+    // The notes of an entry with its ornament played, or the entry alone if
+    // it can't be.
+    fn ornament_notes(&mut self, mut e: Entry) -> Vec<Entry> {
+        let (orn, main) = match (e.orn.take(), &e.note.pitch) {
+            (None, _) => return vec![e],
+            (Some(orn), &Pitch::Single(t)) => (orn, (t, e.note.duration)),
+            (Some(_), _) => {
+                self.measure_at(e.on);
+                self.skip("ornaments on chords");
+                return vec![e];
+            }
+        };
+        let trill = matches!(orn, Ornament::Trill(..));
+        let notes = match orn {
+            Ornament::Trill(upper, k) => {
+                // (tr n), (tr32 n)...: the main and upper notes in turn.
+                let (t, d) = main;
+                let quick = d.scaled(Ratio::new(1, k as i64));
+                (0..k).map(|i| if i % 2 == 0 { t } else { upper })
+                    .map(|x| (Pitch::Single(x), quick))
+                    .collect()
+            }
+            Ornament::Graces(slash, gs) => {
+                match graces_played(main, slash, gs) {
+                    Some(notes) => notes,
+                    None => {
+                        self.measure_at(e.on);
+                        self.skip("grace notes as long as their note");
+                        return vec![e];
+                    }
+                }
+            }
+        };
+        let k = notes.len();
+        let mut on = e.on;
+        let mut out = vec![];
+        for (i, (pitch, dur)) in notes.into_iter().enumerate() {
+            let last = i + 1 == k;
+            let mut note = mk_note(dur, pitch);
+            note.tie = last && e.note.tie;
+            // The marks of a trilled note are for all of its notes.
+            let arts = if trill || last { e.arts.clone() } else { vec![] };
+            let slur = if last { e.slur } else { e.slur.map(|_| false) };
+            out.push(Entry { on, note, arts, slur, orn: None });
+            on += dur.dur();
+        }
+        out
+    }
+
+    // Dynamics, accents, articulations and slurs, as the reader of .ss
+    // files takes them: slurs first, so that the marks under them still
+    // count.
+    fn shape(&self, s: &mut StaffNotes) {
+        s.dynamics.sort_by_key(|x| x.0);
+        // A hairpin goes from the dynamic in force to the one marked where
+        // it stops, or else a level on, as (cresc ...) and (dim ...) do.
+        let mut ramps = vec![];
+        for &(start, stop, up) in &s.hairpins {
+            if stop <= start {
+                continue;
+            }
+            let from = level_at(&s.dynamics, start);
+            let to = match s.dynamics.iter().find(|x| x.0 == stop) {
+                Some(x) => x.1,
+                None => {
+                    let to = notation::step_dynamic(from, up);
+                    let i = s.dynamics.partition_point(|x| x.0 <= stop);
+                    s.dynamics.insert(i, (stop, to));
+                    to
+                }
+            };
+            ramps.push((start, stop, from, to));
+        }
+        let mut ons: Vec<Ratio> = s.bars.iter()
+            .flat_map(|b| b.iter())
+            .flat_map(|v| v.1.iter())
+            .filter(|e| !e.note.is_rest())
+            .map(|e| e.on)
+            .collect();
+        ons.sort();
+        // Each accent is for the next note.
+        let accents: Vec<(Ratio, f32)> = s.accents.iter()
+            .filter_map(|&(at, x)| {
+                ons.iter().find(|&&on| on >= at).map(|&on| (on, x))
+            })
+            .collect();
+        let dynamics = &s.dynamics;
+        for e in s.bars.iter_mut()
+            .flat_map(|b| b.iter_mut())
+            .flat_map(|v| v.1.iter_mut()) {
+            if e.note.is_rest() {
+                continue;
+            }
+            let (on, n) = (e.on, &mut e.note);
+            n.amp = level_at(dynamics, on);
+            for &(start, stop, from, to) in &ramps {
+                if start <= on && on < stop {
+                    let x = ((on - start) / (stop - start)).to_f64() as f32;
+                    n.amp *= (from + (to - from) * x) / from;
+                }
+            }
+            for &(at, x) in &accents {
+                if at == on {
+                    n.amp *= x;
+                }
+            }
+            if let Some(last) = e.slur {
+                shape_note(n, self.style.shape(Articulation::Slur), last);
+            }
+            for &a in &e.arts {
+                shape_note(n, self.style.shape(a), true);
+            }
+        }
+    }
+
+    // Runs of measures with one voice make a line of notes, and runs with
+    // more make a note of Voices, a line for each.
+    fn staff_notes(&mut self, s: &mut StaffNotes) -> Vec<Note> {
+        let mut out = vec![];
+        let mut k = 0;
+        while k < self.bars.len() {
+            let many = s.bars[k].len() > 1;
+            let first = k;
+            while k < self.bars.len() && (s.bars[k].len() > 1) == many {
+                k += 1;
+            }
+            let from = self.bars[first].0;
+            let to = self.bars[k - 1].0 + self.bars[k - 1].1;
+            let mut voices: Vec<(String, Vec<Entry>)> = vec![];
+            for b in &mut s.bars[first..k] {
+                for (id, es) in b.drain(..) {
+                    let id = if many { id } else { String::new() };
+                    match voices.iter_mut().find(|x| x.0 == id) {
+                        Some(v) => v.1.extend(es),
+                        None => voices.push((id, es)),
+                    }
+                }
+            }
+            if !many {
+                let es = voices.pop().map_or(vec![], |x| x.1);
+                out.extend(self.line(es, from, to));
+            } else {
+                let lines = voices.into_iter()
+                    .map(|(_, es)| self.line(es, from, to))
+                    .collect();
+                // As the reader of .ss files has them.
+                let len = Duration::exact(to - from);
+                out.push(mk_note(len, Pitch::Voices(lines)));
+            }
+        }
+        out
+    }
+
+    // One voice from `from` to `to`, with rests where it has no notes.
+    fn line(&mut self, mut es: Vec<Entry>, from: Ratio, to: Ratio)
+        -> Vec<Note> {
+        es.sort_by_key(|e| e.on);
+        let mut out: Vec<Note> = vec![];
+        let mut at = from;
+        for e in es {
+            if e.on < at {
+                self.measure_at(e.on);
+                self.skip("notes that overlap others of their voice");
+                continue;
+            }
+            if e.on > at {
+                out.push(mk_rest(Duration::from_len(e.on - at)));
+            }
+            at = e.on + e.note.dur();
+            out.push(e.note);
+        }
+        if out.last().is_some_and(|n| n.tie) {
+            self.measure_at(at);
+            self.skip("ties into or out of passages in several voices");
+        }
+        if at < to {
+            out.push(mk_rest(Duration::from_len(to - at)));
+        }
+        // Only ties between the same pitches, one right after the other,
+        // make a longer note.
+        for i in 0..out.len() {
+            let next = out.get(i + 1);
+            let held = next.is_some_and(|n| n.pitch == out[i].pitch);
+            out[i].tie &= held;
+        }
+        out
+    }
+}
+
+// Grace notes before a note of `main` and `d` as the reader of .ss files
+// plays them: a slashed one just below as (acciac n), a single one next to
+// the note as (short-appog g n) if slashed, or as (appog g n) if printed at
+// the value it takes, and the others as (grace g... n). None if they take
+// all of the note.
+fn graces_played((main, d): (Tone, Duration), slash: bool, gs: Vec<Note>)
+    -> Option<Vec<(Pitch, Duration)>> {
+
+    if let [Note { pitch: Pitch::Single(g), duration, .. }] = gs[..] {
+        if slash && g.step + 1 == main.step {
+            return Some(vec![
+                (Pitch::Single(g), d.scaled(Ratio::new(1, 12))),
+                (Pitch::Single(main), d.scaled(Ratio::new(11, 12))),
+            ]);
+        }
+        let dotted = d.dots > 0;
+        let long = Duration::new(if dotted { d.klass } else { d.klass * 2 }, 0);
+        let share = if slash {
+            Ratio::new(1, 8)
+        } else if dotted {
+            Ratio::new(2, 3)
+        } else {
+            Ratio::new(1, 2)
+        };
+        if (g.step - main.step).abs() == 1 && (slash || duration == long) {
+            let a = d.scaled(share);
+            return Some(vec![
+                (Pitch::Single(g), a),
+                (Pitch::Single(main), Duration::exact(d.dur() - a.dur())),
+            ]);
+        }
+    }
+    let len: Ratio = gs.iter().map(|g| g.dur()).sum();
+    if len >= d.dur() {
+        return None;
+    }
+    let mut out: Vec<_> = gs.into_iter().map(|g| (g.pitch, g.duration))
+        .collect();
+    out.push((Pitch::Single(main), Duration::exact(d.dur() - len)));
+    Some(out)
+}
+
+fn alter_of(accidental: &str) -> Option<i32> {
+    Some(match accidental {
+        "natural" => 0,
+        "sharp" => 1,
+        "double-sharp" | "sharp-sharp" => 2,
+        "flat" => -1,
+        "flat-flat" => -2,
+        _ => return None,
+    })
+}
+
+fn articulation(name: &str) -> Option<Articulation> {
+    Some(match name {
+        "staccato" => Articulation::Staccato,
+        "staccatissimo" | "spiccato" => Articulation::Staccatissimo,
+        "tenuto" => Articulation::Tenuto,
+        "detached-legato" => Articulation::Portato,
+        "accent" => Articulation::Accent,
+        "strong-accent" => Articulation::Marcato,
+        _ => return None,
+    })
+}
+
+// Quarters a minute, from a beat unit (maybe dotted) and a number.
+fn metronome(m: Node) -> Option<f64> {
+    let klass = match text_of(m, "beat-unit")? {
+        "whole" => 1,
+        "half" => 2,
+        "quarter" => 4,
+        "eighth" => 8,
+        "16th" => 16,
+        _ => return None,
+    };
+    let dots = children(m, "beat-unit-dot").count() as i8;
+    let per_minute: f64 = text_of(m, "per-minute")?.parse().ok()?;
+    let quarters = Duration::new(klass, dots).dur() / Ratio::new(1, 2);
+    Some(per_minute * quarters.to_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::to_musicxml;
+
+    // Where each key is struck, and for how long.
+    fn notes(ns: &[Note], mut at: Ratio, out: &mut Vec<(Ratio, i32, Ratio)>) {
+        for n in ns {
+            let tones = match &n.pitch {
+                Pitch::Rest => vec![],
+                Pitch::Single(t) => vec![*t],
+                Pitch::Chord(ts) => ts.clone(),
+                Pitch::Voices(vs) => {
+                    for v in vs {
+                        notes(v, at, out);
+                    }
+                    vec![]
+                }
+            };
+            out.extend(tones.iter().map(|t| (at, t.midi_key(), n.dur())));
+            at += n.dur();
+        }
+    }
+
+    fn all_notes(sh: &Sheet) -> Vec<(Ratio, i32, Ratio)> {
+        let mut out = vec![];
+        for t in &sh.tracks {
+            notes(&t.notes, Ratio::zero(), &mut out);
+        }
+        out.sort();
+        out
+    }
+
+    fn round_trip(name: &str, src: &str) {
+        let sh = notation::read_sheet(src.as_bytes()).unwrap();
+        let xml = to_musicxml::write_musicxml(&sh);
+        let back = read_musicxml(xml.as_bytes(), &ReadOptions::default())
+            .unwrap();
+        let left: Vec<_> = back.unsupported.iter()
+            .map(|u| u.to_string())
+            .collect();
+        assert!(left.is_empty(), "{}: {:?}", name, left);
+        assert!(all_notes(&sh) == all_notes(&back.sheet), "{}", name);
+    }
+
+    #[test]
+    fn reads_back_what_is_written() {
+        for f in &["kv545.ss", "kv545-m2.ss", "kv545-m3.ss"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(f);
+            round_trip(f, &fs::read_to_string(path).unwrap());
+        }
+    }
+
+    #[test]
+    fn reads_back_grace_notes() {
+        round_trip("graces", "(piano (4 4) (key D major) \
+            ((treble-C (acciac (/4 5)) (appog 6 (/4 5)) \
+                       (short-appog 6 (/4 5)) (grace (/32 3 4) (/4 5))) \
+             (treble-C (/1 0))))");
+    }
+
+    #[test]
+    fn ramps_through_hairpins() {
+        let c = "<note><pitch><step>C</step><octave>4</octave></pitch>\
+                 <duration>1</duration><type>quarter</type></note>";
+        let mark = |x: &str| format!("<direction><direction-type>{}\
+                                      </direction-type></direction>", x);
+        let xml = format!(r#"<score-partwise><part-list>
+            <score-part id="P1"><part-name>P</part-name></score-part>
+            </part-list><part id="P1"><measure number="1">
+            <attributes><divisions>1</divisions></attributes>
+            {}{}{c}{c}{c}{c}</measure><measure number="2">
+            {}{}{c}{}{c}{c}{}{c}</measure></part></score-partwise>"#,
+            mark("<dynamics><p/></dynamics>"),
+            mark(r#"<wedge type="crescendo"/>"#),
+            mark(r#"<wedge type="stop"/>"#),
+            mark("<dynamics><f/></dynamics>"),
+            mark(r#"<wedge type="diminuendo"/>"#),
+            mark(r#"<wedge type="stop"/>"#),
+            c = c);
+        let back = read_musicxml(xml.as_bytes(), &ReadOptions::default())
+            .unwrap();
+        assert!(!back.unsupported.iter().any(|u| u.what == "hairpins"));
+        let amps: Vec<f32> = back.sheet.tracks[0].notes.iter()
+            .filter(|n| !n.is_rest())
+            .map(|n| n.amp)
+            .collect();
+        let level = |name| DYNAMICS.iter().find(|x| x.0 == name).unwrap().1;
+        let (p, f) = (level("p"), level("f"));
+        // Without a dynamic where it stops, the diminuendo goes a level
+        // down.
+        let mf = notation::step_dynamic(f, false);
+        let want = [p, p + (f - p) / 4., p + (f - p) / 2.,
+                    p + (f - p) * 0.75, f, f, (f + mf) / 2., mf];
+        assert_eq!(amps.len(), want.len());
+        for (a, w) in amps.iter().zip(&want) {
+            assert!((a - w).abs() < 1e-6, "{:?} {:?}", amps, want);
+        }
+    }
+
+    #[test]
+    fn leaves_out_notes_of_no_length() {
+        let xml = r#"<score-partwise><part-list>
+            <score-part id="P1"><part-name>P</part-name></score-part>
+            </part-list><part id="P1"><measure number="1">
+            <attributes><divisions>1</divisions></attributes>
+            <note><pitch><step>C</step><octave>4</octave></pitch>
+            <duration>0</duration></note>
+            <note><pitch><step>D</step><octave>4</octave></pitch>
+            <duration>4</duration><type>whole</type></note>
+            </measure></part></score-partwise>"#;
+        let back = read_musicxml(xml.as_bytes(), &ReadOptions::default())
+            .unwrap();
+        assert!(back.unsupported.iter()
+            .any(|u| u.what == "notes of no length"));
+        assert_eq!(all_notes(&back.sheet).len(), 1);
+    }
+
+    #[test]
+    fn rejects_triple_sharps() {
+        let xml = r#"<score-partwise><part-list>
+            <score-part id="P1"><part-name>P</part-name></score-part>
+            </part-list><part id="P1"><measure number="7">
+            <attributes><divisions>1</divisions></attributes>
+            <note><pitch><step>C</step><alter>3</alter><octave>4</octave>
+            </pitch><duration>4</duration><type>whole</type></note>
+            </measure></part></score-partwise>"#;
+        let e = read_musicxml(xml.as_bytes(), &ReadOptions::default())
+            .err().unwrap();
+        assert_eq!(e.to_string(),
+                   "measure 7 (P): expected an <alter> from -2 to 2, found 3");
+    }
+}
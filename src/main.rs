@@ -6,26 +6,198 @@ mod geniter;
 mod playback;
 mod conc;
 mod to_wav;
+mod to_ss;
+mod to_midi;
+mod to_musicxml;
+mod to_lilypond;
+mod from_midi;
+mod from_abc;
+mod from_musicxml;
 mod notes;
 mod notes_old;
 mod types;
 mod soundprim;
 mod notation;
+mod srcpos;
+mod repeats;
+mod ratio;
+mod style;
 
-fn play_sheet() {
-    use std::fs::File;
+// A .ss work, or ABC tunes as its movements.
+fn read_score(path: &str) -> notes::Score {
+    let opts = notation::ReadOptions::default();
+    if path.ends_with(".abc") {
+        return match from_abc::load(path, &opts) {
+            Ok(score) => score,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+    }
+    match notation::read_score(path, &opts) {
+        Ok(score) => score,
+        Err(e) => {
+            for e in &e.errors {
+                eprintln!("{}", e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+// A movement by number (from 1) or name.
+fn movement<'a>(score: &'a notes::Score, path: &str, which: &str)
+    -> &'a notes::Sheet {
+
+    let found = score.movements.iter().enumerate().find(|(i, m)| {
+        which.parse() == Ok(i + 1)
+            || m.name.as_deref() == Some(which)
+    });
+    match found {
+        Some((_, m)) => &m.sheet,
+        None => {
+            eprintln!("{}: no movement {}", path, which);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let sh = notation::read_sheet(File::open("kv545.ss").unwrap());
-    let m = notes::build_sheet(&sh).map(|x| x * 0.1);
+// cargo run -- [file] [movement]: the movement by number or name, or else
+// the whole work.
+fn play_sheet() {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.get(1).map_or("kv545.ss", |x| x);
+    if path.ends_with(".mid") || path.ends_with(".midi") {
+        return play_midi(path);
+    }
+    if [".musicxml", ".xml", ".mxl"].iter().any(|x| path.ends_with(x)) {
+        return play_musicxml(path);
+    }
+    let score = read_score(path);
+    let m: Box<dyn types::Sound> = match args.get(2) {
+        None => Box::new(notes::build_score(&score)),
+        Some(which) => {
+            Box::new(notes::build_sheet(movement(&score, path, which)))
+        }
+    };
+    let m = m.map(|x| x * 0.1);
     // to_wav::save(m, "kv545.wav");
     // let m = m.collect::<Vec<_>>().into_iter();
     // playback::play(m).unwrap();
     conc::buffer_playback(m);
 }
 
+// cargo run -- file.mid: through the same synth.
+fn play_midi(path: &str) {
+    let sheet = match from_midi::load(path) {
+        Ok(sheet) => sheet,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let m = notes::build_sheet(&sheet).map(|x| x * 0.1);
+    conc::buffer_playback(m);
+}
+
+// cargo run -- file.musicxml (or .mxl): what it leaves out is listed.
+fn play_musicxml(path: &str) {
+    let opts = notation::ReadOptions::default();
+    let imported = match from_musicxml::load(path, &opts) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    for u in &imported.unsupported {
+        eprintln!("{}: {}", path, u);
+    }
+    let m = notes::build_sheet(&imported.sheet).map(|x| x * 0.1);
+    conc::buffer_playback(m);
+}
+
+// cargo run -- midi file out.mid [movement], or musicxml file
+// out.musicxml [movement]: the first movement unless told otherwise.
+fn export(args: &[String], usage: &str,
+          save: fn(&notes::Sheet, &str) -> std::io::Result<()>) {
+    let (path, out) = match args {
+        [path, out, ..] => (path, out),
+        _ => {
+            eprintln!("usage: {}", usage);
+            std::process::exit(1);
+        }
+    };
+    let score = read_score(path);
+    let which = args.get(2).map_or("1", |x| x);
+    if let Err(e) = save(movement(&score, path, which), out) {
+        eprintln!("{}: {}", out, e);
+        std::process::exit(1);
+    }
+}
+
+// cargo run -- lilypond file out.ly [movement]: the whole work unless a
+// movement is given.
+fn export_lilypond(args: &[String]) {
+    const USAGE: &str = "lilypond file.ss out.ly [movement]";
+    let (path, out) = match args {
+        [_, _, _, ..] => return export(args, USAGE, to_lilypond::save),
+        [path, out] => (path, out),
+        _ => {
+            eprintln!("usage: {}", USAGE);
+            std::process::exit(1);
+        }
+    };
+    let score = read_score(path);
+    if let Err(e) = to_lilypond::save_score(&score, out) {
+        eprintln!("{}: {}", out, e);
+        std::process::exit(1);
+    }
+}
+
+// cargo run -- dump file: the sheet as the reader makes it out.
+fn dump_sheet(path: &str) {
+    let dumped = std::fs::File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|f| to_ss::dump(f).map_err(|e| e.to_string()));
+    match dumped {
+        Ok(s) => print!("{}", s),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// cargo run -- fmt file: the file as written, laid out afresh.
+fn format_sheet(path: &str) {
+    let formatted = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|src| to_ss::format(&src).map_err(|e| e.to_string()));
+    match formatted {
+        Ok(s) => print!("{}", s),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     // to_wav::save(music::kv545(), "kv545.wav");
     // let m = music::kv545().collect::<Vec<_>>().into_iter();
     // playback::play(notes_old::kv545()).unwrap();
-    play_sheet();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|x| x.as_str()) {
+        Some("dump") => dump_sheet(args.get(2).map_or("kv545.ss", |x| x)),
+        Some("fmt") => format_sheet(args.get(2).map_or("kv545.ss", |x| x)),
+        Some("midi") => export(&args[2..], "midi file.ss out.mid [movement]",
+                               to_midi::save),
+        Some("musicxml") => export(&args[2..],
+                                   "musicxml file.ss out.musicxml [movement]",
+                                   to_musicxml::save),
+        Some("lilypond") => export_lilypond(&args[2..]),
+        _ => play_sheet(),
+    }
 }
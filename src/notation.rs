@@ -1,411 +1,2451 @@
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::collections::HashMap;
+use std::fmt;
+use std::mem;
 use itertools::Itertools;
 use lexpr::{
     Value::{self, *},
-    Atom::{self, *},
+    Atom::{self, Symbol},
 };
 use crate::notes::*;
 use crate::types::*;
+use crate::srcpos::{Pos, SrcMap};
+use crate::repeats::{self, Flow};
+use crate::ratio::Ratio;
+use crate::style::{Articulation, Shape, Style};
 
-pub fn read_sheet(r: impl Read) -> Sheet {
-    let v = lexpr::from_reader(r).expect("from_reader");
-    read_toplevel(&v)
+pub struct ReadOptions {
+    // Whether to take repeats (and the voltas before the last one).
+    pub repeats: bool,
+    // How to play articulations, unless the sheet says otherwise.
+    pub style: Style,
 }
 
-#[derive(Copy, Clone)]
-enum Clef {
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self { repeats: true, style: Style::default() }
+    }
+}
+
+pub fn read_sheet(r: impl Read) -> Result<Sheet, NotationError> {
+    read_sheet_with(r, &ReadOptions::default())
+}
+
+pub fn read_sheet_with(mut r: impl Read, opts: &ReadOptions)
+    -> Result<Sheet, NotationError> {
+    let mut src = String::new();
+    if let Err(e) = r.read_to_string(&mut src) {
+        return Err(Box::new(Error::plain("readable input", e)).into());
+    }
+    let v = parse_forms(&src)?;
+    let forms: Vec<_> = as_list(&v).unwrap_or(&[]).iter().collect();
+
+    let mut diag = Diag {
+        maps: vec![(None, SrcMap::new(&src, as_list(&v).unwrap_or(&[])))],
+        errors: vec![],
+    };
+    let sh = read_toplevel(&forms, opts, &mut diag);
+    if diag.errors.is_empty() {
+        Ok(sh)
+    } else {
+        Err(NotationError { errors: diag.errors })
+    }
+}
+
+// A whole work: the movements of the file at `path` and the files it
+// includes. Errors name the file they are in.
+pub fn read_score(path: impl AsRef<Path>, opts: &ReadOptions)
+    -> Result<Score, NotationError> {
+    let mut diag = Diag { maps: vec![], errors: vec![] };
+    let mut files = vec![];
+    let score = match load(path.as_ref(), &mut files, &mut vec![], &mut diag) {
+        Some(main) => {
+            let scopes = file_motifs(&files, &mut diag);
+            let mut forms = vec![];
+            flatten(&files, main, &mut forms);
+            read_work(&forms, &scopes, opts, &mut diag)
+        }
+        None => Score::default(),
+    };
+    if diag.errors.is_empty() {
+        Ok(score)
+    } else {
+        Err(NotationError { errors: diag.errors })
+    }
+}
+
+// A file may have several toplevel forms, so read them as one list.
+pub fn parse_forms(src: &str) -> Result<Value, Box<Error>> {
+    let src = blank_comments(src);
+    lexpr::from_reader(format!("(\n{}\n)", src).as_bytes())
+        .map_err(|e| Box::new(Error::plain("an s-expression", e)))
+}
+
+// lexpr doesn't take ; and #| |# comments, so they turn into spaces; lines
+// and columns stay where they were for SrcMap.
+fn blank_comments(src: &str) -> String {
+    let cs: Vec<char> = src.chars().collect();
+    let mut out = String::with_capacity(src.len());
+    let blank = |c: char| if c == '\n' { '\n' } else { ' ' };
+    let mut i = 0;
+    while i < cs.len() {
+        let c = cs[i];
+        if c == '"' {
+            // Strings are kept whole, escapes and all.
+            out.push(c);
+            i += 1;
+            while i < cs.len() && cs[i] != '"' {
+                if cs[i] == '\\' && i + 1 < cs.len() {
+                    out.push(cs[i]);
+                    i += 1;
+                }
+                out.push(cs[i]);
+                i += 1;
+            }
+            if i < cs.len() {
+                out.push('"');
+                i += 1;
+            }
+        } else if c == ';' {
+            while i < cs.len() && cs[i] != '\n' {
+                out.push(' ');
+                i += 1;
+            }
+        } else if c == '#' && cs.get(i + 1) == Some(&'|') {
+            while i < cs.len()
+                && !(cs[i] == '|' && cs.get(i + 1) == Some(&'#')) {
+                out.push(blank(cs[i]));
+                i += 1;
+            }
+            let end = (cs.len() - i).min(2);
+            out.extend(std::iter::repeat_n(' ', end));
+            i += end;
+        } else {
+            out.push(c);
+            i += 1;
+        }
+    }
+    out
+}
+
+// A file read by read_score.
+struct Source {
+    // Its toplevel forms, as one list.
+    forms: Value,
+    // The file brought in by each (include ...) form, by their positions
+    // among the forms.
+    includes: HashMap<usize, usize>,
+}
+
+// Reads the file and whatever it includes into `files`, included files
+// first. `within` are the files being read, to catch include cycles.
+fn load(path: &Path, files: &mut Vec<Source>, within: &mut Vec<PathBuf>,
+        diag: &mut Diag) -> Option<usize> {
+    let name = path.display().to_string();
+    let parsed = std::fs::read_to_string(path)
+        .map_err(|e| Box::new(Error::plain("a readable file", e)))
+        .and_then(|src| parse_forms(&src).map(|v| (src, v)));
+    let (src, v) = match parsed {
+        Ok(x) => x,
+        Err(e) => {
+            diag.errors.push(Error { file: Some(name), ..*e });
+            return None;
+        }
+    };
+    let forms = as_list(&v).unwrap_or(&[]);
+    diag.maps.push((Some(name), SrcMap::new(&src, forms)));
+
+    within.push(path.canonicalize().unwrap_or_else(|_| path.to_owned()));
+    let mut includes = HashMap::new();
+    for (i, f) in forms.iter().enumerate() {
+        if form_tag(f) != Some("include") {
+            continue;
+        }
+        let file = match as_list(f) {
+            Some([_, file]) => file.as_str(),
+            _ => None,
+        };
+        let inc = match file {
+            Some(file) => path.parent().unwrap_or(Path::new("")).join(file),
+            None => {
+                diag.report(Unexpected {
+                    at: f,
+                    expected: "an include like (include \"file.ss\")"
+                        .to_owned(),
+                    found: None,
+                }, None, None);
+                continue;
+            }
+        };
+        let key = inc.canonicalize().unwrap_or_else(|_| inc.clone());
+        if within.contains(&key) {
+            diag.report(Unexpected {
+                at: f,
+                expected: "a file that doesn't include this one".to_owned(),
+                found: Some(inc.display().to_string()),
+            }, None, None);
+            continue;
+        }
+        if let Some(j) = load(&inc, files, within, diag) {
+            includes.insert(i, j);
+        }
+    }
+    within.pop();
+
+    files.push(Source { forms: v, includes });
+    Some(files.len() - 1)
+}
+
+// The forms of a file, with those of the included files in place of the
+// (include ...) forms. Each goes with the file it is in.
+fn flatten<'a>(files: &'a [Source], i: usize,
+               out: &mut Vec<(usize, &'a Value)>) {
+    let src = &files[i];
+    for (j, v) in as_list(&src.forms).unwrap_or(&[]).iter().enumerate() {
+        match src.includes.get(&j) {
+            Some(&k) => flatten(files, k, out),
+            // Couldn't be read, which is reported already.
+            None if form_tag(v) == Some("include") => (),
+            None => out.push((i, v)),
+        }
+    }
+}
+
+// The motifs each file can use: its own, then those of the files it
+// includes that it doesn't define itself. Included files come first in
+// `files`, so theirs are known by then.
+fn file_motifs<'a>(files: &'a [Source], diag: &mut Diag) -> Vec<Motifs<'a>> {
+    let mut out: Vec<Motifs> = vec![];
+    for src in files {
+        let forms: Vec<&Value> = as_list(&src.forms).unwrap_or(&[]).iter()
+            .collect();
+        let (mut motifs, _) = read_defines(&forms, diag);
+        let mut includes: Vec<(&usize, &usize)> = src.includes.iter()
+            .collect();
+        includes.sort();
+        for (_, &k) in includes {
+            for (name, &body) in &out[k] {
+                motifs.entry(name.clone()).or_insert(body);
+            }
+        }
+        out.push(motifs);
+    }
+    out
+}
+
+// Errors
+
+// All the errors found in one pass over the sheet.
+#[derive(Debug)]
+pub struct NotationError {
+    pub errors: Vec<Error>,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    // Only given by read_score, which may read several files.
+    pub file: Option<String>,
+    pub pos: Option<Pos>,
+    // 1-based, counting every bar in the file (including dropped ones).
+    pub bar: Option<usize>,
+    pub track: Option<String>,
+    pub expected: String,
+    pub found: String,
+}
+
+impl Error {
+    fn plain(expected: &str, found: impl fmt::Display) -> Self {
+        Self {
+            file: None,
+            pos: None,
+            bar: None,
+            track: None,
+            expected: expected.to_owned(),
+            found: found.to_string(),
+        }
+    }
+}
+
+impl From<Box<Error>> for NotationError {
+    fn from(e: Box<Error>) -> Self {
+        Self { errors: vec![*e] }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.pos) {
+            (Some(file), Some(pos)) => write!(f, "{}:{}: ", file, pos)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            (None, Some(pos)) => write!(f, "{}: ", pos)?,
+            (None, None) => (),
+        }
+        match (self.bar, &self.track) {
+            (Some(bar), Some(track)) => write!(f, "bar {} ({}): ", bar, track)?,
+            (Some(bar), None) => write!(f, "bar {}: ", bar)?,
+            (None, Some(track)) => write!(f, "{}: ", track)?,
+            (None, None) => (),
+        }
+        write!(f, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl fmt::Display for NotationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, e) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NotationError {}
+
+// What the reader functions return: the offending value, located later.
+struct Unexpected<'a> {
+    at: &'a Value,
+    expected: String,
+    // When printing the offending value isn't helpful (e.g. a whole bar).
+    found: Option<String>,
+}
+
+type Res<'a, T> = Result<T, Unexpected<'a>>;
+
+fn fail<'a, T>(at: &'a Value, expected: impl Into<String>) -> Res<'a, T> {
+    Err(Unexpected { at, expected: expected.into(), found: None })
+}
+
+struct Diag {
+    // One for each file read, with its name if errors should give it.
+    maps: Vec<(Option<String>, SrcMap)>,
+    errors: Vec<Error>,
+}
+
+impl Diag {
+    fn report(&mut self, e: Unexpected, bar: Option<usize>,
+              track: Option<&str>) {
+        let at = e.at;
+        let (file, pos) = self.maps.iter()
+            .filter_map(|(file, map)| map.get(at).map(|pos| (file, pos)))
+            .next()
+            .map_or((None, None), |(file, pos)| (file.clone(), Some(pos)));
+        self.errors.push(Error {
+            file,
+            pos,
+            bar,
+            track: track.map(|x| x.to_owned()),
+            expected: e.expected,
+            found: e.found.unwrap_or_else(|| at.to_string()),
+        });
+    }
+}
+
+fn read_meter(v: &Value) -> Res<'_, Meter> {
+    match as_list(v) {
+        Some([beats, unit]) => match (beats.as_i64(), unit.as_i64()) {
+            (Some(beats), Some(unit))
+                if beats > 0 && unit > 0 && (unit & (unit - 1)) == 0 =>
+                    Ok(Meter { beats: beats as i32, unit: unit as i32 }),
+            _ => fail(v, "a meter like (3 4)"),
+        },
+        _ => fail(v, "a meter like (3 4)"),
+    }
+}
+
+// Bar lengths as a fraction of a whole note, in the meter's unit if
+// possible: 5/8 in a (6 8) bar, 3/8 for a dotted quarter in a (2 4) bar.
+fn fmt_dur(d: Ratio, unit: i32) -> String {
+    // Duration::dur of a whole note is 2.
+    let whole = d / Ratio::from_int(2);
+    let unit = unit as i64;
+    if unit % whole.denom() == 0 {
+        format!("{}/{}", whole.numer() * (unit / whole.denom()), unit)
+    } else {
+        whole.to_string()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Clef {
     Treble,
     Bass,
+    Alto,
+    Tenor,
+    // Sounds an octave lower, as in tenor parts.
+    Treble8vb,
+    Bass8va,
+    // Unpitched; the lines read as in treble.
+    Percussion,
+}
+
+impl Clef {
+    // Pitch of the bottom line, in steps from C5.
+    pub fn bottom_line(&self) -> i32 {
+        match self {
+            Clef::Treble | Clef::Percussion => -5,
+            Clef::Bass => -17,
+            Clef::Alto => -11,
+            Clef::Tenor => -13,
+            Clef::Treble8vb => -12,
+            Clef::Bass8va => -10,
+        }
+    }
+}
+
+fn read_key(v: &Value) -> Res<'_, Key> {
+    // A bare number used to raise every note by that many half steps.
+    if v.as_i64().is_some() {
+        return fail(v, "a key like (key Bb minor) (a number no longer \
+                        transposes by half steps)");
+    }
+    match as_list(v) {
+        Some([tag, tonic, mode]) if tag.as_symbol() == Some("key") =>
+            read_key_name(v, tonic, mode),
+        _ => fail(v, "a key like (key Bb minor)"),
+    }
+}
+
+fn read_key_name<'a>(v: &'a Value, tonic: &'a Value, mode: &'a Value)
+    -> Res<'a, Key> {
+
+    let minor = match mode.as_symbol() {
+        Some("major") => false,
+        Some("minor") => true,
+        _ => return fail(mode, "major or minor"),
+    };
+    let name = match tonic.as_symbol() {
+        Some(name) if !name.is_empty() => name,
+        _ => return fail(tonic, "a tonic like D, F# or Bb"),
+    };
+    // Position of the letter on the circle of fifths, from C major.
+    let letter = match &name[..1] {
+        "F" => -1,
+        "C" => 0,
+        "G" => 1,
+        "D" => 2,
+        "A" => 3,
+        "E" => 4,
+        "B" => 5,
+        _ => return fail(tonic, "a tonic like D, F# or Bb"),
+    };
+    let accidental = match &name[1..] {
+        "" => 0,
+        "#" => 1,
+        "b" => -1,
+        _ => return fail(tonic, "a tonic like D, F# or Bb"),
+    };
+    // The relative minor is three fifths down.
+    let fifths: i32 = letter + 7 * accidental - if minor { 3 } else { 0 };
+    if fifths.abs() > 7 {
+        return fail(v, "a key with at most 7 sharps or flats");
+    }
+    Ok(Key { fifths, minor })
+}
+
+// Amplitudes of the dynamics, relative to mf.
+pub const DYNAMICS: [(&str, f32); 8] = [
+    ("ppp", 0.25),
+    ("pp", 0.35),
+    ("p", 0.5),
+    ("mp", 0.7),
+    ("mf", 1.),
+    ("f", 1.3),
+    ("ff", 1.6),
+    ("fff", 2.),
+];
+
+pub const SFORZANDO: f32 = 1.8;
+
+fn try_read_dynamic(s: &str) -> Option<f32> {
+    DYNAMICS.iter().find(|x| x.0 == s).map(|x| x.1)
+}
+
+// One level up or down from the closest dynamic, for hairpins that don't
+// say where they're going.
+pub fn step_dynamic(amp: f32, up: bool) -> f32 {
+    let (ix, _) = DYNAMICS
+        .iter()
+        .enumerate()
+        .min_by(|x, y| {
+            let dx = ((x.1).1 - amp).abs();
+            let dy = ((y.1).1 - amp).abs();
+            dx.partial_cmp(&dy).unwrap()
+        })
+        .unwrap();
+    let ix = if up {
+        (ix + 1).min(DYNAMICS.len() - 1)
+    } else {
+        ix.saturating_sub(1)
+    };
+    DYNAMICS[ix].1
 }
 
 #[derive(Clone)]
-struct TrackState {
+struct TrackState<'a> {
     clef: Clef,
-    // Map from pitch to number of sharps.
+    meter: Meter,
+    key: Key,
+    // Current dynamic as an amplitude, mf being 1.
+    dynamic: f32,
+    // One-shot multiplier for the next note (sfz, fp).
+    accent: f32,
+    // Accidentals written in this bar: map from pitch to number of sharps,
+    // overriding the key signature. Naturals are 0.
     sharps: HashMap<i32, i32>,
-    global_sharp: i32,
+    motifs: &'a Motifs<'a>,
+    // Staff steps added to every pitch, within (use name :transpose n).
+    transpose: i32,
+    // The motifs being expanded, innermost last.
+    using: Vec<&'a str>,
+    // Product of the tuplets we're in: 2/3 within a triplet.
+    tuplet: Ratio,
+    // Pitches of the last note if it's tied to the next one, which has to
+    // be the same.
+    tie: Option<Vec<i32>>,
+    // Octaves added by (8va ...) and the like.
+    octave: i32,
+    // Pedals, as in Pedal.
+    sustain: f32,
+    una_corda: bool,
+    style: &'a Style,
 }
 
-impl TrackState {
-    fn new() -> Self {
+impl<'a> TrackState<'a> {
+    fn new(meter: Meter, key: Key, motifs: &'a Motifs<'a>, style: &'a Style)
+        -> Self {
         Self {
             clef: Clef::Treble,
+            meter,
+            key,
+            dynamic: 1.,
+            accent: 1.,
             sharps: HashMap::new(),
-            global_sharp: 0,
+            motifs,
+            transpose: 0,
+            using: vec![],
+            tuplet: Ratio::one(),
+            tie: None,
+            octave: 0,
+            sustain: 0.,
+            una_corda: false,
+            style,
         }
     }
 
-    fn add_sharp(&mut self, ix: i32, n: i32) {
-        if let Some(orig) = self.sharps.get_mut(&ix) {
-            *orig += n;
-        } else {
-            self.sharps.insert(ix, n);
-        }
+    // A duration as written, stretched by the tuplets we're in.
+    fn duration(&self, s: &str) -> Option<Duration> {
+        try_read_duration(s).map(|d| Duration {
+            scale: d.scale * self.tuplet,
+            ..d
+        })
     }
 
-    fn reset_sharp(&mut self, ix: i32) {
-        self.sharps.remove(&ix);
+    fn set_sharp(&mut self, ix: i32, n: i32) {
+        self.sharps.insert(ix, n);
     }
 
+    // Pitches are written as positions on the staff, 0 being the bottom
+    // line.
     fn norm_pitch(&self, ix: i32) -> i32 {
-        let dix = match self.clef {
-            Clef::Treble => 2,
-            Clef::Bass => -10,
+        self.abs_pitch(ix + self.clef.bottom_line())
+    }
+
+    // For pitches given by name, which don't depend on the clef.
+    fn abs_pitch(&self, ix: i32) -> i32 {
+        ix + self.transpose + self.octave * 7
+    }
+
+    fn tone_for_ix(&self, ix: i32) -> Tone {
+        let sharp = self.sharps.get(&ix).cloned()
+            .unwrap_or_else(|| self.key.sharps_for(ix.rem_euclid(7)));
+        Tone::new(ix, sharp)
+    }
+}
+
+// Motifs from (define name cmds...), by name.
+type Motifs<'a> = HashMap<String, &'a [Value]>;
+
+// Takes out the (define ...) forms, leaving the others in order.
+fn read_defines<'a>(forms: &[&'a Value], diag: &mut Diag)
+    -> (Motifs<'a>, Vec<&'a Value>) {
+
+    let mut motifs = HashMap::new();
+    let mut rest = vec![];
+    for &v in forms {
+        let vs = match as_list(v) {
+            Some(vs) if form_tag(v) == Some("define") => vs,
+            _ => {
+                rest.push(v);
+                continue;
+            }
         };
-        // -7 to move C5 to C4.
-        ix + dix - 7
+        match read_define(vs) {
+            Ok((name, body)) => {
+                if motifs.insert(name.to_owned(), body).is_some() {
+                    diag.report(Unexpected {
+                        at: &vs[1],
+                        expected: "a motif name not defined before"
+                            .to_owned(),
+                        found: None,
+                    }, None, None);
+                }
+            }
+            Err(e) => diag.report(e, None, None),
+        }
+    }
+    (motifs, rest)
+}
+
+// The sheet, with any (define ...) forms around it.
+fn read_toplevel(forms: &[&Value], opts: &ReadOptions, diag: &mut Diag)
+    -> Sheet {
+
+    let (motifs, rest) = read_defines(forms, diag);
+    for &v in rest.iter().skip(1) {
+        diag.report(Unexpected {
+            at: v,
+            expected: "a single sheet per file".to_owned(),
+            found: Some("another one".to_owned()),
+        }, None, None);
+    }
+    match rest.first() {
+        Some(v) => read_sheet_form(v, &motifs, opts, diag),
+        None => {
+            diag.errors.push(Error::plain("a (piano ...) or (score ...) form",
+                                          "none"));
+            Sheet::default()
+        }
     }
+}
 
-    fn freq_for_ix(&self, mut ix: i32) -> f64 {
-        let sharp = self.sharps.get(&ix).cloned().unwrap_or(0)
-            + self.global_sharp;
+// Every sheet is a movement, in order, after an optional (work ...) header.
+// Sheets use the motifs of the file they are in.
+fn read_work(forms: &[(usize, &Value)], scopes: &[Motifs],
+             opts: &ReadOptions, diag: &mut Diag) -> Score {
 
-        let mut pow2 = 0;
-        while ix < 0 {
-            ix += 7;
-            pow2 -= 1;
+    let rest: Vec<(usize, &Value)> = forms.iter().cloned()
+        .filter(|x| form_tag(x.1) != Some("define"))
+        .collect();
+    let mut score = Score::default();
+    let mut names = None;
+    let mut sheets = vec![];
+    for (i, &(file, v)) in rest.iter().enumerate() {
+        match as_list(v) {
+            Some(vs) if i == 0 && form_tag(v) == Some("work") =>
+                match read_work_header(vs, &mut score) {
+                    Ok(x) => names = x,
+                    Err(e) => diag.report(e, None, None),
+                },
+            _ if form_tag(v) == Some("work") => diag.report(Unexpected {
+                at: v,
+                expected: "the (work ...) header before the movements"
+                    .to_owned(),
+                found: Some("another one".to_owned()),
+            }, None, None),
+            _ => sheets.push((file, v)),
+        }
+    }
+    if sheets.is_empty() {
+        diag.errors.push(Error::plain("a (piano ...) or (score ...) form",
+                                      "none"));
+    }
+    let names = match names {
+        Some((at, ref ns)) if ns.len() != sheets.len() => {
+            diag.report(Unexpected {
+                at,
+                expected: format!("a name for each of the {} movements",
+                                  sheets.len()),
+                found: Some(format!("{} names", ns.len())),
+            }, None, None);
+            vec![]
         }
-        while ix > 7 {
-            ix -= 7;
-            pow2 += 1;
+        Some((_, ns)) => ns,
+        None => vec![],
+    };
+    score.movements = sheets.iter().enumerate().map(|(i, &(file, v))| {
+        Movement {
+            name: names.get(i).cloned(),
+            sheet: read_sheet_form(v, &scopes[file], opts, diag),
+        }
+    }).collect();
+    score
+}
+
+// (work :title "Sonata facile" :composer "Mozart" :catalogue "K. 545"
+//       :movements ("Allegro" "Andante" "Rondo")): all optional.
+fn read_work_header<'a>(vs: &'a [Value], score: &mut Score)
+    -> Res<'a, Option<(&'a Value, Vec<String>)>> {
+
+    let mut names = None;
+    for kv in vs[1..].chunks(2) {
+        let (k, x) = match kv {
+            [k, x] => (k, x),
+            _ => return fail(&kv[0], "a value after the option"),
+        };
+        let field = match as_keyword(k) {
+            Some("title") => &mut score.title,
+            Some("composer") => &mut score.composer,
+            Some("catalogue") => &mut score.catalogue,
+            Some("movements") => {
+                let expected = "movement names like (\"Allegro\" \"Rondo\")";
+                let mut ns = vec![];
+                for n in expect_list(x, expected)? {
+                    match n.as_str() {
+                        Some(n) => ns.push(n.to_owned()),
+                        None => return fail(n, "a movement name (a string)"),
+                    }
+                }
+                names = Some((x, ns));
+                continue;
+            }
+            _ => return fail(k, ":title, :composer, :catalogue or :movements"),
+        };
+        match x.as_str() {
+            Some(x) => *field = Some(x.to_owned()),
+            None => return fail(x, "a string"),
         }
-        assert!(ix >= 0 && ix <= 7);
-        OCTAVE_5[ix as usize]
-            * (2_f64.powi(pow2))
-            * (HALF_STEP.powi(sharp))
     }
+    Ok(names)
 }
 
-fn read_toplevel(v: &Value) -> Sheet {
-    let vs = expect_list(v, "toplevel");
+fn read_sheet_form(v: &Value, motifs: &Motifs, opts: &ReadOptions,
+                   diag: &mut Diag) -> Sheet {
+    let r = match as_list(v) {
+        Some(vs) if vs.len() >= 4 && vs[0].as_symbol() == Some("piano") =>
+            read_sheet_body(piano_staves(), &vs[1..], motifs, opts, diag),
+        Some(vs) if vs.len() >= 5 && vs[0].as_symbol() == Some("score") =>
+            read_staves(&vs[1]).and_then(|staves| {
+                read_sheet_body(staves, &vs[2..], motifs, opts, diag)
+            }),
+        _ => fail(v, "a toplevel (piano meter key ... (bars ...)) \
+                      or (score (staves ...) meter key ... (bars ...))"),
+    };
+    r.unwrap_or_else(|e| {
+        diag.report(e, None, None);
+        Sheet::default()
+    })
+}
+
+// (define name cmds...): the commands are anything that can go in a bar.
+fn read_define(vs: &[Value]) -> Res<'_, (&str, &[Value])> {
     match vs {
-        [Atom(Symbol(tag)), _beat, gsharp, tracks]
-            if tag == "piano" =>
-                read_piano_tracks(tracks, gsharp.as_i64().unwrap()),
-        _ => panic!("Not a toplevel: {}", v),
+        [_, name, _, ..] => match name.as_symbol() {
+            Some(s) => Ok((s, &vs[2..])),
+            None => fail(name, "a motif name"),
+        },
+        _ => fail(&vs[0], "a motif like (define name notes...)"),
     }
 }
 
-fn read_piano_tracks(v: &Value, gsharp: i64) -> Sheet {
-    let vs = expect_list(v, "tracks");
-    let mut xst = TrackState::new();
-    xst.global_sharp = gsharp as i32;
-    let mut yst = TrackState::new();
-    yst.global_sharp = gsharp as i32;
-    let mut xs = vec![];
-    let mut ys = vec![];
+// A staff as declared by the score.
+struct Staff {
+    name: String,
+    instrument: Instrument,
+    clef: Clef,
+}
+
+fn piano_staves() -> Vec<Staff> {
+    // The bass staff of a piano sheet still starts in treble-C, as it
+    // always has.
+    vec!["treble", "bass"].into_iter().map(|name| Staff {
+        name: name.to_owned(),
+        instrument: Instrument::Piano,
+        clef: Clef::Treble,
+    }).collect()
+}
+
+// (staves (rh piano) (lh piano bass-C) (vn violin) ...): a name, an
+// instrument and optionally the clef to start with.
+fn read_staves(v: &Value) -> Res<'_, Vec<Staff>> {
+    let expected = "staves like (staves (rh piano) (lh piano bass-C))";
+    let vs = match as_list(v) {
+        Some(vs) if vs.len() > 1 && vs[0].as_symbol() == Some("staves") =>
+            &vs[1..],
+        _ => return fail(v, expected),
+    };
+    let mut out = vec![];
+    for v in vs {
+        let (name, instrument, clef) = match as_list(v) {
+            Some([name, instrument]) => (name, instrument, None),
+            Some([name, instrument, clef]) => (name, instrument, Some(clef)),
+            _ => return fail(v, "a staff like (rh piano) or (lh piano bass-C)"),
+        };
+        let name = match name.as_symbol() {
+            Some(name) => name.to_owned(),
+            None => return fail(name, "a staff name"),
+        };
+        let instrument = match instrument.as_symbol()
+            .and_then(try_read_instrument) {
+            Some(x) => x,
+            None => return fail(instrument,
+                                "piano, violin, viola, cello or voice"),
+        };
+        let clef = match clef {
+            None => Clef::Treble,
+            Some(v) => match v.as_symbol().and_then(try_read_clef) {
+                Some(clef) => clef,
+                None => return fail(v, "a clef"),
+            },
+        };
+        out.push(Staff { name, instrument, clef });
+    }
+    Ok(out)
+}
+
+fn try_read_instrument(s: &str) -> Option<Instrument> {
+    Some(match s {
+        "piano" => Instrument::Piano,
+        "violin" => Instrument::Violin,
+        "viola" => Instrument::Viola,
+        "cello" => Instrument::Cello,
+        "voice" => Instrument::Voice,
+        _ => return None,
+    })
+}
+
+// (meter key directives... bars)
+fn read_sheet_body<'a>(staves: Vec<Staff>, vs: &'a [Value],
+                       motifs: &Motifs, opts: &ReadOptions, diag: &mut Diag)
+    -> Res<'a, Sheet> {
+
+    let meter = read_meter(&vs[0])?;
+    let key = read_key(&vs[1])?;
+    let mut tempo = vec![];
+    let mut style = opts.style.clone();
+    for v in &vs[2..vs.len() - 1] {
+        let args = as_list(v).unwrap_or(&[]);
+        match args.first().and_then(|x| x.as_symbol()) {
+            Some("tempo") =>
+                tempo.push((0., TempoMark::Set(read_tempo(args)?))),
+            Some("style") => read_style(args, &mut style)?,
+            _ => return fail(v, "a sheet-wide directive such as (tempo 120)"),
+        }
+    }
+    let setup = Setup { meter, key, tempo, motifs, style: &style };
+    Ok(read_systems(&vs[vs.len() - 1], &staves, setup, opts, diag))
+}
+
+// (style (staccato :length 0.4) (^ :gap 0.05) (accent :attack 1.2))
+fn read_style<'a>(vs: &'a [Value], style: &mut Style) -> Res<'a, ()> {
+    let expected = "an articulation like (staccato :length 0.4)";
+    for v in &vs[1..] {
+        let xs = expect_list(v, expected)?;
+        let art = match xs.first().and_then(|x| x.as_symbol())
+            .and_then(Articulation::from_name) {
+            Some(art) => art,
+            None => return fail(v, expected),
+        };
+        let mut shape = style.shape(art);
+        for kv in xs[1..].chunks(2) {
+            let (k, x) = match kv {
+                [k, x] => (k, x),
+                _ => return fail(&kv[0], "a value after the option"),
+            };
+            let x = match x.as_f64() {
+                Some(x) if x >= 0. => x,
+                _ => return fail(x, "a non-negative number"),
+            };
+            match as_keyword(k) {
+                Some("length") | Some("gap") if x > 1. =>
+                    return fail(&kv[1], "a share of the note, up to 1"),
+                Some("length") => shape.length = Some(x),
+                Some("gap") => shape.gap = Some(x),
+                Some("attack") => shape.attack = x as f32,
+                _ => return fail(k, ":length, :gap or :attack"),
+            }
+        }
+        style.shapes.insert(art, shape);
+    }
+    Ok(())
+}
+
+// (tempo 132) is in quarters; (tempo /4. 60) gives the beat explicitly.
+fn read_tempo(vs: &[Value]) -> Res<'_, f64> {
+    let expected = "a tempo like (tempo 132) or (tempo /4. 60)";
+    let (beat, bpm) = match vs {
+        [_, bpm] => (Duration::new(4, 0), bpm),
+        [_, beat, bpm] => match beat.as_symbol().and_then(try_read_duration) {
+            Some(beat) => (beat, bpm),
+            None => return fail(beat, "a beat unit like /4."),
+        },
+        _ => return fail(&vs[0], expected),
+    };
+    match bpm.as_f64() {
+        Some(x) if x > 0. => {
+            let quarter = Duration::new(4, 0);
+            Ok(x * (beat.dur() / quarter.dur()).to_f64())
+        }
+        _ => fail(bpm, "a positive number of beats per minute"),
+    }
+}
+
+// Tempo markings as written. They only turn into a TempoMap once we know
+// what the tempo was before each of them.
+#[derive(Copy, Clone, PartialEq)]
+enum TempoMark {
+    Set(f64),
+    // rit. or accel. to a bpm, over a length of score time.
+    Ramp { len: f64, bpm: f64 },
+    // Back to the last Set.
+    ATempo,
+    // Fermata: the given length of score time is played `factor` times
+    // slower.
+    Stretch { len: f64, factor: f64 },
+}
+
+const FERMATA: f64 = 2.;
+
+// (rit 4 bars to 80), (accel 2 beats to /4. 96), (rit /2. to 60)
+fn read_ramp<'a>(vs: &'a [Value], st: &TrackState) -> Res<'a, TempoMark> {
+    let expected = "a tempo change like (rit 4 bars to 80)";
+    let to = match vs.iter().position(|v| v.as_symbol() == Some("to")) {
+        Some(to) => to,
+        None => return fail(&vs[0], expected),
+    };
+    let len = match &vs[1..to] {
+        [n, unit] => {
+            let unit = match unit.as_symbol() {
+                Some("bars") | Some("bar") => st.meter.bar_dur(),
+                Some("beats") | Some("beat") => st.meter.beat_dur(),
+                _ => return fail(unit, "bars or beats"),
+            };
+            match n.as_f64() {
+                Some(n) if n > 0. => n * unit.to_f64(),
+                _ => return fail(n, "a positive length"),
+            }
+        }
+        [dur] => match dur.as_symbol().and_then(try_read_duration) {
+            Some(dur) => dur.dur().to_f64(),
+            None => return fail(dur, "a length like /2."),
+        },
+        _ => return fail(&vs[0], expected),
+    };
+    Ok(TempoMark::Ramp { len, bpm: read_tempo(&vs[to..])? })
+}
+
+// Turns the marks (sorted by position) into tempo points.
+fn resolve_tempo(marks: &[(f64, TempoMark)]) -> TempoMap {
+    let mut points = vec![];
+    let mut base = DEFAULT_BPM;
+    let mut cur = DEFAULT_BPM;
+    for &(at, mark) in marks {
+        let step = |bpm| Tempo { at, bpm, ramp: false };
+        match mark {
+            TempoMark::Set(bpm) => {
+                base = bpm;
+                cur = bpm;
+                points.push(step(bpm));
+            }
+            TempoMark::Ramp { len, bpm } => {
+                // Start from wherever we are now.
+                points.push(step(cur));
+                points.push(Tempo { at: at + len, bpm, ramp: true });
+                cur = bpm;
+            }
+            TempoMark::ATempo => {
+                cur = base;
+                points.push(step(base));
+            }
+            TempoMark::Stretch { len, factor } => {
+                points.push(step(cur / factor));
+                points.push(Tempo { at: at + len, bpm: cur, ramp: false });
+            }
+        }
+    }
+    // Ramps and stretches end after marks that follow them.
+    points.sort_by(|x, y| x.at.partial_cmp(&y.at).unwrap());
+    // A jump straight into another one at the same place doesn't count:
+    // (tempo 90) (rit ...) at one spot plays as the ramp alone, and a dump
+    // of the sheet, which writes that as the ramp alone, reads back the
+    // same.
+    let mut out: Vec<Tempo> = vec![];
+    for p in points {
+        match out.last() {
+            Some(last) if last.at == p.at && !last.ramp && !p.ramp => {
+                out.pop();
+            }
+            _ => (),
+        }
+        out.push(p);
+    }
+    TempoMap { points: out }
+}
+
+// One staff's worth of a bar.
+struct Bar {
+    notes: Vec<Note>,
+    // Positioned from the start of the bar.
+    tempo: Vec<(Ratio, TempoMark)>,
+    pedal: Vec<Pedal>,
+}
+
+impl Bar {
+    fn new() -> Self {
+        Self { notes: vec![], tempo: vec![], pedal: vec![] }
+    }
+
+    fn dur(&self) -> Ratio {
+        self.notes.iter().map(|n| n.dur()).sum()
+    }
+
+    // Takes effect after the notes so far.
+    fn mark_tempo(&mut self, mark: TempoMark) {
+        let at = self.dur();
+        self.tempo.push((at, mark));
+    }
+
+    fn mark_pedal(&mut self, st: &TrackState) {
+        let at = self.dur();
+        self.pedal.push(Pedal {
+            at,
+            sustain: st.sustain,
+            una_corda: st.una_corda,
+        });
+    }
+}
+
+// One bar for each staff.
+struct System {
+    bars: Vec<Bar>,
+    // Of all the staves, positioned from the start of the system.
+    tempo: Vec<(Ratio, TempoMark)>,
+    dur: Ratio,
+}
+
+// What a sheet sets before its bars.
+struct Setup<'a> {
+    meter: Meter,
+    key: Key,
+    // The sheet-wide markings, at the start.
+    tempo: Vec<(f64, TempoMark)>,
+    motifs: &'a Motifs<'a>,
+    style: &'a Style,
+}
+
+// The bars come in systems, with repeat and jump marks between them.
+fn read_systems<'a>(v: &'a Value, staves: &[Staff], setup: Setup<'a>,
+                    opts: &ReadOptions, diag: &mut Diag) -> Sheet {
+    let Setup { meter, key, mut tempo, motifs, style } = setup;
+    let vs = match expect_list(v, "a list of bars") {
+        Ok(vs) => vs,
+        Err(e) => {
+            diag.report(e, None, None);
+            return Sheet::default();
+        }
+    };
+    let mut sts: Vec<_> = staves.iter().map(|staff| {
+        let mut st = TrackState::new(meter, key, motifs, style);
+        st.clef = staff.clef;
+        st
+    }).collect();
+    let mut systems = vec![];
+    let mut flow = vec![];
+    let mut jumps = vec![];
     let mut to_drop = 0;
-    for (x, y) in vs.iter().tuples() {
-        if x.as_symbol() == Some("drop") {
+    let mut bar_no = 0;
+    let mut ix = 0;
+    while ix < vs.len() {
+        if vs[ix].as_symbol() == Some("drop") {
             // Drop several bars.
-            to_drop += y.as_i64().unwrap();
+            match vs.get(ix + 1).and_then(|v| v.as_i64()) {
+                Some(n) => to_drop += n,
+                None => diag.report(Unexpected {
+                    at: &vs[ix],
+                    expected: "a number of bars to drop".to_owned(),
+                    found: None,
+                }, Some(bar_no + 1), None),
+            }
+            ix += 2;
             continue;
         }
 
+        if let Some(r) = try_read_flow(&vs[ix]) {
+            match r {
+                Ok(f) => {
+                    if let Flow::Jump { .. } | Flow::ToCoda = f {
+                        jumps.push((&vs[ix], f.clone()));
+                    }
+                    flow.push(f);
+                }
+                Err(e) => diag.report(e, Some(bar_no + 1), None),
+            }
+            ix += 1;
+            continue;
+        }
+
+        let system = &vs[ix..(ix + staves.len()).min(vs.len())];
+        ix += staves.len();
+        bar_no += 1;
+        if system.len() < staves.len() {
+            diag.report(Unexpected {
+                at: &system[0],
+                expected: format!("a bar for each of the {} staves",
+                                  staves.len()),
+                found: Some(format!("{} bars", system.len())),
+            }, Some(bar_no), None);
+            break;
+        }
+
         if to_drop > 0 {
             to_drop -= 1;
             continue;
         }
 
-        // Reset pitch for each bar.
-        xst.sharps.clear();
-        yst.sharps.clear();
+        let mut bad = false;
+        let mut bars = vec![];
+        let mut sys_tempo: Vec<(Ratio, TempoMark)> = vec![];
+        for (i, v) in system.iter().enumerate() {
+            // Reset pitch for each bar.
+            let st = &mut sts[i];
+            st.sharps.clear();
+
+            let mut errors = vec![];
+            let b = read_bar(v, st, &mut errors);
+            if errors.is_empty() {
+                // The first bar is allowed to be a pickup.
+                check_bar_dur(v, &b, meter, bar_no == 1, &mut errors);
+            }
+            for &(at, mark) in &b.tempo {
+                // Each staff may well repeat the marking.
+                match sys_tempo.iter().find(|x| {
+                    x.0 == at
+                        && mem::discriminant(&x.1) == mem::discriminant(&mark)
+                }) {
+                    Some(x) if x.1 != mark => errors.push(Unexpected {
+                        at: v,
+                        expected: "the same tempo marking as the other staves"
+                            .to_owned(),
+                        found: None,
+                    }),
+                    Some(_) => (),
+                    None => sys_tempo.push((at, mark)),
+                }
+            }
+            bad |= !errors.is_empty();
+            for e in errors {
+                diag.report(e, Some(bar_no), Some(&staves[i].name));
+            }
+            bars.push(b);
+        }
+        if !bad && bar_no == 1 {
+            check_pickup(&bars, &system[0], meter, diag);
+        }
+        flow.push(Flow::System(systems.len()));
+        systems.push(System {
+            dur: bars.iter().map(|b| b.dur()).max().unwrap_or_default(),
+            bars,
+            tempo: sys_tempo,
+        });
+    }
+
+    for (at, f) in jumps {
+        let needs = match f {
+            Flow::Jump { segno: true, .. } => Some(Flow::Segno),
+            Flow::Jump { coda: true, .. } | Flow::ToCoda => Some(Flow::Coda),
+            _ => None,
+        };
+        match needs {
+            Some(x) if !flow.contains(&x) => diag.report(Unexpected {
+                at,
+                expected: format!("a {} to go to",
+                                  if x == Flow::Segno { "segno" }
+                                  else { "coda" }),
+                found: None,
+            }, None, None),
+            _ => (),
+        }
+    }
+
+    // Lay the systems out in playing order.
+    let mut tracks: Vec<Track> = staves.iter().map(|staff| Track {
+        name: staff.name.clone(),
+        instrument: staff.instrument,
+        notes: vec![],
+        pedal: vec![],
+    }).collect();
+    let mut pos = Ratio::zero();
+    let mut prev = None;
+    for i in repeats::expand(&flow, opts.repeats) {
+        let sys = &systems[i];
+        // A tie goes on to the bar written next, not to where a repeat or
+        // jump leads.
+        if prev.is_some_and(|p| p + 1 != i) {
+            for t in &mut tracks {
+                if let Some(n) = t.notes.last_mut() {
+                    n.tie = false;
+                }
+            }
+        }
+        prev = Some(i);
+        for (t, b) in tracks.iter_mut().zip(&sys.bars) {
+            t.notes.extend(b.notes.iter().cloned());
+            t.pedal.extend(b.pedal.iter().map(|p| Pedal {
+                at: pos + p.at,
+                ..*p
+            }));
+        }
+        tempo.extend(sys.tempo.iter().map(|&(at, mark)| {
+            ((pos + at).to_f64(), mark)
+        }));
+        pos += sys.dur;
+    }
+    for t in &mut tracks {
+        t.notes = merge_ties(mem::take(&mut t.notes));
+    }
+    // Sorting is stable, so sheet-wide marks stay first.
+    tempo.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap());
+    Sheet { tracks, tempo: resolve_tempo(&tempo), meter, key }
+}
+
+// Repeats and jumps, written between systems.
+fn try_read_flow(v: &Value) -> Option<Res<'_, Flow>> {
+    if let Some(s) = v.as_symbol() {
+        let jump = |segno, coda| Flow::Jump { segno, coda };
+        return Some(Ok(match s {
+            "repeat-start" => Flow::RepeatStart,
+            "repeat-end" => Flow::RepeatEnd(None),
+            "segno" => Flow::Segno,
+            "coda" => Flow::Coda,
+            "to-coda" => Flow::ToCoda,
+            "fine" => Flow::Fine,
+            "dc" | "dc-al-fine" => jump(false, false),
+            "dc-al-coda" => jump(false, true),
+            "ds" | "ds-al-fine" => jump(true, false),
+            "ds-al-coda" => jump(true, true),
+            _ => return None,
+        }));
+    }
+
+    // (repeat-end 3), (ending 1 2)
+    let vs = as_list(v)?;
+    let tag = vs.first()?.as_symbol()?;
+    if tag != "repeat-end" && tag != "ending" {
+        return None;
+    }
+    let mut ns = vec![];
+    for n in &vs[1..] {
+        match n.as_i64() {
+            Some(n) if n > 0 => ns.push(n as u32),
+            _ => return Some(fail(n, "a pass number")),
+        }
+    }
+    Some(match (tag, &ns[..]) {
+        ("repeat-end", [n]) => Ok(Flow::RepeatEnd(Some(*n))),
+        ("ending", [_, ..]) => Ok(Flow::Ending(ns)),
+        _ => fail(v, "(repeat-end times) or (ending pass...)"),
+    })
+}
+
+fn check_bar_dur<'a>(v: &'a Value, b: &Bar, meter: Meter, pickup: bool,
+                     errors: &mut Vec<Unexpected<'a>>) {
+    let expected = meter.bar_dur();
+    let got = b.dur();
+    let ok = if pickup { got <= expected } else { got == expected };
+    if !ok {
+        errors.push(Unexpected {
+            at: v,
+            expected: format!("{} bar of {}/{}",
+                              if pickup { "a pickup or a" } else { "a" },
+                              meter.beats, meter.unit),
+            found: Some(format!("{} worth of notes",
+                                fmt_dur(got, meter.unit))),
+        });
+    }
+}
 
-        // Two tracks at a time.
-        let xb = read_bar(expect_list(x, "bar"), &mut xst);
-        let yb = read_bar(expect_list(y, "bar"), &mut yst);
-        xs.extend(xb.into_iter());
-        ys.extend(yb.into_iter());
+// All staves of a pickup bar have to agree, otherwise the hands drift apart
+// for the rest of the piece.
+fn check_pickup(bars: &[Bar], at: &Value, meter: Meter, diag: &mut Diag) {
+    let durs: Vec<Ratio> = bars.iter().map(|b| b.dur()).collect();
+    if durs.iter().any(|d| *d != durs[0]) {
+        let found = durs.iter().map(|d| fmt_dur(*d, meter.unit)).join(" vs ");
+        diag.report(Unexpected {
+            at,
+            expected: "staves of the same length in the pickup bar"
+                .to_owned(),
+            found: Some(found),
+        }, Some(1), None);
     }
-    vec![xs, ys]
 }
 
-fn read_bar(vs: &[Value], st: &mut TrackState) -> Track {
-    let mut out = vec![];
-    for v in vs {
-        read_cmd(v, st, &mut out);
+// Tied notes become one, with the pitch (and so the accidental) of the
+// first.
+pub fn merge_ties(notes: Vec<Note>) -> Vec<Note> {
+    let mut out: Vec<Note> = vec![];
+    for mut n in notes {
+        if let Pitch::Voices(vs) = &mut n.pitch {
+            for v in vs {
+                *v = merge_ties(mem::take(v));
+            }
+        }
+        match out.last_mut() {
+            Some(last) if last.tie => {
+                let len = last.dur() + n.dur();
+                // Only the end of the last note is let go early.
+                last.rest_after = n.rest_after * (n.dur() / len).to_f64();
+                last.duration = Duration::exact(len);
+                last.tie = n.tie;
+            }
+            _ => out.push(n),
+        }
     }
     out
 }
 
-fn read_cmd(v: &Value, st: &mut TrackState, out: &mut Track) {
-    match v {
-        Atom(Symbol(s)) => read_simple_cmd(s, st, out),
-        List(vs) => read_compound_cmd(vs, st, out),
-        _ => panic!("Not a cmd: {}", v),
+// Carries on after a bad command so that we can report the next one.
+fn read_bar<'a>(v: &'a Value, st: &mut TrackState<'a>,
+                errors: &mut Vec<Unexpected<'a>>) -> Bar {
+    let mut out = Bar::new();
+    match expect_list(v, "a bar") {
+        Ok(vs) => for v in vs {
+            if let Err(e) = read_cmd(v, st, &mut out) {
+                errors.push(e);
+            }
+        },
+        Err(e) => errors.push(e),
     }
+    out
 }
 
-fn read_simple_cmd(s: &str, st: &mut TrackState, out: &mut Track) {
+fn read_cmd<'a>(v: &'a Value, st: &mut TrackState<'a>, out: &mut Bar)
+    -> Res<'a, ()> {
+
+    let start = out.notes.len();
+    let r = match v {
+        Atom(Symbol(s)) => read_simple_cmd(v, s, st, out),
+        List(vs) if !vs.is_empty() => read_compound_cmd(vs, st, out),
+        _ => fail(v, "a note, a rest or a clef"),
+    };
+    if st.accent != 1. {
+        // Pending sfz or fp goes to the first note played after it.
+        let n = out.notes[start..].iter_mut().find(|n| !n.is_rest());
+        if let Some(n) = n {
+            // Within voices, those struck at once.
+            let accent = st.accent;
+            scale_amp(n, Ratio::zero(),
+                      &|at| if at.is_zero() { accent } else { 1. });
+            st.accent = 1.;
+        }
+    }
+    r
+}
+
+// Scales how loud a note is by where it starts. Voices carry no loudness
+// of their own, so it goes to their notes.
+fn scale_amp(n: &mut Note, at: Ratio, by: &impl Fn(Ratio) -> f32) {
+    match &mut n.pitch {
+        Pitch::Voices(vs) => for v in vs {
+            let mut pos = at;
+            for n in v {
+                scale_amp(n, pos, by);
+                pos += n.dur();
+            }
+        },
+        _ => n.amp *= by(at),
+    }
+}
+
+fn read_simple_cmd<'a>(v: &'a Value, s: &str, st: &mut TrackState<'a>,
+                       out: &mut Bar) -> Res<'a, ()> {
     if let Some(clef) = try_read_clef(s) {
         // Is a clef change
         st.clef = clef;
-    } else if let Some(dur) = try_read_duration(s) {
+    } else if s == "a-tempo" {
+        out.mark_tempo(TempoMark::ATempo);
+    } else if let Some(sustain) = try_read_sustain(s) {
+        st.sustain = sustain;
+        out.mark_pedal(st);
+    } else if s == "una-corda" || s == "tre-corde" {
+        st.una_corda = s == "una-corda";
+        out.mark_pedal(st);
+    } else if let Some(amp) = try_read_dynamic(s) {
+        st.dynamic = amp;
+    } else if s == "sfz" {
+        st.accent = SFORZANDO;
+    } else if s == "fp" {
+        // Forte on the next note, piano right after.
+        let p = try_read_dynamic("p").unwrap();
+        st.accent = try_read_dynamic("f").unwrap() / p;
+        st.dynamic = p;
+    } else if let Some(dur) = st.duration(s) {
         // Is a rest with duration
-        out.push(mk_rest(dur));
+        check_tie(v, st, &[])?;
+        out.notes.push(mk_rest(dur));
     } else {
-        panic!("Unknown simple cmd: {}", s);
-    }
-}
-
-fn read_compound_cmd(vs: &[Value], st: &mut TrackState, out: &mut Track) {
-    if let Some(tags) = as_list(&vs[0]) {
-        let tag = tags[0].as_symbol().expect("tag");
-        let rest = tags[1].as_symbol().expect("tag rest");
-        if tag == "acciac" && rest == "sharp" {
-            let rns = try_read_rawnote(
-                &vs[1], st).expect("rawnote");
-            assert_eq!(rns.len(), 1);
-            let rn = &rns[0];
-            let mut rns = rn.acciaccatura();
-            let p = rns[0].pitch[0];
-            st.add_sharp(p, 1);
-            rns[0].state = st.clone();
-            out.extend(rns.into_iter().map(|x| x.to_note()));
-        } else {
-            panic!("Unknown tag: {}", vs[0]);
-        }
-        return;
+        return fail(v, "a rest, a clef, a dynamic, a pedal or a-tempo");
     }
+    Ok(())
+}
+
+fn read_compound_cmd<'a>(vs: &'a [Value], st: &mut TrackState<'a>,
+                         out: &mut Bar) -> Res<'a, ()> {
+    if let Some((by, notes)) = try_read_octave_span(vs) {
+        // (8va notes...): played an octave higher than written.
+        st.octave += by;
+        let r: Res<()> = notes.iter().try_for_each(|v| read_cmd(v, st, out));
+        st.octave -= by;
+        return r;
+    }
+    let tag = match vs[0].as_symbol() {
+        Some(tag) => tag,
+        None => return fail(&vs[0], "a duration or a tag"),
+    };
+    if tag == "key" {
+        // Key change from here on: (key Eb major)
+        st.key = match vs {
+            [_, tonic, mode] => read_key_name(&vs[0], tonic, mode)?,
+            _ => return fail(&vs[0], "a key like (key Bb minor)"),
+        };
 
-    let tag = vs[0].as_symbol().expect("tag");
-    if tag == "^" {
-        // (^ note note): slur the notes
-        let mut to_slur = vec![];
+    } else if tag == "amp" {
+        // (amp 0.8): a dynamic by its amplitude, relative to mf.
+        st.dynamic = match vs {
+            [_, x] => match x.as_f64() {
+                Some(x) if x >= 0. => x as f32,
+                _ => return fail(x, "a non-negative amplitude"),
+            },
+            _ => return fail(&vs[0], "an amplitude like (amp 0.8)"),
+        };
+
+    } else if tag == "tempo" {
+        // Tempo change for all staves from here on.
+        out.mark_tempo(TempoMark::Set(read_tempo(vs)?));
+
+    } else if tag == "rit" || tag == "accel" {
+        out.mark_tempo(read_ramp(vs, st)?);
+
+    } else if tag == "fermata" {
+        // (fermata notes...) or (fermata 1.5 notes...)
+        let (factor, vs) = match vs.get(1).and_then(|v| v.as_f64()) {
+            Some(x) if x > 0. => (x, &vs[2..]),
+            _ => (FERMATA, &vs[1..]),
+        };
+        let start = out.dur();
+        out.mark_tempo(TempoMark::Stretch { len: 0., factor });
+        let ix = out.tempo.len() - 1;
+        for v in vs {
+            read_cmd(v, st, out)?;
+        }
+        let len = (out.dur() - start).to_f64();
+        out.tempo[ix].1 = TempoMark::Stretch { len, factor };
+
+    } else if tag == "cresc" || tag == "dim" {
+        // (cresc notes...) goes up one level over the notes,
+        // (dim pp notes...) down to pp.
+        let up = tag == "cresc";
+        let target = vs.get(1)
+            .and_then(|v| v.as_symbol())
+            .and_then(try_read_dynamic);
+        let (to, vs) = match target {
+            Some(to) => (to, &vs[2..]),
+            None => (step_dynamic(st.dynamic, up), &vs[1..]),
+        };
+        let from = st.dynamic;
+        let start = out.notes.len();
+        let start_pos = out.dur();
+        for v in vs {
+            read_cmd(v, st, out)?;
+        }
+        let len = out.dur() - start_pos;
+        let level = |at: Ratio| {
+            let x = (at / len).to_f64() as f32;
+            (from + (to - from) * x) / from
+        };
+        let mut pos = Ratio::zero();
+        for n in &mut out.notes[start..] {
+            scale_amp(n, pos, &level);
+            pos += n.dur();
+        }
+        st.dynamic = to;
+
+    } else if tag == "use" {
+        // (use name) or (use name :transpose -2), moving every pitch of the
+        // motif by staff steps. Accidentals and clefs are the ones in effect
+        // here.
+        let (name, by) = read_use(vs)?;
+        let body = match st.motifs.get(name) {
+            Some(body) => *body,
+            None => return fail(&vs[1], "the name of a defined motif"),
+        };
+        if st.using.contains(&name) {
+            return fail(&vs[1], "a motif that doesn't use itself");
+        }
+        st.transpose += by;
+        st.using.push(name);
+        let r: Res<()> = body.iter().try_for_each(|v| read_cmd(v, st, out));
+        st.using.pop();
+        st.transpose -= by;
+        r?;
+
+    } else if tag == "tuplet" {
+        // (tuplet 3 2 notes...): three notes in the time of two. Tuplets
+        // nest.
+        let n = vs.get(1).and_then(|v| v.as_i64());
+        let m = vs.get(2).and_then(|v| v.as_i64());
+        let (n, m) = match (n, m) {
+            (Some(n), Some(m)) if n > 0 && m > 0 && vs.len() > 3 => (n, m),
+            _ => return fail(&vs[0], "a tuplet like (tuplet 3 2 notes...)"),
+        };
+        let outer = st.tuplet;
+        st.tuplet = outer * Ratio::new(m, n);
+        let r: Res<()> = vs[3..].iter().try_for_each(|v| read_cmd(v, st, out));
+        st.tuplet = outer;
+        r?;
+
+    } else if tag == "tie" {
+        // (tie notes...): holds the notes as one.
+        let start = out.notes.len();
         for v in &vs[1..] {
-            to_slur.extend(try_read_rawnote(v, st).expect("slur")
-                           .iter()
-                           .map(|x| x.to_note()))
+            read_cmd(v, st, out)?;
         }
-        let len = to_slur.len();
-        for n in &mut to_slur[..len - 1] {
-            // TODO: Tweak
-            n.easing = 0.1;
-            n.rest_after = 0.;
+        let notes = &mut out.notes[start..];
+        if notes.len() < 2
+            || notes.iter().any(|n| n.is_rest() || n.pitch != notes[0].pitch) {
+            return fail(&vs[0], "notes of the same pitch to tie");
+        }
+        let last = notes.len() - 1;
+        for n in &mut notes[..last] {
+            n.tie = true;
         }
-        out.extend(to_slur.into_iter());
 
-    } else if tag == "staccato" {
-        let mut to_slur = vec![];
+    } else if tag == "voices" {
+        // (voices (notes...) (notes...)): timelines starting from here, all
+        // of the same length. Accidentals and clefs are the staff's, but
+        // each voice starts from the dynamic in force; the first one leads
+        // after. A pending sfz goes to every voice, once they're read.
+        if vs.len() < 2 {
+            return fail(&vs[0], "some voices like (voices (/2 1) (/4 3 4))");
+        }
+        check_tie(&vs[0], st, &[])?;
+        let dynamic = st.dynamic;
+        let accent = mem::replace(&mut st.accent, 1.);
+        let mut after = None;
+        let start = out.dur();
+        let mut voices = vec![];
         for v in &vs[1..] {
-            to_slur.extend(try_read_rawnote(v, st).expect("staccato")
-                           .iter()
-                           .map(|x| x.to_note()))
+            st.dynamic = dynamic;
+            let mut voice = Bar::new();
+            for v in expect_list(v, "a voice like (/4 1 2)")? {
+                read_cmd(v, st, &mut voice)?;
+            }
+            if st.tie.is_some() {
+                return fail(v, "ties that end within the voice");
+            }
+            after.get_or_insert(st.dynamic);
+            out.tempo.extend(voice.tempo.iter().map(|&(at, mark)| {
+                (start + at, mark)
+            }));
+            out.pedal.extend(voice.pedal.iter().map(|p| Pedal {
+                at: start + p.at,
+                ..*p
+            }));
+            voices.push(voice);
         }
-        let len = to_slur.len();
-        for n in &mut to_slur[..len - 1] {
-            // TODO: Tweak
-            n.rest_after = 0.5;
+        let lens: Vec<Ratio> = voices.iter().map(|b| b.dur()).collect();
+        if lens.iter().any(|x| *x != lens[0]) {
+            return Err(Unexpected {
+                at: &vs[0],
+                expected: "voices of the same length".to_owned(),
+                found: Some(lens.iter()
+                            .map(|d| fmt_dur(*d, st.meter.unit))
+                            .join(" vs ")),
+            });
+        }
+        st.dynamic = after.unwrap();
+        st.accent = accent;
+        let mut n = mk_rest(Duration::exact(lens[0]));
+        n.pitch = Pitch::Voices(voices.into_iter().map(|b| b.notes).collect());
+        out.notes.push(n);
+
+    } else if tag == "gap" {
+        // (gap 0.25 notes...): that share of every note is left silent,
+        // whatever the articulation.
+        let gap = match vs.get(1).and_then(|v| v.as_f64()) {
+            Some(x) if x >= 0. && vs.len() > 2 => x,
+            _ => return fail(&vs[0], "a gap like (gap 0.25 notes...)"),
+        };
+        let start = out.notes.len();
+        for v in &vs[2..] {
+            read_cmd(v, st, out)?;
+        }
+        for n in &mut out.notes[start..] {
+            if !n.is_rest() && n.as_voices().is_none() {
+                n.rest_after = gap;
+            }
+        }
+
+    } else if let Some(art) = Articulation::from_name(tag) {
+        // (^ notes...), (staccato notes...): shapes every note of the
+        // span, as the style says.
+        let start = out.notes.len();
+        for v in &vs[1..] {
+            read_cmd(v, st, out)?;
+        }
+        let shape = st.style.shape(art);
+        let mut notes: Vec<&mut Note> = out.notes[start..]
+            .iter_mut()
+            .filter(|n| !n.is_rest() && n.as_voices().is_none())
+            .collect();
+        if notes.is_empty() {
+            return fail(&vs[0], "some notes to articulate");
+        }
+        let last = notes.len() - 1;
+        for (i, n) in notes.iter_mut().enumerate() {
+            shape_note(n, shape, i == last);
         }
-        out.extend(to_slur.into_iter());
 
     } else {
-        let rns = try_read_rawnote_from_list(vs, st).expect("rawnote");
+        let rns = read_rawnote_from_list(vs, st)?;
         for rn in rns {
-            out.push(rn.to_note());
+            out.notes.push(rn.to_note());
         }
     }
+    Ok(())
+}
+
+fn read_use(vs: &[Value]) -> Res<'_, (&str, i32)> {
+    let expected = "a motif like (use name) or (use name :transpose -2)";
+    let (name, by) = match vs {
+        [_, name] => (name, 0),
+        [_, name, kw, by] if as_keyword(kw) == Some("transpose") =>
+            match by.as_i64() {
+                Some(by) => (name, by as i32),
+                None => return fail(by, "a number of staff steps"),
+            },
+        _ => return fail(&vs[0], expected),
+    };
+    match name.as_symbol() {
+        Some(name) => Ok((name, by)),
+        None => fail(name, "a motif name"),
+    }
 }
 
-struct RawNote {
+struct RawNote<'a> {
     dur: Duration,
-    state: TrackState,
+    state: TrackState<'a>,
     pitch: Vec<i32>,
+    tie: bool,
 }
 
-impl RawNote {
+impl<'a> RawNote<'a> {
     fn to_note(&self) -> Note {
         let p = if self.pitch.is_empty() {
             Pitch::Rest
         } else {
-            let fs: Vec<_> = self.pitch
+            let ts: Vec<_> = self.pitch
                 .iter()
-                .map(|p| self.state.freq_for_ix(*p))
+                .map(|p| self.state.tone_for_ix(*p))
                 .collect();
-            if fs.len() == 1 {
-                Pitch::Single(fs[0])
+            if ts.len() == 1 {
+                Pitch::Single(ts[0])
             } else {
-                Pitch::Chord(fs)
+                Pitch::Chord(ts)
             }
         };
-        mk_note(self.dur, p)
+        let mut n = mk_note(self.dur, p);
+        n.amp = self.state.dynamic;
+        n.tie = self.tie;
+        n
     }
 
-    fn trill(&self, chunks: i32) -> Vec<Self> {
-        let n = chunks;
-        let dur = self.dur.faster(n as usize);
+    // The note played as a figure of (steps from the note, share of its
+    // length), for ornaments.
+    fn figure(&self, steps: &[(i32, Ratio)], accs: &Neighbours) -> Vec<Self> {
         assert_eq!(self.pitch.len(), 1);
         let p = self.pitch[0];
-        let ps0 = vec![p, p + 1];
-        let ps = (0..(n / 2)).flat_map(|_| ps0.clone());
-        ps.map(|p| Self {
-                pitch: vec![p],
-                dur,
-                state: self.state.clone(),
-            })
-            .collect()
+        let mut out: Vec<Self> = steps.iter().map(|&(step, share)| {
+            let mut state = self.state.clone();
+            let acc = if step > 0 {
+                accs.upper
+            } else if step < 0 {
+                accs.lower
+            } else {
+                None
+            };
+            // Only for this note, unlike an accidental in the bar.
+            if let Some(n) = acc {
+                state.set_sharp(p + step, n);
+            }
+            Self {
+                dur: self.dur.scaled(share),
+                state,
+                pitch: vec![p + step],
+                tie: false,
+            }
+        }).collect();
+        out.last_mut().unwrap().tie = self.tie;
+        out
     }
 
-    // XXX: Kind of hard to render this right.
-    fn acciaccatura(&self) -> Vec<Self> {
-        let n = 2;
-        let mut dur = self.dur.faster(12);
-        let mut dur2 = dur.slower(11);
-        assert_eq!(self.pitch.len(), 1);
-        let p = self.pitch[0];
-        let mut st = self.state.clone();
-        vec![
-            Self {
-                pitch: vec![p - 1],
-                dur,
-                state: st.clone()
-            },
-            Self {
-                pitch: vec![p],
-                dur: dur2,
-                state: st.clone()
-            },
-        ]
+    // Grace notes (or an appoggiatura) taking their time from the note.
+    fn after_graces(mut self, graces: Vec<Self>) -> Option<Vec<Self>> {
+        let len: Ratio = graces.iter().map(|x| x.dur.dur()).sum();
+        let left = self.dur.dur() - len;
+        if left <= Ratio::zero() {
+            return None;
+        }
+        self.dur = Duration::exact(left);
+        let mut out = graces;
+        out.push(self);
+        Some(out)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Ornament {
+    // Number of notes, the step to start from (1 for the upper note) and
+    // whether it ends with a turn.
+    Trill { notes: i32, from: i32, turn: bool },
+    UpperMordent,
+    LowerMordent,
+    Turn,
+    InvertedTurn,
+    Acciaccatura,
+}
+
+// Accidentals for the upper and lower neighbours of an ornament, where
+// they differ from the ones in force.
+struct Neighbours {
+    upper: Option<i32>,
+    lower: Option<i32>,
+}
+
+impl Ornament {
+    fn steps(&self) -> Vec<(i32, Ratio)> {
+        let quick = Ratio::new(1, 8);
+        let steps = |xs: &[(i32, i64, i64)]| {
+            xs.iter().map(|&(x, n, d)| (x, Ratio::new(n, d))).collect()
+        };
+        match *self {
+            Ornament::Trill { notes, from, turn } => {
+                let notes = notes.max(2);
+                let mut xs: Vec<i32> = (0..notes).map(|i| match (from, i) {
+                    (-1, 0) => -1,
+                    (-1, _) | (0, _) => i % 2,
+                    _ => 1 - i % 2,
+                }).collect();
+                if turn {
+                    let n = xs.len();
+                    xs[n - 2] = -1;
+                    xs[n - 1] = 0;
+                }
+                let share = Ratio::new(1, notes as i64);
+                xs.into_iter().map(|x| (x, share)).collect()
+            }
+            Ornament::UpperMordent =>
+                vec![(0, quick), (1, quick), (0, Ratio::new(3, 4))],
+            Ornament::LowerMordent =>
+                vec![(0, quick), (-1, quick), (0, Ratio::new(3, 4))],
+            Ornament::Turn => steps(&[(1, 1, 4), (0, 1, 4), (-1, 1, 4),
+                                      (0, 1, 4)]),
+            Ornament::InvertedTurn => steps(&[(-1, 1, 4), (0, 1, 4),
+                                              (1, 1, 4), (0, 1, 4)]),
+            // XXX: Kind of hard to render this right.
+            Ornament::Acciaccatura => steps(&[(-1, 1, 12), (0, 11, 12)]),
+        }
+    }
+}
+
+// tr is a trill of 4 notes, tr32 one of 32.
+fn try_read_ornament(s: &str) -> Option<Ornament> {
+    Some(match s {
+        "upper-mordent" => Ornament::UpperMordent,
+        "lower-mordent" => Ornament::LowerMordent,
+        "turn" => Ornament::Turn,
+        "inverted-turn" => Ornament::InvertedTurn,
+        "acciac" => Ornament::Acciaccatura,
+        _ if s.starts_with("tr") => Ornament::Trill {
+            notes: if s == "tr" { 4 } else { i32::from_str(&s[2..]).ok()? },
+            from: 0,
+            turn: false,
+        },
+        _ => return None,
+    })
+}
+
+// Options before the notes of an ornament: :upper sharp and :lower natural
+// set the neighbours' accidentals, :from upper and :turn shape a trill.
+fn read_ornament_options<'a>(mut orn: Ornament, vs: &'a [Value])
+    -> Res<'a, (Ornament, Neighbours, &'a [Value])> {
+
+    let mut accs = Neighbours { upper: None, lower: None };
+    let mut i = 0;
+    while let Some(kw) = vs.get(i).and_then(as_keyword) {
+        let arg = vs.get(i + 1).unwrap_or(&vs[i]);
+        match (kw, &mut orn) {
+            ("upper", _) | ("lower", _) => {
+                let acc = match arg.as_symbol().and_then(try_read_accidental) {
+                    Some(n) => n,
+                    None => return fail(arg, "an accidental like sharp"),
+                };
+                if kw == "upper" {
+                    accs.upper = Some(acc);
+                } else {
+                    accs.lower = Some(acc);
+                }
+                i += 2;
+            }
+            ("from", Ornament::Trill { from, .. }) => {
+                *from = match arg.as_symbol() {
+                    Some("upper") => 1,
+                    Some("main") => 0,
+                    Some("lower") => -1,
+                    _ => return fail(arg, "upper, main or lower"),
+                };
+                i += 2;
+            }
+            ("turn", Ornament::Trill { turn, .. }) => {
+                *turn = true;
+                i += 1;
+            }
+            _ => return fail(&vs[i], "an option such as :upper sharp, \
+                                      or :from upper or :turn for trills"),
+        }
     }
+    Ok((orn, accs, &vs[i..]))
 }
 
-fn try_read_rawnote(v: &Value, st: &mut TrackState)
-    -> Option<Vec<RawNote>> {
+fn read_rawnote<'a>(v: &'a Value, st: &mut TrackState<'a>)
+    -> Res<'a, Vec<RawNote<'a>>> {
 
     if let Some(vs) = as_list(v) {
-        try_read_rawnote_from_list(vs, st)
-    } else {
-        let dur = try_read_duration(v.as_symbol()?)?;
+        if vs.is_empty() {
+            return fail(v, "a note");
+        }
+        read_rawnote_from_list(vs, st)
+    } else if let Some(dur) = v.as_symbol().and_then(|s| st.duration(s)) {
         // Duration only: is a rest
-        Some(vec![RawNote {
+        check_tie(v, st, &[])?;
+        Ok(vec![RawNote {
             dur,
             pitch: vec![],
             state: st.clone(),
+            tie: false,
         }])
+    } else {
+        fail(v, "a note or a rest")
     }
 }
 
-fn try_read_rawnote_from_list(vs: &[Value], st: &mut TrackState)
-    -> Option<Vec<RawNote>> {
+// For ornaments, which only make sense on one pitch.
+fn read_single_rawnote<'a>(v: &'a Value, st: &mut TrackState<'a>)
+    -> Res<'a, RawNote<'a>> {
 
-    let tag = vs[0].as_symbol()?;
-    if tag.starts_with("tr") {
-        let chunks = i32::from_str(&tag[2..]).unwrap_or(4);
-        // Trill
-        Some(vs[1..].iter().
-             flat_map(|v| {
-                 let rns = try_read_rawnote(v, st).expect("tr-rawnote");
-                 rns.into_iter().flat_map(|x| x.trill(chunks))
-             })
-             .collect())
+    let mut rns = read_rawnote(v, st)?;
+    if rns.len() != 1 || rns[0].pitch.len() != 1 {
+        return fail(v, "a single note (no chord or rest)");
+    }
+    Ok(rns.pop().unwrap())
+}
 
-    } else if tag == "acciac" {
-        // Acciaccatura
-        Some(vs[1..].iter().
-             flat_map(|v| {
-                 let rns = try_read_rawnote(v, st).expect("tr-rawnote");
-                 rns.into_iter().flat_map(|x| x.acciaccatura())
-             })
-             .collect())
+fn read_rawnote_from_list<'a>(vs: &'a [Value], st: &mut TrackState<'a>)
+    -> Res<'a, Vec<RawNote<'a>>> {
 
-    } else {
-        // Single or chord
-        let dur = try_read_duration(tag)?;
-        Some(vs[1..].iter().map(|v| RawNote {
-            dur,
-            pitch: read_pitch(v, st),
+    let tag = match vs[0].as_symbol() {
+        Some(tag) => tag,
+        None => return fail(&vs[0], "a duration or a tag"),
+    };
+    if let Some(orn) = try_read_ornament(tag) {
+        // (tr :upper flat (/8 8)), (turn (/4 5) (/4 3))...
+        let (orn, accs, vs) = read_ornament_options(orn, &vs[1..])?;
+        let steps = orn.steps();
+        let mut out = vec![];
+        for v in vs {
+            out.extend(read_single_rawnote(v, st)?.figure(&steps, &accs));
+        }
+        Ok(out)
+
+    } else if tag == "appog" || tag == "short-appog" {
+        // (appog 6 (/4 5)): the upper or lower note, on the beat. A long
+        // one takes half the note (two thirds if dotted).
+        let (aux, v) = match vs {
+            [_, aux, v] => (aux, v),
+            _ => return fail(&vs[0], "an appoggiatura like (appog 6 (/4 5))"),
+        };
+        let aux = read_pitch(aux, st)?;
+        let rn = read_single_rawnote(v, st)?;
+        let share = if tag == "short-appog" {
+            Ratio::new(1, 8)
+        } else if rn.dur.dots > 0 {
+            Ratio::new(2, 3)
+        } else {
+            Ratio::new(1, 2)
+        };
+        let grace = RawNote {
+            dur: rn.dur.scaled(share),
+            pitch: aux,
             state: st.clone(),
-        }).collect())
+            tie: false,
+        };
+        Ok(rn.after_graces(vec![grace]).unwrap())
+
+    } else if tag == "grace" {
+        // (grace (/32 3 4) (/4 5)): the grace notes as written, taking
+        // their time from the note.
+        if vs.len() < 3 {
+            return fail(&vs[0], "grace notes like (grace (/32 3 4) (/4 5))");
+        }
+        let mut graces = vec![];
+        for v in &vs[1..vs.len() - 1] {
+            graces.extend(read_rawnote(v, st)?);
+        }
+        let v = &vs[vs.len() - 1];
+        match read_single_rawnote(v, st)?.after_graces(graces) {
+            Some(out) => Ok(out),
+            None => fail(v, "a note longer than its grace notes"),
+        }
+
+    } else if let Some(dur) = st.duration(tag) {
+        // Single or chord, tied to the next note by 3~ or (1 3) ~
+        let mut out = vec![];
+        let mut i = 1;
+        while i < vs.len() {
+            let v = &vs[i];
+            let (pitch, tie) = match read_tied_pitch(v, st) {
+                Some(p) => (vec![p], true),
+                None => {
+                    let tie = vs.get(i + 1).and_then(|v| v.as_symbol())
+                        == Some("~");
+                    if tie {
+                        i += 1;
+                    }
+                    (read_pitch(v, st)?, tie)
+                }
+            };
+            i += 1;
+            check_tie(v, st, &pitch)?;
+            if tie {
+                st.tie = Some(pitch.clone());
+            }
+            out.push(RawNote {
+                dur,
+                pitch,
+                state: st.clone(),
+                tie,
+            });
+        }
+        Ok(out)
+
+    } else {
+        fail(&vs[0], "a duration (/4, /8. ...) or an ornament")
     }
 }
 
-fn read_norm_simple_pitch(v: &Value, st: &TrackState) -> Option<i32> {
-    Some(st.norm_pitch(v.as_i64()? as i32))
+// 3~ (or c5~) is a single pitch tied to the next note.
+fn read_tied_pitch(v: &Value, st: &mut TrackState) -> Option<i32> {
+    let s = v.as_symbol()?;
+    if !s.ends_with('~') {
+        return None;
+    }
+    let s = &s[..s.len() - 1];
+    match i32::from_str(s) {
+        Ok(ix) => Some(st.norm_pitch(ix)),
+        Err(_) => read_pitch_name(s, st),
+    }
+}
+
+// After a tie the same pitch has to follow.
+fn check_tie<'a>(v: &'a Value, st: &mut TrackState, pitch: &[i32])
+    -> Res<'a, ()> {
+
+    match st.tie.take() {
+        Some(ref tied) if tied[..] != *pitch =>
+            fail(v, "the same pitch as the note tied to it"),
+        _ => Ok(()),
+    }
+}
+
+// A position on the staff, or a note name like f#4.
+fn read_norm_simple_pitch<'a>(v: &'a Value, st: &mut TrackState<'a>)
+    -> Res<'a, i32> {
+
+    if let Some(ix) = v.as_i64() {
+        return Ok(st.norm_pitch(ix as i32));
+    }
+    match v.as_symbol().and_then(|s| read_pitch_name(s, st)) {
+        Some(p) => Ok(p),
+        None => fail(v, "a pitch (an integer or a name like f#4)"),
+    }
+}
+
+// An accidental in the name holds for the rest of the bar, like (sharp p).
+fn read_pitch_name(s: &str, st: &mut TrackState) -> Option<i32> {
+    let (ix, acc) = try_read_pitch_name(s)?;
+    let p = st.abs_pitch(ix);
+    if let Some(n) = acc {
+        st.set_sharp(p, n);
+    }
+    Some(p)
+}
+
+// c4 is middle C: the letter, then #, ##, b, bb or n (natural), then the
+// octave. Gives the pitch in steps from C5 and the accidental, if any.
+fn try_read_pitch_name(s: &str) -> Option<(i32, Option<i32>)> {
+    let mut cs = s.chars();
+    let degree = match cs.next()? {
+        'c' => 0,
+        'd' => 1,
+        'e' => 2,
+        'f' => 3,
+        'g' => 4,
+        'a' => 5,
+        'b' => 6,
+        _ => return None,
+    };
+    let rest = cs.as_str();
+    let octave_at = rest.find(|c: char| c.is_ascii_digit() || c == '-')?;
+    let acc = match &rest[..octave_at] {
+        "" => None,
+        "#" => Some(1),
+        "##" => Some(2),
+        "b" => Some(-1),
+        "bb" => Some(-2),
+        "n" => Some(0),
+        _ => return None,
+    };
+    let octave = i32::from_str(&rest[octave_at..]).ok()?;
+    Some(((octave - 5) * 7 + degree, acc))
 }
 
 // These are the notes that happen in the same time.
 // Empty means rest.
-fn read_pitch(v: &Value, st: &mut TrackState) -> Vec<i32> {
+fn read_pitch<'a>(v: &'a Value, st: &mut TrackState<'a>)
+    -> Res<'a, Vec<i32>> {
+
     if let Some("r") = v.as_symbol() {
-        vec![]
-    } else if let Some(p) = read_norm_simple_pitch(v, st) {
-        vec![p]
+        Ok(vec![])
+    } else if v.as_i64().is_some()
+        || v.as_symbol().and_then(try_read_pitch_name).is_some() {
+        Ok(vec![read_norm_simple_pitch(v, st)?])
     } else if let Some(vs) = as_list(v) {
-        // Either chord (1 2 3), or accidental (sharp 1)
-        if let Some(tag) = vs[0].as_symbol() {
-            let p = read_norm_simple_pitch(&vs[1], st).unwrap();
-            if tag == "sharp" {
-                st.add_sharp(p, 1);
-            } else if tag == "flat" {
-                st.add_sharp(p, -1);
-            } else if tag == "natural" {
-                st.reset_sharp(p);
-            } else {
-                panic!("read_pitch, tag = {}", tag);
-            }
-            vec![p]
+        // Either chord (1 2 3) or (c4 e4 g4), or accidental (sharp 1)
+        let tag = vs.first().and_then(|v| v.as_symbol())
+            .filter(|s| try_read_pitch_name(s).is_none());
+        if let Some(tag) = tag {
+            let p = match &vs[1..] {
+                [p] => read_norm_simple_pitch(p, st)?,
+                _ => return fail(v, "an accidental like (sharp 1)"),
+            };
+            let n = match try_read_accidental(tag) {
+                Some(n) => n,
+                None => return fail(&vs[0], "sharp, flat or natural"),
+            };
+            st.set_sharp(p, n);
+            Ok(vec![p])
+        } else if vs.is_empty() {
+            fail(v, "a pitch, a chord or an accidental")
         } else {
             // Chord
             vs.iter()
                 .map(|v| read_norm_simple_pitch(v, st))
-                .map(|v| v.expect("pitch"))
                 .collect()
         }
     } else {
-        panic!("Not a pitch: {}", v)
+        fail(v, "a pitch (like 3 or f#4), a chord, an accidental or r")
     }
 }
 
+// As a number of sharps.
+pub const ACCIDENTALS: [(&str, i32); 5] = [
+    ("sharp", 1),
+    ("flat", -1),
+    ("natural", 0),
+    ("double-sharp", 2),
+    ("double-flat", -2),
+];
+
+fn try_read_accidental(s: &str) -> Option<i32> {
+    ACCIDENTALS.iter().find(|x| x.0 == s).map(|x| x.1)
+}
+
 fn try_read_duration(s: &str) -> Option<Duration> {
-    if s.starts_with("/") {
-        let mut sp = &s[1..];
-        let mut dots = 0;
-        while sp.ends_with(".") {
-            sp = &sp[..sp.len() - 1];
-            dots += 1;
-        }
-        let klass = i32::from_str(sp).ok()?;
-        Some(Duration { klass, dots, longer: None })
-    } else {
-        None
+    let mut sp = s.strip_prefix('/')?;
+    let mut dots = 0;
+    while let Some(x) = sp.strip_suffix('.') {
+        sp = x;
+        dots += 1;
+    }
+    let klass = i32::from_str(sp).ok()?;
+    // Also keeps the lengths exact.
+    if klass <= 0 || dots > 8 {
+        return None;
     }
+    Some(Duration::new(klass, dots))
 }
 
-fn try_read_clef(v: &str) -> Option<Clef> {
-    Some(match v {
-        "treble-C" => Clef::Treble,
-        "bass-C" => Clef::Bass,
+// How far ped, half-ped and ped-up lift the dampers.
+fn try_read_sustain(s: &str) -> Option<f32> {
+    Some(match s {
+        "ped" => 1.,
+        "half-ped" => 0.5,
+        "ped-up" => 0.,
         _ => return None,
     })
 }
 
+// Octaves up or down, and the notes they span. lexpr reads 8va as the
+// number 8 and then the symbol va.
+fn try_read_octave_span(vs: &[Value]) -> Option<(i32, &[Value])> {
+    let (n, s, notes) = match vs {
+        [n, s, notes @ ..] => (n.as_u64()?, s.as_symbol()?, notes),
+        _ => return None,
+    };
+    let by = match (n, s) {
+        (8, "va") => 1,
+        (8, "vb") => -1,
+        (15, "ma") => 2,
+        (15, "mb") => -2,
+        _ => return None,
+    };
+    Some((by, notes))
+}
+
+pub const CLEFS: [(&str, Clef); 7] = [
+    ("treble-C", Clef::Treble),
+    ("bass-C", Clef::Bass),
+    ("alto-C", Clef::Alto),
+    ("tenor-C", Clef::Tenor),
+    ("treble-8vb-C", Clef::Treble8vb),
+    ("bass-8va-C", Clef::Bass8va),
+    ("percussion-C", Clef::Percussion),
+];
+
+fn try_read_clef(v: &str) -> Option<Clef> {
+    CLEFS.iter().find(|x| x.0 == v).map(|x| x.1)
+}
+
 // Note / sexp helpers
 
-fn mk_rest(dur: Duration) -> Note {
+// Share of a note left silent, unless said otherwise.
+pub const REST_AFTER: f64 = 0.1;
+
+pub fn mk_rest(dur: Duration) -> Note {
     mk_note(dur, Pitch::Rest)
 }
 
-fn mk_note(dur: Duration, pitch: Pitch) -> Note {
+pub fn mk_note(dur: Duration, pitch: Pitch) -> Note {
     Note {
         duration: dur,
         pitch,
 
         amp: 1.,
         easing: 0.05,
-        rest_after: 0.1,
+        rest_after: REST_AFTER,
+        tie: false,
     }
 }
 
-fn as_list<'a>(v: &'a Value) -> Option<&'a [Value]> {
+// As a span of articulated notes is shaped, `last` being its end.
+pub fn shape_note(n: &mut Note, shape: Shape, last: bool) {
+    n.amp *= shape.attack;
+    match (shape.gap, shape.length) {
+        (Some(gap), _) if last => n.rest_after = gap,
+        (_, Some(length)) => n.rest_after = 1. - length,
+        _ => (),
+    }
+}
+
+fn as_list(v: &Value) -> Option<&[Value]> {
     match v {
-        &List(ref vs) => Some(vs),
+        List(vs) => Some(vs),
         _ => None,
     }
 }
 
-fn expect_list<'a>(v: &'a Value, msg: &str) -> &'a [Value] {
-    as_list(v)
-        .expect(&format!("Expecting {} (a list), but got {}", msg, v))
+fn expect_list<'a>(v: &'a Value, msg: &str) -> Res<'a, &'a [Value]> {
+    match as_list(v) {
+        Some(vs) => Ok(vs),
+        None => fail(v, msg),
+    }
+}
+
+// The head of a toplevel form, such as define or include.
+fn form_tag(v: &Value) -> Option<&str> {
+    as_list(v).and_then(|vs| vs.first()).and_then(|v| v.as_symbol())
+}
+
+// :transpose reads as a plain symbol unless the parser is told about
+// keywords; take both.
+fn as_keyword(v: &Value) -> Option<&str> {
+    match v {
+        Atom(Atom::Keyword(k)) => Some(k),
+        Atom(Symbol(s)) if s.starts_with(':') => Some(&s[1..]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sheet_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+    }
+
+    fn first_steps(sh: &Sheet) -> Vec<i32> {
+        sh.tracks[0].notes.iter().filter_map(|n| match n.pitch {
+            Pitch::Single(t) => Some(t.step),
+            _ => None,
+        }).collect()
+    }
+
+    fn read(src: &str) -> Sheet {
+        read_sheet(src.as_bytes()).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // A 4/4 sheet of one bar, over a whole rest.
+    fn piano(rh: &str) -> String {
+        format!("(piano (4 4) (key C major) ((treble-C {}) (/1)))", rh)
+    }
+
+    fn expected(src: &str) -> Vec<String> {
+        errors(src).into_iter().map(|e| e.expected).collect()
+    }
+
+    fn errors(src: &str) -> Vec<Error> {
+        read_sheet(src.as_bytes()).err().map_or(vec![], |e| e.errors)
+    }
+
+    #[test]
+    fn reads_the_sonata() {
+        let opts = ReadOptions::default();
+        let score = read_score(sheet_path("kv545-sonata.ss"), &opts)
+            .unwrap_or_else(|e| panic!("{}", e));
+        let names: Vec<_> = score.movements.iter()
+            .map(|m| m.name.clone().unwrap_or_default())
+            .collect();
+        assert_eq!(names, ["Allegro", "Andante", "Rondo: Allegretto"]);
+        assert_eq!(score.catalogue.as_deref(), Some("K. 545"));
+    }
+
+    #[test]
+    fn comments_keep_positions() {
+        let src = "; a comment (\n(piano \"a ; b\" #| x\n) |# 1)";
+        let blanked = blank_comments(src);
+        assert_eq!(blanked.len(), src.len());
+        assert_eq!(blanked.lines().count(), src.lines().count());
+        assert!(blanked.contains("\"a ; b\""));
+        assert!(!blanked.contains('x'));
+    }
+
+    #[test]
+    fn motifs_belong_to_their_file() {
+        let dir = std::env::temp_dir().join("motif-scopes");
+        fs::create_dir_all(&dir).unwrap();
+        let movement = |step| format!(
+            "(define theme (/4 {0} {0} {0} {0}))\n\
+             (piano (4 4) (key C major) ((treble-C (use theme)) \
+             (bass-C /1)))", step);
+        fs::write(dir.join("a.ss"), movement(0)).unwrap();
+        fs::write(dir.join("b.ss"), movement(2)).unwrap();
+        fs::write(dir.join("work.ss"),
+                  "(include \"a.ss\")\n(include \"b.ss\")").unwrap();
+        let score = read_score(dir.join("work.ss"), &ReadOptions::default())
+            .unwrap_or_else(|e| panic!("{}", e));
+        let steps: Vec<Vec<i32>> = score.movements.iter()
+            .map(|m| first_steps(&m.sheet))
+            .collect();
+        // Two steps apart, whatever the clef starts from.
+        let b: Vec<i32> = steps[0].iter().map(|x| x + 2).collect();
+        assert_eq!(steps[1], b);
+    }
+
+    #[test]
+    fn errors_say_where_they_are() {
+        let dir = std::env::temp_dir().join("located-errors");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bad.ss");
+        let src = ["(piano (4 4) (key C major)",
+                   " (((/1 0)) ((/1 0))",
+                   "  ((/4 0 x 0 0)) ((/1 0))))"].join("\n");
+        fs::write(&path, src).unwrap();
+        let es = read_score(&path, &ReadOptions::default()).err().unwrap()
+            .errors;
+        assert_eq!(es.len(), 1);
+        let e = &es[0];
+        assert_eq!(e.file, Some(path.display().to_string()));
+        assert_eq!(e.pos, Some(Pos { line: 3, col: 10 }));
+        assert_eq!(e.bar, Some(2));
+        assert_eq!(e.track.as_deref(), Some("treble"));
+        assert_eq!(e.found, "x");
+    }
+
+    #[test]
+    fn reports_every_bad_bar() {
+        let es = errors(&["(piano (4 4) (key C major)",
+                          " (((/1 0)) ((/1 0))",
+                          "  ((/4 0 x 0 0)) ((/1 0))",
+                          "  ((/1 0)) ((/4 0 0 y 0))))"].join("\n"));
+        let found: Vec<_> = es.iter()
+            .map(|e| (e.pos.map(|p| (p.line, p.col)), e.bar,
+                      e.track.as_deref(), e.found.as_str()))
+            .collect();
+        assert_eq!(found, [(Some((3, 10)), Some(2), Some("treble"), "x"),
+                           (Some((4, 21)), Some(3), Some("bass"), "y")]);
+    }
+
+    #[test]
+    fn bars_have_to_fill_the_meter() {
+        let es = errors("(piano (3 4) (key C major) \
+                         (((/2. 0)) ((/2. 0)) ((/2. 0)) ((/2. 0)) \
+                          ((/2 0)) ((/2. 0))))");
+        let found: Vec<_> = es.iter()
+            .map(|e| (e.bar, e.expected.as_str(), e.found.as_str()))
+            .collect();
+        assert_eq!(found, [(Some(3), "a bar of 3/4", "2/4 worth of notes")]);
+    }
+
+    #[test]
+    fn only_the_first_bar_is_a_pickup() {
+        let bars = |first: &str, second: &str| format!(
+            "(piano (3 4) (key C major) \
+             ((({0})) (({0})) (({1})) (({1})) ((/2. 0)) ((/2. 0))))",
+            first, second);
+        assert!(errors(&bars("/4 0", "/2. 0")).is_empty());
+        let es = errors(&bars("/2. 0", "/4 0"));
+        assert_eq!(es.iter().map(|e| e.bar).collect::<Vec<_>>(),
+                   [Some(2), Some(2)]);
+        assert_eq!(es[0].expected, "a bar of 3/4");
+    }
+
+    #[test]
+    fn pickup_staves_agree() {
+        let es = errors("(piano (3 4) (key C major) \
+                         (((/4 0)) ((/2 0)) ((/2. 0)) ((/2. 0))))");
+        assert_eq!(es.len(), 1);
+        assert_eq!(es[0].bar, Some(1));
+        assert_eq!(es[0].found, "1/4 vs 2/4");
+    }
+
+    #[test]
+    fn motifs_move_by_staff_steps() {
+        let sh = read(&format!("(define up (/4 0 1)) {}",
+                               piano("(use up) (use up :transpose 2)")));
+        assert_eq!(first_steps(&sh), [-5, -4, -3, -2]);
+        let src = format!("(define up (/4 0) (use up)) {}",
+                          piano("(use up)"));
+        assert_eq!(expected(&src), ["a motif that doesn't use itself"]);
+    }
+
+    #[test]
+    fn tuplets_scale_exactly() {
+        let sh = read(&piano("(tuplet 3 2 (/8 0 1 2)) \
+                              (tuplet 3 2 (/4 0) (tuplet 3 2 (/8 0 0 0)) \
+                                          (/4 0)) \
+                              (/4 0)"));
+        let lens: Vec<Ratio> = sh.tracks[0].notes.iter()
+            .map(|n| n.dur())
+            .collect();
+        // Triplet eighths and quarters, and eighths in a triplet's triplet.
+        let (eighth, quarter) = (Ratio::new(1, 6), Ratio::new(1, 3));
+        let nested = Ratio::new(1, 9);
+        assert_eq!(lens, [eighth, eighth, eighth, quarter, nested, nested,
+                          nested, quarter, Ratio::new(1, 2)]);
+        assert_eq!(expected(&piano("(tuplet 3 (/8 0 1 2)) (/4. 0) (/2 0)")),
+                   ["a tuplet like (tuplet 3 2 notes...)"]);
+    }
+
+    #[test]
+    fn ties_merge_across_bars() {
+        let sh = read("(piano (4 4) (key C major) \
+                       ((treble-C (/2 0 (sharp 1) ~)) (/1) \
+                        ((tie (/4 1) (/8 1)) (/8 1) (/2 2)) (/1)))");
+        let notes: Vec<_> = sh.tracks[0].notes.iter()
+            .map(|n| (n.pitch.clone(), n.dur()))
+            .collect();
+        let e4 = Pitch::Single(Tone::new(-5, 0));
+        let fis4 = Pitch::Single(Tone::new(-4, 1));
+        let f4 = Pitch::Single(Tone::new(-4, 0));
+        let g4 = Pitch::Single(Tone::new(-3, 0));
+        // The tied note keeps the sharp of the first bar, the next one
+        // doesn't.
+        assert!(notes == [(e4, Ratio::one()),
+                          (fis4, Ratio::new(7, 4)),
+                          (f4, Ratio::new(1, 4)),
+                          (g4, Ratio::one())]);
+        assert_eq!(expected(&piano("(/2 0 0~) (/2 1)")),
+                   ["the same pitch as the note tied to it"]);
+    }
+
+    #[test]
+    fn voices_run_side_by_side() {
+        let sh = read(&piano("(voices ((/2 0) (/2 1)) ((/1 4)))"));
+        let notes = &sh.tracks[0].notes;
+        assert_eq!(notes.len(), 1);
+        let lens: Vec<Vec<Ratio>> = notes[0].as_voices().unwrap().iter()
+            .map(|v| v.iter().map(|n| n.dur()).collect())
+            .collect();
+        assert_eq!(lens, [vec![Ratio::one(); 2], vec![Ratio::from_int(2)]]);
+        let es = errors(&piano("(voices ((/2 0)) ((/1 4)))"));
+        assert_eq!(es.len(), 1);
+        assert_eq!(es[0].expected, "voices of the same length");
+        assert_eq!(es[0].found, "2/4 vs 4/4");
+    }
+
+    #[test]
+    fn clefs_and_octave_spans() {
+        let sh = read(&piano("bass-C (/4 0) alto-C (/4 0) (8va (/4 0)) \
+                              treble-8vb-C (15mb (/4 0))"));
+        // The bottom line of each clef, moved by the spans.
+        assert_eq!(first_steps(&sh), [-17, -11, -4, -26]);
+    }
+
+    #[test]
+    fn ornaments_fill_their_note() {
+        let sh = read(&piano("(tr (/4 0)) (tr6 (/4 0)) \
+                              (tr :from upper (/4 0)) (turn (/4 0))"));
+        let (e4, f4, d4) = (-5, -4, -6);
+        assert_eq!(first_steps(&sh), [e4, f4, e4, f4,
+                                      e4, f4, e4, f4, e4, f4,
+                                      f4, e4, f4, e4,
+                                      f4, e4, d4, e4]);
+        let lens: Vec<Ratio> = sh.tracks[0].notes.iter()
+            .map(|n| n.dur())
+            .collect();
+        assert_eq!(lens[..4], [Ratio::new(1, 8); 4]);
+        assert_eq!(lens[4..10], [Ratio::new(1, 12); 6]);
+        // Any tag starting with tr is a trill, or nothing.
+        assert_eq!(expected(&piano("(tremolo (/4 0)) (/2. 0)")),
+                   ["a duration (/4, /8. ...) or an ornament"]);
+        assert_eq!(expected(&piano("(turn :wobble (/4 0)) (/2. 0)")),
+                   ["an option such as :upper sharp, \
+                     or :from upper or :turn for trills"]);
+    }
+
+    #[test]
+    fn articulations_follow_the_style() {
+        let bar = "((treble-C (^ (/4 0 1)) (staccato (/2 2))) (/1))";
+        let rests = |src: &str| -> Vec<f64> {
+            read(src).tracks[0].notes.iter().map(|n| n.rest_after).collect()
+        };
+        // A slur only lets go at its end.
+        assert_eq!(rests(&format!("(piano (4 4) (key C major) {})", bar)),
+                   [0., 0.1, 0.5]);
+        assert_eq!(rests(&format!("(piano (4 4) (key C major) \
+                                   (style (staccato :length 0.4)) {})", bar)),
+                   [0., 0.1, 1. - 0.4]);
+        assert_eq!(expected(&format!("(piano (4 4) (key C major) \
+                                      (style (staccato :length 2)) {})", bar)),
+                   ["a share of the note, up to 1"]);
+    }
+
+    #[test]
+    fn pitch_names_are_absolute() {
+        let sh = read(&piano("(/4 c4 f#4 f4 bb3)"));
+        let tones: Vec<_> = sh.tracks[0].notes.iter()
+            .map(|n| n.pitch.clone())
+            .collect();
+        // The sharp holds to the end of the bar, as if written on the staff.
+        let tone = |step, sharp| Pitch::Single(Tone::new(step, sharp));
+        assert!(tones == [tone(-7, 0), tone(-4, 1), tone(-4, 1),
+                          tone(-8, -1)]);
+        assert_eq!(expected(&piano("(/4 c4 f#4 f4 h3)")),
+                   ["a pitch (like 3 or f#4), a chord, an accidental or r"]);
+    }
 }
@@ -1,27 +1,221 @@
 use crate::types::*;
 use crate::soundprim::*;
+use crate::ratio::Ratio;
 
-pub type Sheet = Vec<Track>;
-pub type Track = Vec<Note>;
+#[derive(Default)]
+pub struct Sheet {
+    pub tracks: Vec<Track>,
+    // Shared by all tracks.
+    pub tempo: TempoMap,
+    pub meter: Meter,
+    // At the start; the notes have their accidentals already.
+    pub key: Key,
+}
+
+// A work in movements, such as a sonata.
+#[derive(Default)]
+pub struct Score {
+    pub title: Option<String>,
+    pub composer: Option<String>,
+    // Such as "K. 545".
+    pub catalogue: Option<String>,
+    pub movements: Vec<Movement>,
+}
+
+pub struct Movement {
+    pub name: Option<String>,
+    pub sheet: Sheet,
+}
+
+pub struct Track {
+    // The staff it came from.
+    pub name: String,
+    pub instrument: Instrument,
+    pub notes: Vec<Note>,
+    // Sorted by position.
+    pub pedal: Vec<Pedal>,
+}
+
+// The pedals of a track from a position in the score on.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Pedal {
+    pub at: Ratio,
+    // How far the dampers are lifted: 1 for the sustain pedal all the way
+    // down, 0.5 for half pedal, 0 for none.
+    pub sustain: f32,
+    pub una_corda: bool,
+}
+
+// How long a note keeps ringing when the pedal is never released.
+const LET_RING: f64 = 3.;
+// Una corda plays softer, with a gentler attack and a duller tone.
+const UNA_CORDA_AMP: f32 = 0.75;
+
+// Relative strength of the overtones of a struck string, and of one struck
+// with the hammers shifted onto the softer felt.
+const PIANO: &[f32] = &[1., 0.3, 0.12, 0.05];
+const UNA_CORDA: &[f32] = &[1., 0.12, 0.03];
+// Relative strength of the overtones of a bowed string.
+const BOWED: &[f32] = &[1., 0.5, 0.3, 0.15];
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Instrument {
+    Piano,
+    Violin,
+    Viola,
+    Cello,
+    Voice,
+}
+
+// Used until the first tempo change.
+pub const DEFAULT_BPM: f64 = 120.;
+
+#[derive(Copy, Clone)]
+pub struct Tempo {
+    // Position in the same unit as Duration::dur.
+    pub at: f64,
+    // In quarters per minute.
+    pub bpm: f64,
+    // Whether we get here gradually from the previous point (rit., accel.),
+    // rather than jump here.
+    pub ramp: bool,
+}
+
+#[derive(Default)]
+pub struct TempoMap {
+    // Sorted by position.
+    pub points: Vec<Tempo>,
+}
+
+impl TempoMap {
+    // Wall-clock time of a position in the score.
+    pub fn time_at(&self, pos: f64) -> f64 {
+        let mut t = 0.;
+        let mut x = 0.;
+        let mut bpm = DEFAULT_BPM;
+        for p in &self.points {
+            if p.at >= pos {
+                if p.ramp && p.at > x {
+                    let bpm1 = bpm + (p.bpm - bpm) * (pos - x) / (p.at - x);
+                    t += ramp_secs(pos - x, bpm, bpm1);
+                } else {
+                    t += ramp_secs(pos - x, bpm, bpm);
+                }
+                return t;
+            }
+            t += ramp_secs(p.at - x, bpm, if p.ramp { p.bpm } else { bpm });
+            x = p.at;
+            bpm = p.bpm;
+        }
+        t + ramp_secs(pos - x, bpm, bpm)
+    }
+}
+
+// Seconds for a stretch of score whose tempo moves linearly from bpm0 to
+// bpm1. Integrates dx * 120 / bpm(x).
+fn ramp_secs(len: f64, bpm0: f64, bpm1: f64) -> f64 {
+    if (bpm1 - bpm0).abs() < 1e-9 {
+        len * 120. / bpm0
+    } else {
+        120. * len / (bpm1 - bpm0) * (bpm1 / bpm0).ln()
+    }
+}
+
+// As declared by the sheet.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Meter {
+    // (3 4) is three quarters per bar.
+    pub beats: i32,
+    pub unit: i32,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self { beats: 4, unit: 4 }
+    }
+}
+
+impl Meter {
+    // In the same unit as Duration::dur.
+    pub fn beat_dur(&self) -> Ratio {
+        Duration::new(self.unit, 0).dur()
+    }
+
+    pub fn bar_dur(&self) -> Ratio {
+        self.beat_dur() * Ratio::from_int(self.beats as i64)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Key {
+    // Number of sharps in the signature, negative for flats.
+    pub fifths: i32,
+    pub minor: bool,
+}
+
+// Scale degrees (0 = C) in the order their sharps appear in a signature.
+// Flats come in the reverse order.
+const SHARP_ORDER: [i32; 7] = [3, 0, 4, 1, 5, 2, 6];
+
+impl Default for Key {
+    fn default() -> Self {
+        Self::c_major()
+    }
+}
+
+impl Key {
+    pub fn c_major() -> Self {
+        Self { fifths: 0, minor: false }
+    }
+
+    // Sharps (or flats, if negative) the signature puts on a scale degree.
+    pub fn sharps_for(&self, degree: i32) -> i32 {
+        let n = self.fifths.unsigned_abs() as usize;
+        if self.fifths > 0 && SHARP_ORDER[..n].contains(&degree) {
+            1
+        } else if self.fifths < 0 && SHARP_ORDER[7 - n..].contains(&degree) {
+            -1
+        } else {
+            0
+        }
+    }
+}
 
 pub fn build_sheet(sh: &Sheet) -> impl Sound {
     let mut ss: Vec<Box<dyn Sound>> = sh
+        .tracks
         .iter()
         // Not sure why we can't box here in the map function.
-        .map(|t| build_track(t))
+        .map(|t| build_track(t, &sh.tempo))
         .collect();
     let last = ss.pop().unwrap();
     ss.into_iter().fold(last, |x, y| Box::new(superpos(x, y)))
 }
 
-pub fn build_track(tr: &Track) -> Box<dyn Sound> {
+// Seconds of silence between movements.
+const MOVEMENT_BREAK: f64 = 3.;
+
+// The movements one after another.
+pub fn build_score(sc: &Score) -> impl Sound {
+    let mut res: Box<dyn Sound> = Box::new(None.into_iter());
+    for (i, m) in sc.movements.iter().enumerate() {
+        let gap = if i == 0 { 0. } else { MOVEMENT_BREAK };
+        res = Box::new(res.chain(delay(gap, build_sheet(&m.sheet))));
+    }
+    res
+}
+
+pub fn build_track(tr: &Track, tempo: &TempoMap) -> Box<dyn Sound> {
     let mut b = Builder {
         res: None,
         t: 0.,
-        bpm: 120,
+        pos: Ratio::zero(),
+        tempo,
+        pedal: &tr.pedal,
+        instrument: tr.instrument,
     };
 
-    b.build(tr);
+    b.build(&tr.notes);
     if let Some(r) = b.res {
         Box::new(r)
     } else {
@@ -29,25 +223,71 @@ pub fn build_track(tr: &Track) -> Box<dyn Sound> {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct Duration {
     // duration = 1/klass. 2 = half, 4 = quad, 8 = eighth
     pub klass: i32,
     pub dots: i8,
-    pub longer: Option<f64>,
+    // Tuplets and other stretches: 2/3 within a triplet.
+    pub scale: Ratio,
 }
 
 impl Duration {
-    fn dur(&self) -> f64 {
-        let mult = self.longer.unwrap_or(1.);
-        mult * 2.0 / (self.klass as f64) * 1.5_f64.powi(self.dots as i32)
+    pub fn new(klass: i32, dots: i8) -> Self {
+        Self { klass, dots, scale: Ratio::one() }
+    }
+
+    // A whole note is 2.
+    pub fn dur(&self) -> Ratio {
+        // Each dot adds half of what the previous one added.
+        let dots = Ratio::new(2, 1) - Ratio::new(1, 1 << self.dots);
+        Ratio::new(2, self.klass as i64) * dots * self.scale
+    }
+
+    // Any length, such as that of tied notes.
+    pub fn exact(len: Ratio) -> Self {
+        Self {
+            klass: 1,
+            dots: 0,
+            scale: len / Ratio::from_int(2),
+        }
+    }
+
+    // A single written value (maybe dotted, or in a triplet) if there is
+    // one, as when reading a file that only has lengths.
+    pub fn from_len(len: Ratio) -> Self {
+        if len.numer() <= 0 {
+            return Self::exact(len);
+        }
+        for &(scale, most_dots) in &[(Ratio::one(), 3), (Ratio::new(2, 3), 0)] {
+            for dots in 0..=most_dots {
+                let d = Duration { klass: 1, dots, scale };
+                // The length of the value as a whole note; 2 / klass for
+                // others.
+                let k = d.dur() / len;
+                if k.denom() == 1 && k.numer() > 0 && k.numer() <= 256
+                    && k.numer() & (k.numer() - 1) == 0 {
+                    return Duration { klass: k.numer() as i32, dots, scale };
+                }
+            }
+        }
+        Self::exact(len)
+    }
+
+    // A share of the duration, such as the notes of an ornament.
+    pub fn scaled(&self, by: Ratio) -> Self {
+        Self {
+            klass: self.klass,
+            dots: self.dots,
+            scale: self.scale * by,
+        }
     }
 
     pub fn faster(&self, x: usize) -> Self {
         Self {
             klass: self.klass * x as i32,
             dots: self.dots,
-            longer: self.longer,
+            scale: self.scale,
         }
     }
 
@@ -55,40 +295,99 @@ impl Duration {
         Self {
             klass: self.klass,
             dots: self.dots,
-            longer: Some(self.longer.unwrap_or(1.) * x as f64),
+            scale: self.scale * Ratio::from_int(x as i64),
         }
     }
+
+}
+
+// A pitch as written: steps from C5, and sharps (flats, if negative).
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Tone {
+    pub step: i32,
+    pub sharp: i32,
 }
 
+impl Tone {
+    pub fn new(step: i32, sharp: i32) -> Self {
+        Self { step, sharp }
+    }
+
+    // The tuning.
+    pub fn freq(&self) -> f64 {
+        let (mut ix, mut pow2) = (self.step, 0);
+        while ix < 0 {
+            ix += 7;
+            pow2 -= 1;
+        }
+        while ix > 7 {
+            ix -= 7;
+            pow2 += 1;
+        }
+        OCTAVE_5[ix as usize]
+            * (2_f64.powi(pow2))
+            * (HALF_STEP.powi(self.sharp))
+    }
+
+    // As a MIDI key number, middle C (C4) being 60.
+    pub fn midi_key(&self) -> i32 {
+        const SEMITONES: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+        72 + 12 * self.step.div_euclid(7)
+            + SEMITONES[self.step.rem_euclid(7) as usize]
+            + self.sharp
+    }
+
+    // The same key with no more than a double sharp or flat, as sheets are
+    // written.
+    pub fn respelled(self) -> Self {
+        let key = self.midi_key();
+        let mut t = self;
+        while t.sharp.abs() > 2 {
+            let step = t.step + t.sharp.signum();
+            t = Tone::new(step, key - Tone::new(step, 0).midi_key());
+        }
+        t
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub enum Pitch {
     Rest,
-    Single(f64),
-    Chord(Vec<f64>),
+    Single(Tone),
+    Chord(Vec<Tone>),
+    // Independent timelines of the note's length, such as a held note
+    // under moving eighths on the same staff.
+    Voices(Vec<Vec<Note>>),
 }
 
 impl Pitch {
     fn is_rest(&self) -> bool {
+        matches!(self, Pitch::Rest)
+    }
+
+    fn as_chord(&self) -> Option<&[Tone]> {
         match self {
-            &Pitch::Rest => true,
-            _ => false,
+            Pitch::Chord(xs) => Some(xs),
+            _ => None,
         }
     }
 
-    fn as_chord(&self) -> Option<&[f64]> {
+    fn as_single(&self) -> Option<Tone> {
         match self {
-            &Pitch::Chord(ref xs) => Some(xs),
+            Pitch::Single(x) => Some(*x),
             _ => None,
         }
     }
 
-    fn as_single(&self) -> Option<f64> {
+    fn as_voices(&self) -> Option<&[Vec<Note>]> {
         match self {
-            &Pitch::Single(ref x) => Some(*x),
+            Pitch::Voices(vs) => Some(vs),
             _ => None,
         }
     }
 }
 
+#[derive(Clone, PartialEq)]
 pub struct Note {
     // Full duration, including easing and rest-after
     pub duration: Duration,
@@ -98,69 +397,135 @@ pub struct Note {
     // These are defined as percentage of duration
     pub easing: f64,
     pub rest_after: f64,
+
+    // Held into the next note. The reader merges tied notes, so this is
+    // only set while reading.
+    pub tie: bool,
 }
 
 impl Note {
-    fn is_rest(&self) -> bool {
+    pub fn is_rest(&self) -> bool {
         self.pitch.is_rest()
     }
 
-    fn as_chord(&self) -> Option<&[f64]> {
+    fn as_chord(&self) -> Option<&[Tone]> {
         self.pitch.as_chord()
     }
 
-    fn as_single(&self) -> Option<f64> {
+    fn as_single(&self) -> Option<Tone> {
         self.pitch.as_single()
     }
 
-    fn dur(&self) -> f64 {
+    pub fn as_voices(&self) -> Option<&[Vec<Note>]> {
+        self.pitch.as_voices()
+    }
+
+    pub fn dur(&self) -> Ratio {
         self.duration.dur()
     }
 }
 
-struct Builder {
+struct Builder<'a> {
     res: Option<Box<Sound>>,
     t: f64,
-    bpm: usize,
+    // Same as t, but in score time.
+    pos: Ratio,
+    tempo: &'a TempoMap,
+    instrument: Instrument,
+    pedal: &'a [Pedal],
 }
 
-impl Builder {
+impl<'a> Builder<'a> {
     fn build(&mut self, ns: &[Note]) {
         for n in ns.iter() {
             if n.is_rest() {
                 // Do nothing
             } else if let Some(ps) = n.as_chord() {
                 for p in ps {
-                    self.build_p(n, *p);
+                    self.build_p(n, p.freq());
+                }
+            } else if let Some(vs) = n.as_voices() {
+                // Each from the same point.
+                let (pos, t) = (self.pos, self.t);
+                for v in vs {
+                    self.build(v);
+                    self.pos = pos;
+                    self.t = t;
                 }
             } else {
-                self.build_p(n, n.as_single().unwrap());
+                self.build_p(n, n.as_single().unwrap().freq());
             }
 
-            self.t += n.dur() * self.dur_factor();
+            self.pos += n.dur();
+            self.t = self.tempo.time_at(self.pos.to_f64());
         }
     }
 
-    fn dur_factor(&self) -> f64 {
-        120. / self.bpm as f64
-    }
-
     fn build_p(&mut self, n: &Note, freq: f64) {
-        let dur = n.dur() * self.dur_factor();
+        let end = self.pos + n.dur();
+        let dur = self.tempo.time_at(end.to_f64()) - self.t;
         let sleep = dur * n.rest_after;
         let ease = dur * n.easing;
         let note_dur = dur - sleep;
-        let amp = n.amp;
+        let mut amp = n.amp;
 
-        let thiz = mult(
-            sine(freq, note_dur),
-            piano_envelope(note_dur))
-            // easing(ease, note_dur))
-            .map(move |x| x * amp); 
+        let thiz: Box<dyn Sound> = match self.instrument {
+            Instrument::Piano => {
+                let una_corda = self.pedal_at(self.pos)
+                    .is_some_and(|p| p.una_corda);
+                match self.held(end, self.t + note_dur) {
+                    None if !una_corda => Box::new(mult(
+                        harmonics(freq, note_dur, PIANO),
+                        piano_envelope(note_dur))),
+                        // easing(ease, note_dur))
+                    held => {
+                        let (held, sustain) = held.unwrap_or((0., 0.));
+                        let (peak, weights) = if una_corda {
+                            amp *= UNA_CORDA_AMP;
+                            (1.05, UNA_CORDA)
+                        } else {
+                            (1.2, PIANO)
+                        };
+                        Box::new(mult(
+                            harmonics(freq, note_dur + held, weights),
+                            pedal_envelope(note_dur, held, sustain, peak)))
+                    }
+                }
+            }
+            Instrument::Violin | Instrument::Viola | Instrument::Cello =>
+                Box::new(mult(
+                    harmonics(freq, note_dur, BOWED),
+                    sustained_envelope(note_dur))),
+            Instrument::Voice => Box::new(mult(
+                vibrato(freq, note_dur, 5.5, 0.006),
+                sustained_envelope(note_dur))),
+        };
+        let thiz = thiz.map(move |x| x * amp);
+        // Tracks that start with a rest (a late entry) need the delay too.
         if let Some(v) = self.res.take() {
             self.res = Some(Box::new(superpos(v, delay(self.t, thiz))));
         } else {
-            self.res = Some(Box::new(thiz));
+            self.res = Some(Box::new(delay(self.t, thiz)));
+        }
+    }
+
+    fn pedal_at(&self, pos: Ratio) -> Option<&Pedal> {
+        self.pedal.iter().take_while(|p| p.at <= pos).last()
+    }
+
+    // For a note written to end at `end` (sounding until `t`), how much
+    // longer the sustain pedal holds it, and how far down that pedal is.
+    fn held(&self, end: Ratio, t: f64) -> Option<(f64, f32)> {
+        let down = self.pedal.iter().take_while(|p| p.at < end).last()?;
+        if down.sustain == 0. {
+            return None;
         }
+        // Changing the pedal on the next note still damps this one.
+        let until = match self.pedal.iter()
+            .find(|p| p.at >= end && p.sustain == 0.) {
+            Some(up) => self.tempo.time_at(up.at.to_f64()),
+            None => t + LET_RING,
+        };
+        Some(((until - t).max(0.), down.sustain))
     }
 }
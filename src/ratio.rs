@@ -0,0 +1,146 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Sub, Mul, Div};
+
+// Exact fractions for lengths in the score. Floats won't do: three triplet
+// eighths have to add up to a quarter exactly, tuplets within tuplets too.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ratio {
+    // In lowest terms, with a positive denominator.
+    num: i64,
+    den: i64,
+}
+
+impl Ratio {
+    pub fn new(num: i64, den: i64) -> Self {
+        assert!(den != 0, "zero denominator");
+        let g = gcd(num, den);
+        let sign = if den < 0 { -1 } else { 1 };
+        Self { num: sign * num / g, den: sign * den / g }
+    }
+
+    pub fn from_int(n: i64) -> Self {
+        Self { num: n, den: 1 }
+    }
+
+    pub fn zero() -> Self {
+        Self::from_int(0)
+    }
+
+    pub fn one() -> Self {
+        Self::from_int(1)
+    }
+
+    pub fn numer(&self) -> i64 {
+        self.num
+    }
+
+    pub fn denom(&self) -> i64 {
+        self.den
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.num == 0
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a.max(1)
+}
+
+impl Default for Ratio {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl Add for Ratio {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        // Over the least common denominator, to stay clear of overflow.
+        let g = gcd(self.den, other.den);
+        Self::new(self.num * (other.den / g) + other.num * (self.den / g),
+                  self.den / g * other.den)
+    }
+}
+
+impl AddAssign for Ratio {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Ratio {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self + Self { num: -other.num, den: other.den }
+    }
+}
+
+impl Mul for Ratio {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        // Cross-cancel first, for the same reason.
+        let g1 = gcd(self.num, other.den);
+        let g2 = gcd(other.num, self.den);
+        Self::new((self.num / g1) * (other.num / g2),
+                  (self.den / g2) * (other.den / g1))
+    }
+}
+
+impl Div for Ratio {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        // Times the reciprocal, cross-cancelled as in mul.
+        let g1 = gcd(self.num, other.num);
+        let g2 = gcd(other.den, self.den);
+        Self::new((self.num / g1) * (other.den / g2),
+                  (self.den / g2) * (other.num / g1))
+    }
+}
+
+impl Ord for Ratio {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let x = self.num as i128 * other.den as i128;
+        let y = other.num as i128 * self.den as i128;
+        x.cmp(&y)
+    }
+}
+
+impl PartialOrd for Ratio {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Sum for Ratio {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::zero(), |x, y| x + y)
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.den == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+// Playing order of a sheet with repeats, voltas and D.C./D.S. jumps.
+
+#[derive(Clone, PartialEq)]
+pub enum Flow {
+    // The n-th system (one bar of every staff) of the sheet.
+    System(usize),
+    RepeatStart,
+    // How many times to play the section, if not given by the voltas.
+    RepeatEnd(Option<u32>),
+    // Volta bracket, played on the given passes.
+    Ending(Vec<u32>),
+    Segno,
+    Coda,
+    ToCoda,
+    Fine,
+    // D.C. (from the start) or D.S. (from the segno), al Fine or al Coda.
+    Jump { segno: bool, coda: bool },
+}
+
+// Systems in the order they are played. Without `repeats` every repeat is
+// played once, taking the last volta. Repeats are never taken again after a
+// D.C. or D.S., as is the custom.
+pub fn expand(flow: &[Flow], repeats: bool) -> Vec<usize> {
+    let mut out = vec![];
+    let mut pc = 0;
+    // Where the current repeat goes back to.
+    let mut section = 0;
+    let mut pass = 1;
+    // Times we went back from each repeat end.
+    let mut counts = HashMap::new();
+    // Within a volta that isn't for this pass.
+    let mut skipping = false;
+    // Some(al_coda) once we've taken the D.C. or D.S.
+    let mut jumped = None;
+    let mut coda_taken = false;
+
+    while pc < flow.len() {
+        let taking = repeats && jumped.is_none();
+        match &flow[pc] {
+            Flow::System(i) => {
+                if !skipping {
+                    out.push(*i);
+                }
+            }
+            Flow::RepeatStart => {
+                skipping = false;
+                section = pc + 1;
+                pass = 1;
+            }
+            Flow::RepeatEnd(times) => {
+                if skipping {
+                    // End of a volta we didn't take.
+                    skipping = false;
+                } else {
+                    if taking {
+                        let times = times.unwrap_or_else(|| {
+                            passes(flow, section)
+                        });
+                        let count = counts.entry(pc).or_insert(0);
+                        if *count + 1 < times {
+                            *count += 1;
+                            pass = *count + 1;
+                            pc = section;
+                            continue;
+                        }
+                    }
+                    // A repeat without a start goes back to here.
+                    section = pc + 1;
+                    match flow.get(pc + 1) {
+                        // Keep the pass for the voltas that follow.
+                        Some(Flow::Ending(_)) => (),
+                        _ => pass = 1,
+                    }
+                }
+            }
+            Flow::Ending(ns) => {
+                let pass = if taking { pass } else { passes(flow, section) };
+                skipping = !ns.contains(&pass);
+            }
+            Flow::Segno | Flow::Coda => (),
+            Flow::ToCoda => {
+                if !skipping && jumped == Some(true) && !coda_taken {
+                    coda_taken = true;
+                    let coda = flow.iter().position(|x| *x == Flow::Coda);
+                    if let Some(coda) = coda {
+                        pc = coda;
+                        continue;
+                    }
+                }
+            }
+            Flow::Fine => {
+                if !skipping && jumped == Some(false) {
+                    break;
+                }
+            }
+            Flow::Jump { segno, coda } => {
+                if !skipping && jumped.is_none() {
+                    jumped = Some(*coda);
+                    pc = if *segno {
+                        flow.iter().position(|x| *x == Flow::Segno)
+                            .unwrap_or(0)
+                    } else {
+                        0
+                    };
+                    section = pc;
+                    pass = 1;
+                    continue;
+                }
+            }
+        }
+        pc += 1;
+    }
+    out
+}
+
+// Number of passes through the repeat starting at `section`: as many as the
+// voltas ask for, and at least two.
+fn passes(flow: &[Flow], section: usize) -> u32 {
+    flow[section..]
+        .iter()
+        .take_while(|x| **x != Flow::RepeatStart)
+        .filter_map(|x| match x {
+            Flow::Ending(ns) => ns.iter().max().cloned(),
+            _ => None,
+        })
+        .fold(2, |x, y| x.max(y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Flow::*;
+    use crate::notation::read_sheet;
+    use crate::ratio::Ratio;
+
+    const DC: Flow = Jump { segno: false, coda: false };
+
+    #[test]
+    fn takes_each_volta_on_its_pass() {
+        let flow = [System(0), RepeatStart, System(1), Ending(vec![1]),
+                    System(2), RepeatEnd(None), Ending(vec![2]), System(3)];
+        assert_eq!(expand(&flow, true), [0, 1, 2, 1, 3]);
+        // Played through once, with the last volta.
+        assert_eq!(expand(&flow, false), [0, 1, 3]);
+    }
+
+    #[test]
+    fn ds_al_coda() {
+        let flow = [System(0), Segno, System(1), ToCoda, System(2),
+                    Jump { segno: true, coda: true }, Coda, System(3)];
+        assert_eq!(expand(&flow, true), [0, 1, 2, 1, 3]);
+    }
+
+    #[test]
+    fn dc_al_fine() {
+        let flow = [System(0), System(1), Fine, System(2), DC];
+        assert_eq!(expand(&flow, true), [0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn no_repeats_after_a_jump() {
+        let flow = [RepeatStart, System(0), RepeatEnd(None), System(1), DC];
+        assert_eq!(expand(&flow, true), [0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn ties_go_to_the_bar_written_next() {
+        // The first volta ties into the second one, not into the repeat.
+        let sh = read_sheet("(piano (4 4) (key C major) \
+                             (repeat-start ((/1 2)) ((/1 2)) \
+                              (ending 1) ((/1 0~)) ((/1 0)) repeat-end \
+                              (ending 2) ((/1 0)) ((/1 0))))".as_bytes())
+            .unwrap_or_else(|e| panic!("{}", e));
+        let lens: Vec<Ratio> = sh.tracks[0].notes.iter()
+            .map(|n| n.dur())
+            .collect();
+        // Four whole notes, none held into the repeat.
+        assert_eq!(lens, [Ratio::from_int(2); 4]);
+    }
+}
@@ -16,6 +16,41 @@ pub fn sine(freq: f64, duration: f64) -> impl Sound {
     })
 }
 
+// Sine with overtones, weights[k] being the strength of the (k+1)th
+// harmonic.
+pub fn harmonics(freq: f64, duration: f64, weights: &'static [f32])
+    -> impl Sound {
+    let ticks = (SAMPLE_RATE * duration) as usize;
+    let step = freq / SAMPLE_RATE * 2.0 * PI;
+    let total: f32 = weights.iter().sum();
+    GenIter(move || {
+        let mut x = 0_f64;
+        for _ in 0..ticks {
+            let mut y = 0.;
+            for (k, w) in weights.iter().enumerate() {
+                y += w * (x * (k + 1) as f64).sin() as f32;
+            }
+            yield y / total;
+            x += step;
+        }
+    })
+}
+
+// Sine whose pitch wobbles by `depth` (relative to freq) `rate` times a
+// second.
+pub fn vibrato(freq: f64, duration: f64, rate: f64, depth: f64)
+    -> impl Sound {
+    let ticks = (SAMPLE_RATE * duration) as usize;
+    GenIter(move || {
+        let mut x = 0_f64;
+        for t in 0..ticks {
+            yield x.sin() as f32;
+            let wobble = (t as f64 / SAMPLE_RATE * rate * 2.0 * PI).sin();
+            x += freq * (1. + depth * wobble) / SAMPLE_RATE * 2.0 * PI;
+        }
+    })
+}
+
 pub fn mult(x: impl Sound, y: impl Sound) -> impl Sound {
     x.zip(y).map(|(x, y)| x * y)
 }
@@ -31,6 +66,38 @@ pub fn piano_envelope(duration: f64) -> impl Sound {
         .chain(interpolate_to(0.7, 0., release))
 }
 
+// piano_envelope for a note the pedal keeps sounding `held` seconds past
+// its end. It keeps fading meanwhile, faster the less the dampers are lifted
+// (sustain 1 being all the way). A lower attack `peak` (1.2 normally) makes
+// the strike softer; the tone itself is the same sine.
+pub fn pedal_envelope(duration: f64, held: f64, sustain: f32, peak: f64)
+    -> impl Sound {
+    let attack = duration * 0.1;
+    let decay = duration * 0.05;
+    let sustain_t = duration * 0.7;
+    let release = duration * 0.15;
+    let fade = if held > 0. {
+        0.7 * 0.5 * (sustain as f64).powi(2)
+    } else {
+        0.7
+    };
+    interpolate_to(0., peak, attack)
+        .chain(interpolate_to(peak, 1., decay))
+        .chain(interpolate_to(1., 0.7, sustain_t))
+        .chain(interpolate_to(0.7, fade, held))
+        .chain(interpolate_to(fade, 0., release))
+}
+
+// Bowed strings and voices: no hammer, the note is held until released.
+pub fn sustained_envelope(duration: f64) -> impl Sound {
+    let attack = duration * 0.15;
+    let sustain = duration * 0.7;
+    let release = duration * 0.15;
+    interpolate_to(0., 1., attack)
+        .chain(interpolate_to(1., 0.9, sustain))
+        .chain(interpolate_to(0.9, 0., release))
+}
+
 fn interpolate_to(y0: f64, y1: f64, t: f64) -> impl Sound {
     let ticks = (t * SAMPLE_RATE) as usize;
     let dy = y1 - y0;
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fmt;
+use lexpr::Value::{self, *};
+
+// lexpr doesn't keep source locations, so we rescan the text and pair up
+// every datum with the Value that was parsed from it.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pos {
+    // Both 1-based.
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+pub struct SrcMap {
+    // Keyed by the address of the Value, which stays put as long as the
+    // parsed tree is alive.
+    pos: HashMap<usize, Pos>,
+}
+
+impl SrcMap {
+    // `forms` are the toplevel forms of `src`, in order.
+    pub fn new(src: &str, forms: &[Value]) -> Self {
+        let starts = scan(src);
+        let mut vals = vec![];
+        for v in forms {
+            preorder(v, &mut vals);
+        }
+
+        let mut pos = HashMap::new();
+        // If we disagree with lexpr about the shape (quotes, dotted lists...)
+        // we'd rather report no position than a wrong one.
+        if starts.len() == vals.len() {
+            for (v, p) in vals.into_iter().zip(starts) {
+                pos.insert(v as *const Value as usize, p);
+            }
+        }
+        Self { pos }
+    }
+
+    pub fn empty() -> Self {
+        Self { pos: HashMap::new() }
+    }
+
+    pub fn get(&self, v: &Value) -> Option<Pos> {
+        self.pos.get(&(v as *const Value as usize)).cloned()
+    }
+}
+
+fn preorder<'a>(v: &'a Value, out: &mut Vec<&'a Value>) {
+    out.push(v);
+    match v {
+        List(vs) | ImproperList(vs, _) => {
+            for v in vs {
+                preorder(v, out);
+            }
+        }
+        _ => (),
+    }
+}
+
+// Start position of every datum, in textual (= pre-) order.
+fn scan(src: &str) -> Vec<Pos> {
+    let mut out = vec![];
+    for (pos, t) in tokens(src) {
+        match t {
+            Token::Open(_) => out.push(pos),
+            Token::Atom(s) => {
+                // lexpr ends a number at the first character that can't go
+                // on with it: 8va is 8 then va, 3~ is 3 then ~.
+                let mut at = 0;
+                loop {
+                    out.push(Pos { line: pos.line, col: pos.col + at });
+                    let rest = &s[at..];
+                    if !rest.starts_with(|c: char| c.is_ascii_digit()
+                                         || c == '-') {
+                        break;
+                    }
+                    match rest[1..].find(|c: char| !c.is_ascii_digit()
+                                         && c != '.') {
+                        Some(n) => at += n + 1,
+                        None => break,
+                    }
+                }
+            }
+            Token::Close(_) | Token::Comment(_) => (),
+        }
+    }
+    out
+}
+
+pub enum Token {
+    Open(char),
+    Close(char),
+    // A symbol, a number or a string, as written.
+    Atom(String),
+    // From ; to the end of the line, or #| to |#.
+    Comment(String),
+}
+
+// The tokens of `src` and where each starts.
+pub fn tokens(src: &str) -> Vec<(Pos, Token)> {
+    let cs: Vec<char> = src.chars().collect();
+    let mut out = vec![];
+    let mut i = 0;
+    let mut line = 1;
+    let mut col = 1;
+
+    macro_rules! bump {
+        () => {{
+            if cs[i] == '\n' {
+                line += 1;
+                col = 1;
+            } else {
+                col += 1;
+            }
+            i += 1;
+        }}
+    }
+
+    while i < cs.len() {
+        let c = cs[i];
+        let (pos, start) = (Pos { line, col }, i);
+        if c.is_whitespace() {
+            bump!();
+            continue;
+        }
+        let t = if c == '(' || c == '[' {
+            bump!();
+            Token::Open(c)
+        } else if c == ')' || c == ']' {
+            bump!();
+            Token::Close(c)
+        } else if c == ';' {
+            while i < cs.len() && cs[i] != '\n' {
+                bump!();
+            }
+            Token::Comment(cs[start..i].iter().collect())
+        } else if c == '#' && cs.get(i + 1) == Some(&'|') {
+            while i < cs.len()
+                && !(cs[i] == '|' && cs.get(i + 1) == Some(&'#')) {
+                bump!();
+            }
+            for _ in 0..(cs.len() - i).min(2) {
+                bump!();
+            }
+            Token::Comment(cs[start..i].iter().collect())
+        } else if c == '"' {
+            bump!();
+            while i < cs.len() && cs[i] != '"' {
+                if cs[i] == '\\' {
+                    bump!();
+                }
+                if i < cs.len() {
+                    bump!();
+                }
+            }
+            if i < cs.len() {
+                bump!();
+            }
+            Token::Atom(cs[start..i].iter().collect())
+        } else {
+            while i < cs.len() && !is_delimiter(cs[i]) {
+                bump!();
+            }
+            Token::Atom(cs[start..i].iter().collect())
+        };
+        out.push((pos, t));
+    }
+    out
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]\";".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_numbers_like_lexpr() {
+        let src = "(/4 0~ -2)\n(8va x)";
+        let v = lexpr::from_str(&format!("({})", src)).unwrap();
+        let forms = match &v {
+            List(vs) => vs,
+            _ => unreachable!(),
+        };
+        let map = SrcMap::new(src, forms);
+        let mut vals = vec![];
+        for v in forms {
+            preorder(v, &mut vals);
+        }
+        let pos: Vec<_> = vals.iter()
+            .map(|v| map.get(v).map(|p| (p.line, p.col)))
+            .collect();
+        assert_eq!(pos, [(1, 1), (1, 2), (1, 5), (1, 6), (1, 8),
+                         (2, 1), (2, 2), (2, 3), (2, 6)]
+                   .map(Some));
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+// How articulations are played. The defaults can be overridden per sheet
+// with (style (staccato :length 0.4) ...).
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Articulation {
+    Slur,
+    Staccato,
+    Staccatissimo,
+    Tenuto,
+    Portato,
+    Accent,
+    Marcato,
+}
+
+impl Articulation {
+    // As written in the notation: (^ notes...), (staccato notes...)
+    pub fn from_name(s: &str) -> Option<Self> {
+        Some(match s {
+            "^" | "slur" => Articulation::Slur,
+            "staccato" => Articulation::Staccato,
+            "staccatissimo" => Articulation::Staccatissimo,
+            "tenuto" => Articulation::Tenuto,
+            "portato" => Articulation::Portato,
+            "accent" => Articulation::Accent,
+            "marcato" => Articulation::Marcato,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Shape {
+    // Share of the written value that sounds, if the articulation has a
+    // say in it.
+    pub length: Option<f64>,
+    // Multiplies the amplitude.
+    pub attack: f32,
+    // Share left silent after the last note of a span, where it differs
+    // from the other notes (the end of a slur).
+    pub gap: Option<f64>,
+}
+
+#[derive(Clone)]
+pub struct Style {
+    pub shapes: HashMap<Articulation, Shape>,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        let shape = |length, attack, gap| Shape { length, attack, gap };
+        let shapes = vec![
+            (Articulation::Slur, shape(Some(1.), 1., Some(0.1))),
+            (Articulation::Staccato, shape(Some(0.5), 1., None)),
+            (Articulation::Staccatissimo, shape(Some(0.25), 1.1, None)),
+            (Articulation::Tenuto, shape(Some(1.), 1.05, None)),
+            (Articulation::Portato, shape(Some(0.75), 1., None)),
+            (Articulation::Accent, shape(None, 1.4, None)),
+            (Articulation::Marcato, shape(Some(0.75), 1.6, None)),
+        ];
+        Self { shapes: shapes.into_iter().collect() }
+    }
+}
+
+impl Style {
+    pub fn shape(&self, a: Articulation) -> Shape {
+        self.shapes.get(&a).cloned().unwrap_or(Shape {
+            length: None,
+            attack: 1.,
+            gap: None,
+        })
+    }
+}
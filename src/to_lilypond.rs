@@ -0,0 +1,441 @@
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::vec;
+use crate::notes::*;
+use crate::ratio::Ratio;
+use crate::notation::{mk_rest, Clef, DYNAMICS};
+use crate::style::Style;
+use crate::to_musicxml::{self, tones_of, Event, Grace, Marks};
+use crate::to_ss::{self, Piece, StaffClef};
+
+// Writes a Sheet (or a whole Score) out as LilyPond, to be engraved. Bars,
+// clefs and the marks told back from the notes are as for MusicXML; the
+// pitches are absolute and LilyPond works out the accidentals itself.
+
+pub fn save(sh: &Sheet, name: &str) -> io::Result<()> {
+    fs::write(name, write_lilypond(sh))
+}
+
+pub fn save_score(sc: &Score, name: &str) -> io::Result<()> {
+    fs::write(name, write_score(sc))
+}
+
+const VERSION: &str = "\\version \"2.24.0\"\n";
+
+pub fn write_lilypond(sh: &Sheet) -> String {
+    format!("{}\n{}", VERSION, score_block(sh, None))
+}
+
+// The work's header, then a \score per movement.
+pub fn write_score(sc: &Score) -> String {
+    let mut out = VERSION.to_owned();
+    let fields = [
+        ("title", &sc.title),
+        ("composer", &sc.composer),
+        ("opus", &sc.catalogue),
+    ];
+    if fields.iter().any(|x| x.1.is_some()) {
+        out.push_str("\n\\header {\n");
+        for (name, value) in &fields {
+            if let Some(v) = value {
+                out.push_str(&format!("  {} = {}\n", name, quote(v)));
+            }
+        }
+        out.push_str("}\n");
+    }
+    for m in &sc.movements {
+        out.push('\n');
+        out.push_str(&score_block(&m.sheet, m.name.as_deref()));
+    }
+    out
+}
+
+fn score_block(sh: &Sheet, piece: Option<&str>) -> String {
+    let len = sh.tracks.iter()
+        .map(|t| t.notes.iter().map(|n| n.dur()).sum())
+        .max()
+        .unwrap_or_default();
+    let bar = sh.meter.bar_dur();
+    let starts = to_ss::bar_starts(len, bar);
+    let style = Style::default();
+    let written: Vec<(Vec<Note>, Vec<Marks>)> = sh.tracks.iter()
+        .map(|t| to_musicxml::written(&t.notes, &style))
+        .collect();
+    // Notes are cut where a tempo or pedal mark falls within them.
+    let events: Vec<Vec<(Ratio, Event)>> = (0..sh.tracks.len())
+        .map(|t| to_musicxml::events(sh, t))
+        .collect();
+    let layouts: Vec<Vec<Vec<Piece>>> = written.iter().zip(&events)
+        .map(|(w, evs)| {
+            let cuts: Vec<Ratio> = evs.iter().map(|x| x.0).collect();
+            to_ss::lay_out(&w.0, &starts, &cuts, len)
+        })
+        .collect();
+
+    let mut out = "\\score {\n".to_owned();
+    if let Some(name) = piece {
+        out.push_str(&format!("  \\header {{ piece = {} }}\n", quote(name)));
+    }
+    let piano = to_ss::is_piano(sh);
+    if piano {
+        out.push_str("  \\new PianoStaff \\with { instrumentName = \"Piano\" } \
+                      <<\n");
+    } else {
+        out.push_str("  <<\n");
+    }
+    for (t, evs) in events.into_iter().enumerate() {
+        let track = &sh.tracks[t];
+        let mut st = Staff {
+            clef: StaffClef::new(track, &layouts[t]),
+            dynamic: 1.,
+            events: evs.into_iter().peekable(),
+            tokens: vec![],
+        };
+        if piano {
+            out.push_str(&format!("    \\new Staff = {} {{\n",
+                                  quote(&track.name)));
+        } else {
+            out.push_str(&format!("    \\new Staff \\with {{ instrumentName \
+                                   = {} }} {{\n", quote(&track.name)));
+        }
+        out.push_str(&format!("      \\key {} \\time {}/{}\n",
+                              key_name(sh.key), sh.meter.beats,
+                              sh.meter.unit));
+        if starts.len() > 1 && starts[1] - starts[0] < bar {
+            out.push_str(&format!("      \\partial {}\n",
+                                  partial(starts[1] - starts[0])));
+        }
+        for b in 0..starts.len() {
+            let (from, to) = (starts[b], starts.get(b + 1).cloned()
+                              .unwrap_or(len));
+            st.write_bar(&layouts[t][b], &written[t].1, (from, to), b == 0,
+                         b + 1 == starts.len());
+            let bar = st.tokens.join(" ");
+            st.tokens.clear();
+            if b + 1 == starts.len() {
+                out.push_str(&format!("      {} \\bar \"|.\"\n", bar));
+            } else {
+                out.push_str(&format!("      {} |\n", bar));
+            }
+        }
+        out.push_str("    }\n");
+    }
+    out.push_str("  >>\n  \\layout { }\n}\n");
+    out
+}
+
+// One staff's music, a bar at a time.
+struct Staff {
+    clef: StaffClef,
+    dynamic: f32,
+    events: Peekable<vec::IntoIter<(Ratio, Event)>>,
+    // The bar being written.
+    tokens: Vec<String>,
+}
+
+impl Staff {
+    // A bar, with rests to fill it if the staff ends early.
+    fn write_bar(&mut self, pieces: &[Piece], marks: &[Marks],
+                 (from, to): (Ratio, Ratio), first: bool, last: bool) {
+        if self.clef.next_bar(pieces) || first {
+            let clef = clef_name(self.clef.current());
+            self.tokens.push(format!("\\clef {}", clef));
+        }
+
+        let end = pieces.last().map_or(from, |p| p.at + p.dur.dur());
+        let rest = mk_rest(Duration::exact(to - end));
+        let mut all = pieces.to_vec();
+        let mut at = end;
+        for dur in to_ss::spell_len(to - end) {
+            all.push(Piece {
+                note: &rest,
+                ix: usize::MAX,
+                at,
+                dur,
+                first: true,
+                last: true,
+            });
+            at += dur.dur();
+        }
+        self.write_line(&all, marks, true);
+        if last {
+            while let Some((_, ev)) = self.events.next() {
+                self.write_event(&ev);
+            }
+        }
+    }
+
+    // One voice's notes, along with the staff's events if it's the main
+    // line.
+    fn write_line(&mut self, pieces: &[Piece], marks: &[Marks], main: bool) {
+        let tuplets = to_musicxml::tuplet_marks(pieces);
+        let none = Marks::default();
+        for (i, p) in pieces.iter().enumerate() {
+            if main {
+                let end = p.at + p.dur.dur();
+                while self.events.peek().is_some_and(|ev| ev.0 < end) {
+                    let (_, ev) = self.events.next().unwrap();
+                    self.write_event(&ev);
+                }
+            }
+            let (start, stop) = tuplets[i];
+            if start {
+                let scale = p.dur.scale;
+                self.tokens.push(format!("\\tuplet {}/{} {{", scale.denom(),
+                                         scale.numer()));
+            }
+            let m = marks.get(p.ix).unwrap_or(&none);
+            match p.note.as_voices() {
+                Some(vs) => self.write_voices(vs, m),
+                None => self.write_note(p, m),
+            }
+            if stop {
+                self.tokens.push("}".to_owned());
+            }
+        }
+    }
+
+    // << { ... } \\ { ... } >>, the voices' notes tied across as written.
+    fn write_voices(&mut self, vs: &[Vec<Note>], m: &Marks) {
+        self.tokens.push("<<".to_owned());
+        for (k, v) in vs.iter().enumerate() {
+            if k > 0 {
+                self.tokens.push("\\\\".to_owned());
+            }
+            self.tokens.push("{".to_owned());
+            let mut at = Ratio::zero();
+            let mut ps = vec![];
+            for (ix, n) in v.iter().enumerate() {
+                let ds = to_ss::note_durs(n);
+                let count = ds.len();
+                for (j, dur) in ds.into_iter().enumerate() {
+                    ps.push(Piece {
+                        note: n,
+                        ix,
+                        at,
+                        dur,
+                        first: j == 0,
+                        last: j + 1 == count,
+                    });
+                    at += dur.dur();
+                }
+            }
+            let inner = m.voices.get(k).map_or(&[][..], |x| &x[..]);
+            self.write_line(&ps, inner, false);
+            self.tokens.push("}".to_owned());
+        }
+        self.tokens.push(">>".to_owned());
+    }
+
+    // Marks at a point between notes go on an empty chord.
+    fn write_event(&mut self, ev: &Event) {
+        let s = match ev {
+            Event::Tempo(bpm, true) => {
+                format!("\\tempo 4 = {}", bpm.round() as i64)
+            }
+            Event::Tempo(_, false) => return,
+            Event::Words(w) => format!("<>^\\markup {{ \\italic {} }}",
+                                       quote(w)),
+            Event::Pedal("start", _) => "<>\\sustainOn".to_owned(),
+            Event::Pedal("stop", _) => "<>\\sustainOff".to_owned(),
+            Event::Pedal(..) => "<>\\sustainOff\\sustainOn".to_owned(),
+        };
+        self.tokens.push(s);
+    }
+
+    fn write_note(&mut self, p: &Piece, m: &Marks) {
+        let n = p.note;
+        let dur = duration(p.dur);
+        let tones = tones_of(n);
+        if tones.is_empty() {
+            self.tokens.push(format!("r{}", dur));
+            return;
+        }
+        if let (Some((kind, gs)), true) = (&m.graces, p.first) {
+            let command = match kind {
+                Grace::Acciaccatura => "\\acciaccatura",
+                Grace::Appoggiatura => "\\appoggiatura",
+                Grace::Plain => "\\grace",
+            };
+            let notes: Vec<String> = gs.iter()
+                .map(|g| chord(&tones_of(g)) + &duration(g.duration))
+                .collect();
+            if notes.len() == 1 {
+                self.tokens.push(format!("{} {}", command, notes[0]));
+            } else {
+                self.tokens.push(format!("{} {{ {} }}", command,
+                                         notes.join(" ")));
+            }
+        }
+
+        let mut s = chord(&tones) + &dur;
+        if !p.last {
+            s.push('~');
+        }
+        if p.first {
+            if let Some(name) = self.dynamic(n.amp) {
+                s.push_str(&format!("\\{}", name));
+            }
+            // The upper note's accidental isn't shown.
+            if m.trill.is_some() {
+                s.push_str("\\trill");
+            }
+            match m.articulation {
+                Some("staccato") => s.push_str("-."),
+                Some("staccatissimo") => s.push_str("-!"),
+                _ => {}
+            }
+            if m.slur_start {
+                s.push('(');
+            }
+        }
+        if m.slur_stop && p.last {
+            s.push(')');
+        }
+        self.tokens.push(s);
+    }
+
+    // The dynamic to mark, if it changes. Accents and the like aren't
+    // dynamics; the one in force stays.
+    fn dynamic(&mut self, amp: f32) -> Option<&'static str> {
+        if amp == self.dynamic {
+            return None;
+        }
+        let name = DYNAMICS.iter().find(|x| x.1 == amp)?.0;
+        self.dynamic = amp;
+        Some(name)
+    }
+}
+
+fn chord(tones: &[Tone]) -> String {
+    let names: Vec<String> = tones.iter().map(|&t| pitch(t)).collect();
+    if names.len() == 1 {
+        names[0].clone()
+    } else {
+        format!("<{}>", names.join(" "))
+    }
+}
+
+// Absolute: c' is middle C.
+fn pitch(t: Tone) -> String {
+    const LETTERS: [&str; 7] = ["c", "d", "e", "f", "g", "a", "b"];
+    let mut s = LETTERS[t.step.rem_euclid(7) as usize].to_owned();
+    s.push_str(match t.sharp {
+        2 => "isis",
+        1 => "is",
+        -1 => "es",
+        -2 => "eses",
+        // tones_of respells the rest.
+        _ => "",
+    });
+    let octave = t.step.div_euclid(7) + 2;
+    for _ in 0..octave.abs() {
+        s.push(if octave > 0 { '\'' } else { ',' });
+    }
+    s
+}
+
+// The value as written; tuplets are around it.
+fn duration(d: Duration) -> String {
+    let mut s = d.klass.to_string();
+    for _ in 0..d.dots {
+        s.push('.');
+    }
+    s
+}
+
+// Such as 4 or 8*3 for the length of a pickup.
+fn partial(len: Ratio) -> String {
+    let whole = len / Ratio::from_int(2);
+    let (n, d) = (whole.numer(), whole.denom());
+    if d.count_ones() != 1 {
+        format!("1*{}/{}", n, d)
+    } else if n == 1 {
+        d.to_string()
+    } else {
+        format!("{}*{}", d, n)
+    }
+}
+
+fn key_name(key: Key) -> String {
+    const TONICS: [&str; 18] = [
+        "ces", "ges", "des", "aes", "ees", "bes", "f", "c", "g", "d", "a",
+        "e", "b", "fis", "cis", "gis", "dis", "ais",
+    ];
+    let i = key.fifths + 7 + if key.minor { 3 } else { 0 };
+    let tonic = TONICS.get(i as usize).cloned().unwrap_or("c");
+    let mode = if key.minor { "minor" } else { "major" };
+    format!("{} \\{}", tonic, mode)
+}
+
+fn clef_name(clef: Clef) -> &'static str {
+    match clef {
+        Clef::Treble => "treble",
+        Clef::Bass => "bass",
+        Clef::Alto => "alto",
+        Clef::Tenor => "tenor",
+        Clef::Treble8vb => "\"treble_8\"",
+        Clef::Bass8va => "\"bass^8\"",
+        Clef::Percussion => "percussion",
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::notation::{self, ReadOptions};
+
+    #[test]
+    fn writes_a_short_sheet() {
+        let src = "(piano (3 4) (key G major) (tempo 80) \
+            ((treble-C (/4 5) (tr (/8 7)) (/8 9) (staccato (/4 8))) \
+             (bass-C (/2. (0 2))) \
+             ((acciac (/2 5)) /4) ((/2. 0))))";
+        let sh = notation::read_sheet(src.as_bytes()).unwrap();
+        assert_eq!(write_lilypond(&sh), r#"\version "2.24.0"
+
+\score {
+  \new PianoStaff \with { instrumentName = "Piano" } <<
+    \new Staff = "treble" {
+      \key g \major \time 3/4
+      \clef treble \tempo 4 = 80 c''4 e''8\trill g''8 fis''4-. |
+      \acciaccatura b'8 c''2 r4 \bar "|."
+    }
+    \new Staff = "bass" {
+      \key g \major \time 3/4
+      \clef bass <g, b,>2. |
+      g,2. \bar "|."
+    }
+  >>
+  \layout { }
+}
+"#);
+    }
+
+    #[test]
+    fn respells_past_double_sharps() {
+        let src = "(piano (1 4) (key C major) ((treble-C (/4 0)) ((/4 0))))";
+        let mut sh = notation::read_sheet(src.as_bytes()).unwrap();
+        sh.tracks[0].notes[0].pitch = Pitch::Single(Tone::new(0, 3));
+        let out = write_lilypond(&sh);
+        assert!(out.contains("dis''4"), "{}", out);
+    }
+
+    #[test]
+    fn writes_every_movement() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("kv545-sonata.ss");
+        let sc = notation::read_score(path, &ReadOptions::default())
+            .unwrap();
+        let out = write_score(&sc);
+        assert_eq!(out.matches("\\score {").count(), sc.movements.len());
+        assert_eq!(out.matches('{').count(), out.matches('}').count());
+        assert_eq!(out.matches("<<").count(), out.matches(">>").count());
+    }
+}
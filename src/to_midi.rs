@@ -0,0 +1,340 @@
+use std::fs;
+use std::io;
+use crate::notes::*;
+use crate::ratio::Ratio;
+
+// Standard MIDI Files, type 1: a first track with the tempo, meter and key,
+// then one track per staff, each on a channel of its own.
+
+// Ticks per quarter.
+const DIVISION: u16 = 480;
+// The velocity of mf, where amp is 1.
+pub const MF_VELOCITY: f32 = 64.;
+// A rit. or accel. goes up or down every sixteenth.
+const RAMP_STEP: i64 = DIVISION as i64 / 4;
+// All but the drums' channel.
+const CHANNELS: usize = 15;
+
+pub fn save(sh: &Sheet, name: &str) -> io::Result<()> {
+    fs::write(name, write_smf(sh)?)
+}
+
+pub fn write_smf(sh: &Sheet) -> io::Result<Vec<u8>> {
+    if sh.tracks.len() > CHANNELS {
+        let msg = format!("{} staves, but MIDI has {} channels for them",
+                          sh.tracks.len(), CHANNELS);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    let mut header = vec![];
+    header.extend(&1_u16.to_be_bytes());
+    header.extend(&(sh.tracks.len() as u16 + 1).to_be_bytes());
+    header.extend(&DIVISION.to_be_bytes());
+
+    let mut out = vec![];
+    chunk(&mut out, b"MThd", &header);
+    chunk(&mut out, b"MTrk", &encode(conductor(sh)));
+    for (i, t) in sh.tracks.iter().enumerate() {
+        chunk(&mut out, b"MTrk", &encode(track_events(t, channel(i))));
+    }
+    Ok(out)
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    out.extend(kind);
+    out.extend(&(data.len() as u32).to_be_bytes());
+    out.extend(data);
+}
+
+// Channel 10 (9 from 0) is for drums.
+fn channel(track: usize) -> u8 {
+    if track < 9 { track as u8 } else { track as u8 + 1 }
+}
+
+struct Event {
+    tick: i64,
+    // At the same tick: notes end, then pedals and programs change, then
+    // notes start.
+    order: u8,
+    data: Vec<u8>,
+}
+
+fn meta(tick: i64, kind: u8, data: &[u8]) -> Event {
+    let mut bytes = vec![0xff, kind];
+    bytes.extend(var_len(data.len() as u32));
+    bytes.extend(data);
+    Event { tick, order: 1, data: bytes }
+}
+
+fn encode(mut events: Vec<Event>) -> Vec<u8> {
+    events.sort_by_key(|e| (e.tick, e.order));
+    let mut out = vec![];
+    let mut tick = 0;
+    for e in &events {
+        out.extend(var_len((e.tick - tick) as u32));
+        out.extend(&e.data);
+        tick = e.tick;
+    }
+    out.extend(&[0, 0xff, 0x2f, 0]);
+    out
+}
+
+// Lengths and delta times are written seven bits at a time, the last byte
+// without the top bit, in up to this many bytes.
+pub const VAR_LEN_BYTES: usize = 4;
+
+fn var_len(mut x: u32) -> Vec<u8> {
+    let mut out = vec![(x & 0x7f) as u8];
+    x >>= 7;
+    while x > 0 {
+        out.push((x & 0x7f) as u8 | 0x80);
+        x >>= 7;
+    }
+    out.reverse();
+    out
+}
+
+// The number at the start of data, and how many bytes it took; None if it
+// runs past the end or past VAR_LEN_BYTES.
+pub fn read_var_len(data: &[u8]) -> Option<(u32, usize)> {
+    let mut x = 0;
+    for (i, &b) in data.iter().take(VAR_LEN_BYTES).enumerate() {
+        x = (x << 7) | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            return Some((x, i + 1));
+        }
+    }
+    None
+}
+
+// A position in the score (a quarter is 0.5).
+fn ticks(pos: f64) -> i64 {
+    (pos * 2. * DIVISION as f64).round() as i64
+}
+
+fn conductor(sh: &Sheet) -> Vec<Event> {
+    let m = sh.meter;
+    let mut out = vec![
+        meta(0, 0x58, &[
+            m.beats as u8,
+            (m.unit as u32).trailing_zeros() as u8,
+            // MIDI clocks per beat, 24 to the quarter, and at least one
+            // for beats shorter than a 64th.
+            (96 / m.unit).max(1) as u8,
+            8,
+        ]),
+        meta(0, 0x59, &[sh.key.fifths as i8 as u8, sh.key.minor as u8]),
+    ];
+
+    let set_tempo = |tick, bpm: f64| {
+        let usecs = (60_000_000. / bpm).round() as u32;
+        meta(tick, 0x51, &usecs.to_be_bytes()[1..])
+    };
+    if sh.tempo.points.first().is_none_or(|p| p.at > 0.) {
+        out.push(set_tempo(0, DEFAULT_BPM));
+    }
+    let mut x = 0.;
+    for p in &sh.tempo.points {
+        if p.ramp {
+            // Steps whose length in time is that of the ramp there.
+            let (from, to) = (ticks(x), ticks(p.at));
+            let time = |t: i64| sh.tempo.time_at(t as f64 / ticks(1.) as f64);
+            let mut tick = from;
+            while tick < to {
+                let next = (tick + RAMP_STEP).min(to);
+                let secs = time(next) - time(tick);
+                let quarters = (next - tick) as f64 / DIVISION as f64;
+                out.push(set_tempo(tick, 60. * quarters / secs));
+                tick = next;
+            }
+        }
+        out.push(set_tempo(ticks(p.at), p.bpm));
+        x = p.at;
+    }
+    out
+}
+
+// General MIDI, from 0.
+pub fn program(i: Instrument) -> u8 {
+    match i {
+        Instrument::Piano => 0,
+        Instrument::Violin => 40,
+        Instrument::Viola => 41,
+        Instrument::Cello => 42,
+        // Choir aahs.
+        Instrument::Voice => 52,
+    }
+}
+
+fn track_events(t: &Track, ch: u8) -> Vec<Event> {
+    let program = program(t.instrument);
+    let mut out = vec![
+        meta(0, 0x03, t.name.as_bytes()),
+        Event { tick: 0, order: 1, data: vec![0xc0 | ch, program] },
+    ];
+    for p in &t.pedal {
+        let tick = ticks(p.at.to_f64());
+        let sustain = (p.sustain * 127.).round() as u8;
+        let soft = if p.una_corda { 127 } else { 0 };
+        out.push(Event { tick, order: 1, data: vec![0xb0 | ch, 64, sustain] });
+        out.push(Event { tick, order: 1, data: vec![0xb0 | ch, 67, soft] });
+    }
+    note_events(&t.notes, Ratio::zero(), ch, &mut out);
+    share_keys(&mut out);
+    out
+}
+
+// Voices that strike a key another one still holds share it: only the last
+// of them to let go ends it.
+fn share_keys(events: &mut Vec<Event>) {
+    events.sort_by_key(|e| (e.tick, e.order));
+    let mut held = [0_u32; 128];
+    events.retain(|e| match e.data[..] {
+        [s, key, _] if s & 0xf0 == 0x90 => {
+            held[key as usize] += 1;
+            true
+        }
+        [s, key, _] if s & 0xf0 == 0x80 => {
+            let h = &mut held[key as usize];
+            *h = h.saturating_sub(1);
+            *h == 0
+        }
+        _ => true,
+    });
+}
+
+// The notes from `at` on.
+fn note_events(notes: &[Note], mut at: Ratio, ch: u8, out: &mut Vec<Event>) {
+    for n in notes {
+        let tones: &[Tone] = match &n.pitch {
+            Pitch::Rest => &[],
+            Pitch::Single(t) => std::slice::from_ref(t),
+            Pitch::Chord(ts) => ts,
+            Pitch::Voices(vs) => {
+                for v in vs {
+                    note_events(v, at, ch, out);
+                }
+                &[]
+            }
+        };
+        let on = ticks(at.to_f64());
+        let len = n.dur().to_f64() * (1. - n.rest_after);
+        let off = ticks(at.to_f64() + len).max(on + 1);
+        let vel = (n.amp * MF_VELOCITY).round().clamp(1., 127.) as u8;
+        for t in tones {
+            let key = t.midi_key().clamp(0, 127) as u8;
+            let on_data = vec![0x90 | ch, key, vel];
+            out.push(Event { tick: on, order: 2, data: on_data });
+            let off_data = vec![0x80 | ch, key, 0];
+            out.push(Event { tick: off, order: 0, data: off_data });
+        }
+        at += n.dur();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::{from_midi, notation};
+
+    // Where each key is struck.
+    fn onsets(notes: &[Note], mut at: Ratio, out: &mut Vec<(Ratio, i32)>) {
+        for n in notes {
+            match &n.pitch {
+                Pitch::Rest => (),
+                Pitch::Single(t) => out.push((at, t.midi_key())),
+                Pitch::Chord(ts) => {
+                    out.extend(ts.iter().map(|t| (at, t.midi_key())));
+                }
+                Pitch::Voices(vs) => {
+                    for v in vs {
+                        onsets(v, at, out);
+                    }
+                }
+            }
+            at += n.dur();
+        }
+    }
+
+    fn all_onsets(sh: &Sheet) -> Vec<(Ratio, i32)> {
+        let mut out = vec![];
+        for t in &sh.tracks {
+            onsets(&t.notes, Ratio::zero(), &mut out);
+        }
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn reads_back_var_lens() {
+        for x in [0, 0x7f, 0x80, 480, 0x3fff, 0x4000, 0x0fff_ffff] {
+            let bytes = var_len(x);
+            assert_eq!(read_var_len(&bytes), Some((x, bytes.len())));
+        }
+        assert_eq!(read_var_len(&[0x81, 0x80]), None);
+        assert_eq!(read_var_len(&[0x81, 0x80, 0x80, 0x80, 0]), None);
+    }
+
+    #[test]
+    fn keeps_every_onset() {
+        for f in &["kv545.ss", "kv545-m2.ss", "kv545-m3.ss"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(f);
+            let src = fs::read_to_string(path).unwrap();
+            let sh = notation::read_sheet(src.as_bytes()).unwrap();
+            let smf = write_smf(&sh).unwrap();
+            let back = from_midi::read_smf(&smf[..]).unwrap();
+            assert_eq!(all_onsets(&sh), all_onsets(&back), "{}", f);
+            assert_eq!((back.meter.beats, back.meter.unit),
+                       (sh.meter.beats, sh.meter.unit), "{}", f);
+            assert!(back.key == sh.key, "{}", f);
+        }
+    }
+
+    #[test]
+    fn lets_go_of_a_shared_key_last() {
+        // The half note is struck again while the whole note holds it.
+        let src = "(piano (4 4) (key C major) \
+            ((treble-C (voices ((/1 0)) ((/2 0) (/2 1)))) \
+             (bass-C (/1 0))))";
+        let sh = notation::read_sheet(src.as_bytes()).unwrap();
+        let key = match &sh.tracks[0].notes[0].pitch {
+            Pitch::Voices(vs) => match vs[0][0].pitch {
+                Pitch::Single(t) => t.midi_key() as u8,
+                _ => panic!("a single tone"),
+            },
+            _ => panic!("voices"),
+        };
+        let kinds: Vec<(i64, u8)> = track_events(&sh.tracks[0], 0).iter()
+            .filter(|e| e.data.len() == 3 && e.data[1] == key)
+            .map(|e| (e.tick, e.data[0]))
+            .collect();
+        assert_eq!(kinds.iter().map(|x| x.1).collect::<Vec<_>>(),
+                   vec![0x90, 0x90, 0x80]);
+        assert!(kinds[2].0 > ticks(1.));
+    }
+
+    #[test]
+    fn keeps_a_clock_for_short_beats() {
+        let sh = Sheet {
+            meter: Meter { beats: 3, unit: 128 },
+            ..Default::default()
+        };
+        assert_eq!(conductor(&sh)[0].data, vec![0xff, 0x58, 4, 3, 7, 1, 8]);
+    }
+
+    #[test]
+    fn refuses_more_staves_than_channels() {
+        let mut sh = Sheet::default();
+        for i in 0..16 {
+            sh.tracks.push(Track {
+                name: i.to_string(),
+                instrument: Instrument::Piano,
+                notes: vec![],
+                pedal: vec![],
+            });
+        }
+        assert!(write_smf(&sh).is_err());
+        sh.tracks.pop();
+        assert!(write_smf(&sh).is_ok());
+    }
+}
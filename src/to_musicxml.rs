@@ -0,0 +1,1085 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::vec;
+use crate::notes::*;
+use crate::ratio::Ratio;
+use crate::notation::{mk_rest, Clef, DYNAMICS};
+use crate::style::{Articulation, Style};
+use crate::to_midi;
+use crate::to_ss::{self, Piece, StaffClef};
+
+// Writes a Sheet out as partwise MusicXML, to be looked at in an engraving
+// app. The bars, clefs and accidentals are worked out as for .ss. Slurs,
+// staccatos, trills and grace notes are gone by the time the sheet is
+// read, so they are told back from the gaps and the quick notes they left.
+
+pub fn save(sh: &Sheet, name: &str) -> io::Result<()> {
+    fs::write(name, write_musicxml(sh))
+}
+
+const DOCTYPE: &str = "<!DOCTYPE score-partwise PUBLIC \
+    \"-//Recordare//DTD MusicXML 3.1 Partwise//EN\" \
+    \"http://www.musicxml.org/dtds/partwise.dtd\">";
+
+pub fn write_musicxml(sh: &Sheet) -> String {
+    let len = sh.tracks.iter()
+        .map(|t| t.notes.iter().map(|n| n.dur()).sum())
+        .max()
+        .unwrap_or_default();
+    let bar = sh.meter.bar_dur();
+    let starts = to_ss::bar_starts(len, bar);
+    // The sheet's own style is gone; what it did is told by the default.
+    let style = Style::default();
+    let written: Vec<(Vec<Note>, Vec<Marks>)> = sh.tracks.iter()
+        .map(|t| written(&t.notes, &style))
+        .collect();
+    let layouts: Vec<Vec<Vec<Piece>>> = written.iter()
+        .map(|w| to_ss::lay_out(&w.0, &starts, &[], len))
+        .collect();
+    let parts: Vec<Vec<usize>> = if to_ss::is_piano(sh) {
+        vec![vec![0, 1]]
+    } else {
+        (0..sh.tracks.len()).map(|t| vec![t]).collect()
+    };
+
+    let mut w = Writer {
+        x: Xml::default(),
+        divisions: divisions(sh, &layouts),
+    };
+    w.x.line(r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#);
+    w.x.line(DOCTYPE);
+    w.x.open(r#"score-partwise version="3.1""#);
+    w.x.open("part-list");
+    for (i, ts) in parts.iter().enumerate() {
+        let t = &sh.tracks[ts[0]];
+        let name = if ts.len() > 1 { "Piano" } else { &t.name };
+        let id = format!("P{}", i + 1);
+        w.x.open(&format!("score-part id=\"{}\"", id));
+        w.x.leaf("part-name", escape(name));
+        w.x.open(&format!("score-instrument id=\"{}-I1\"", id));
+        w.x.leaf("instrument-name", escape(name));
+        w.x.close("score-instrument");
+        w.x.open(&format!("midi-instrument id=\"{}-I1\"", id));
+        // Counted from 1 here.
+        w.x.leaf("midi-program", to_midi::program(t.instrument) + 1);
+        w.x.close("midi-instrument");
+        w.x.close("score-part");
+    }
+    w.x.close("part-list");
+
+    // Measures are numbered from 1, or from 0 after a pickup.
+    let pickup = starts.len() > 1 && starts[1] - starts[0] < bar;
+    for (i, ts) in parts.iter().enumerate() {
+        w.x.open(&format!("part id=\"P{}\"", i + 1));
+        let mut staves: Vec<Staff> = ts.iter().enumerate().map(|(k, &t)| {
+            let number = if ts.len() > 1 { Some(k + 1) } else { None };
+            Staff::new(sh, t, &layouts[t], number)
+        }).collect();
+        for b in 0..starts.len() {
+            let (from, to) = (starts[b], starts.get(b + 1).cloned()
+                              .unwrap_or(len));
+            let number = if pickup { b } else { b + 1 };
+            let implicit = if pickup && b == 0 {
+                " implicit=\"yes\""
+            } else {
+                ""
+            };
+            w.x.open(&format!("measure number=\"{}\"{}", number, implicit));
+            let clefs: Vec<Option<Clef>> = staves.iter_mut()
+                .zip(ts)
+                .map(|(st, &t)| st.next_clef(&layouts[t][b], b == 0))
+                .collect();
+            if b == 0 {
+                w.attributes(sh, &clefs);
+            } else if clefs.iter().any(Option::is_some) {
+                w.x.open("attributes");
+                w.clefs(&clefs);
+                w.x.close("attributes");
+            }
+            for (k, st) in staves.iter_mut().enumerate() {
+                if k > 0 {
+                    w.backup(to - from);
+                }
+                let t = ts[k];
+                let last = b + 1 == starts.len();
+                w.write_bar(st, &layouts[t][b], &written[t].1, (from, to),
+                            last);
+            }
+            if b + 1 == starts.len() {
+                w.x.open(r#"barline location="right""#);
+                w.x.leaf("bar-style", "light-heavy");
+                w.x.close("barline");
+            }
+            w.x.close("measure");
+        }
+        w.x.close("part");
+    }
+    w.x.close("score-partwise");
+    w.x.out
+}
+
+// What the notation commands did to a note, as far as we can tell.
+#[derive(Clone, Default)]
+pub struct Marks {
+    pub slur_start: bool,
+    pub slur_stop: bool,
+    // staccato or staccatissimo.
+    pub articulation: Option<&'static str>,
+    // The note above, and how many notes are played, for a trill.
+    pub trill: Option<(Tone, usize)>,
+    // Grace notes as printed before the note.
+    pub graces: Option<(Grace, Vec<Note>)>,
+    // For each voice of a (voices ...) note.
+    pub voices: Vec<Vec<Marks>>,
+}
+
+// A short appoggiatura is printed as an acciaccatura, slashed.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Grace {
+    Acciaccatura,
+    Appoggiatura,
+    Plain,
+}
+
+// The notes as they were written, with the figures the reader made of
+// ornaments taken back into single notes, and the marks of each.
+pub fn written(notes: &[Note], style: &Style)
+    -> (Vec<Note>, Vec<Marks>) {
+
+    let mut out = vec![];
+    let mut marks = vec![];
+    let mut i = 0;
+    while i < notes.len() {
+        let n = &notes[i];
+        if let Some(vs) = n.as_voices() {
+            let (lines, inner): (Vec<_>, Vec<_>) = vs.iter()
+                .map(|v| written(v, style))
+                .unzip();
+            let mut v = n.clone();
+            v.pitch = Pitch::Voices(lines);
+            out.push(v);
+            marks.push(Marks { voices: inner, ..Marks::default() });
+            i += 1;
+            continue;
+        }
+        let rest = &notes[i..];
+        let (k, m) = if let Some((k, dur, main, upper)) = trill_at(rest) {
+            let mut t = n.clone();
+            t.duration = dur;
+            t.pitch = Pitch::Single(main);
+            t.rest_after = notes[i + k - 1].rest_after;
+            t.tie = notes[i + k - 1].tie;
+            out.push(t);
+            (k, Marks { trill: Some((upper, k)), ..Marks::default() })
+        } else if let Some((dur, grace, kind)) = acciaccatura_at(rest)
+            .or_else(|| appoggiatura_at(rest)) {
+            let mut t = notes[i + 1].clone();
+            t.duration = dur;
+            out.push(t);
+            let graces = Some((kind, vec![grace]));
+            (2, Marks { graces, ..Marks::default() })
+        } else if let Some((k, dur)) = graces_at(rest) {
+            let mut t = notes[i + k].clone();
+            t.duration = dur;
+            out.push(t);
+            let graces = Some((Grace::Plain, notes[i..i + k].to_vec()));
+            (k + 1, Marks { graces, ..Marks::default() })
+        } else {
+            out.push(n.clone());
+            (1, Marks::default())
+        };
+        marks.push(m);
+        i += k;
+    }
+
+    // Slurred notes are held to the next; the slur ends on the note that
+    // lets go.
+    let gap = |a| style.shape(a).length.map(|x| 1. - x);
+    let (legato, staccato, staccatissimo) = (
+        gap(Articulation::Slur),
+        gap(Articulation::Staccato),
+        gap(Articulation::Staccatissimo),
+    );
+    let sounding = |n: &Note| !n.is_rest() && n.as_voices().is_none();
+    let mut j = 0;
+    while j < out.len() {
+        if !sounding(&out[j]) {
+            j += 1;
+            continue;
+        }
+        if Some(out[j].rest_after) == legato {
+            let start = j;
+            while j < out.len() && sounding(&out[j])
+                && Some(out[j].rest_after) == legato {
+                j += 1;
+            }
+            let held = j < out.len() && sounding(&out[j]);
+            let end = if held { j } else { j - 1 };
+            if end > start {
+                marks[start].slur_start = true;
+                marks[end].slur_stop = true;
+            }
+            j = end.max(start + 1);
+            continue;
+        }
+        if Some(out[j].rest_after) == staccato {
+            marks[j].articulation = Some("staccato");
+        } else if Some(out[j].rest_after) == staccatissimo {
+            marks[j].articulation = Some("staccatissimo");
+        }
+        j += 1;
+    }
+    (out, marks)
+}
+
+fn single(n: &Note) -> Option<Tone> {
+    match n.pitch {
+        Pitch::Single(t) => Some(t),
+        _ => None,
+    }
+}
+
+// A trill comes out of the reader as quick notes of the same value, each a
+// share of the trilled note. Gives how many notes, the note's value, and
+// the main and upper notes. Plain notes are tried before ones in tuplets.
+fn trill_at(notes: &[Note]) -> Option<(usize, Duration, Tone, Tone)> {
+    let d = notes.first()?.duration;
+    let run = notes.iter()
+        .take_while(|n| n.duration == d && single(n).is_some())
+        .count();
+    for &plain in &[true, false] {
+        for k in 2..=run {
+            let scale = d.scale * Ratio::from_int(k as i64);
+            let ok = if plain {
+                scale == Ratio::one()
+            } else {
+                scale < Ratio::one() && scale.denom() % 2 == 1
+                    && scale.numer().count_ones() == 1
+            };
+            // Only the last can be tied on.
+            if !ok || notes[..k - 1].iter().any(|n| n.tie) {
+                continue;
+            }
+            let tones: Vec<Tone> = notes[..k].iter()
+                .filter_map(single)
+                .collect();
+            if let Some((main, upper)) = trill_tones(&tones) {
+                return Some((k, Duration { scale, ..d }, main, upper));
+            }
+        }
+    }
+    None
+}
+
+// The main and upper notes, if the tones go between them as the reader's
+// trills do (see Ornament::steps).
+fn trill_tones(tones: &[Tone]) -> Option<(Tone, Tone)> {
+    let n = tones.len();
+    for &from in &[0, 1, -1] {
+        for &turn in &[false, true] {
+            let mut xs: Vec<i32> = (0..n as i32).map(|i| match (from, i) {
+                (-1, 0) => -1,
+                (-1, _) | (0, _) => i % 2,
+                _ => 1 - i % 2,
+            }).collect();
+            if turn {
+                xs[n - 2] = -1;
+                xs[n - 1] = 0;
+            }
+            let at = |x| xs.iter().position(|&y| y == x).map(|i| tones[i]);
+            let (main, upper) = match (at(0), at(1)) {
+                (Some(m), Some(u)) => (m, u),
+                _ => continue,
+            };
+            let lower = at(-1);
+            let fits = tones.iter().zip(&xs).all(|(t, &x)| {
+                let want = match x {
+                    0 => Some(main),
+                    1 => Some(upper),
+                    _ => lower,
+                };
+                t.step == main.step + x && Some(*t) == want
+            });
+            if fits {
+                return Some((main, upper));
+            }
+        }
+    }
+    None
+}
+
+// The reader plays (acciac n) as the note below for 1/12 of the value,
+// then the note. Gives the note's value and the grace note as printed.
+fn acciaccatura_at(notes: &[Note]) -> Option<(Duration, Note, Grace)> {
+    let (a, b) = match notes {
+        [a, b, ..] => (a, b),
+        _ => return None,
+    };
+    let (ta, tb) = (single(a)?, single(b)?);
+    let scale = a.duration.scale * Ratio::from_int(12);
+    let dur = Duration { scale, ..a.duration };
+    let same = a.duration.klass == b.duration.klass
+        && a.duration.dots == b.duration.dots;
+    if same && !a.tie && ta.step + 1 == tb.step
+        && b.duration.scale == scale * Ratio::new(11, 12) {
+        Some((dur, printed(a, Duration::new(8, 0)), Grace::Acciaccatura))
+    } else {
+        None
+    }
+}
+
+// The reader plays (appog aux n) as the note next to it for half the value
+// (two thirds if dotted, an eighth for short-appog), then the rest of it
+// as one length.
+fn appoggiatura_at(notes: &[Note]) -> Option<(Duration, Note, Grace)> {
+    let (a, b) = match notes {
+        [a, b, ..] => (a, b),
+        _ => return None,
+    };
+    let (ta, tb) = (single(a)?, single(b)?);
+    if a.tie || !is_exact(b) || (ta.step - tb.step).abs() != 1 {
+        return None;
+    }
+    let dotted = a.duration.dots > 0;
+    for &share in &[2, 3, 8] {
+        let by = if share == 3 {
+            Ratio::new(3, 2)
+        } else {
+            Ratio::from_int(share)
+        };
+        let dur = Duration { scale: a.duration.scale * by, ..a.duration };
+        if dur.dur() != a.dur() + b.dur() || (share == 3) != dotted
+            && share != 8 {
+            continue;
+        }
+        // Long ones are printed at the value they take.
+        let grace = match share {
+            2 => Duration::new(dur.klass * 2, 0),
+            3 => Duration::new(dur.klass, 0),
+            _ => Duration::new(8, 0),
+        };
+        let kind = if share == 8 {
+            Grace::Acciaccatura
+        } else {
+            Grace::Appoggiatura
+        };
+        return Some((dur, printed(a, grace), kind));
+    }
+    None
+}
+
+// (grace g... n) plays the quick notes as written, then the rest of the
+// note as one length. Gives how many grace notes and the note's value.
+fn graces_at(notes: &[Note]) -> Option<(usize, Duration)> {
+    let quick = |n: &Note| n.duration.klass >= 16 && !n.tie
+        && n.duration.scale == Ratio::one()
+        && !n.is_rest() && n.as_voices().is_none();
+    for k in 1..notes.len().min(5) {
+        if !quick(&notes[k - 1]) {
+            return None;
+        }
+        let b = &notes[k];
+        if !is_exact(b) || b.is_rest() || b.as_voices().is_some() {
+            continue;
+        }
+        let graces: Ratio = notes[..k].iter().map(|n| n.dur()).sum();
+        let ds = to_ss::spell_len(graces + b.dur());
+        if graces < b.dur() && ds.len() == 1 && ds[0].scale == Ratio::one() {
+            return Some((k, ds[0]));
+        }
+    }
+    None
+}
+
+// A length the reader worked out rather than one written.
+fn is_exact(n: &Note) -> bool {
+    n.duration.klass == 1 && n.duration.dots == 0
+        && n.duration.scale != Ratio::one()
+}
+
+fn printed(n: &Note, duration: Duration) -> Note {
+    let mut g = n.clone();
+    g.duration = duration;
+    g
+}
+
+// Where tuplet brackets start and stop: over runs of pieces in the same
+// tuplet, closed once they come to a plain length.
+pub fn tuplet_marks(pieces: &[Piece]) -> Vec<(bool, bool)> {
+    let mut out = vec![(false, false); pieces.len()];
+    let mut open: Option<(Ratio, Ratio)> = None;
+    for (i, p) in pieces.iter().enumerate() {
+        let scale = if p.note.as_voices().is_some() {
+            Ratio::one()
+        } else {
+            p.dur.scale
+        };
+        if open.is_some_and(|(s, _)| s != scale) {
+            out[i - 1].1 = true;
+            open = None;
+        }
+        if scale == Ratio::one() {
+            continue;
+        }
+        if open.is_none() {
+            out[i].0 = true;
+        }
+        let sum = open.map_or(Ratio::zero(), |x| x.1) + p.dur.dur();
+        open = Some((scale, sum));
+        if sum.denom().count_ones() == 1 {
+            out[i].1 = true;
+            open = None;
+        }
+    }
+    if open.is_some() {
+        if let Some(x) = out.last_mut() {
+            x.1 = true;
+        }
+    }
+    out
+}
+
+// Each length in the score is a whole number of divisions of a quarter.
+fn divisions(sh: &Sheet, layouts: &[Vec<Vec<Piece>>]) -> i64 {
+    let mut xs = vec![];
+    for p in layouts.iter().flatten().flatten() {
+        xs.push(p.at);
+        xs.push(p.dur.dur());
+        if let Some(vs) = p.note.as_voices() {
+            for n in vs.iter().flatten() {
+                xs.extend(to_ss::note_durs(n).iter().map(|d| d.dur()));
+            }
+        }
+    }
+    for t in &sh.tracks {
+        xs.extend(t.pedal.iter().map(|p| p.at));
+    }
+    xs.extend(sh.tempo.points.iter().filter_map(|p| to_ss::ratio_of(p.at)));
+    xs.into_iter().fold(1, |d, x| {
+        let q = (x * Ratio::from_int(2)).denom();
+        Ratio::new(d, q).numer() * q
+    })
+}
+
+pub enum Event {
+    // Quarters per minute, and whether to print it.
+    Tempo(f64, bool),
+    // Words for the start of a ramp.
+    Words(&'static str),
+    Pedal(&'static str, f32),
+}
+
+// Tempo goes into the first staff only; pedals into their own.
+pub fn events(sh: &Sheet, t: usize) -> Vec<(Ratio, Event)> {
+    let mut out = vec![];
+    if t == 0 {
+        let (mut prev, mut bpm) = (0., DEFAULT_BPM);
+        for p in &sh.tempo.points {
+            let at = match to_ss::ratio_of(p.at) {
+                Some(at) => at,
+                None => continue,
+            };
+            if p.ramp {
+                if let Some(x) = to_ss::ratio_of(prev) {
+                    let word = if p.bpm < bpm { "rit." } else { "accel." };
+                    out.push((x, Event::Words(word)));
+                }
+            }
+            out.push((at, Event::Tempo(p.bpm, !p.ramp)));
+            prev = p.at;
+            bpm = p.bpm;
+        }
+    }
+    let mut sustain = 0.;
+    for p in &sh.tracks[t].pedal {
+        // Changes of the una corda alone aren't marked.
+        let kind = if p.sustain > 0. && sustain > 0. {
+            if p.sustain == sustain { "change" } else { continue }
+        } else if p.sustain > 0. {
+            "start"
+        } else if sustain > 0. {
+            "stop"
+        } else {
+            continue;
+        };
+        out.push((p.at, Event::Pedal(kind, p.sustain)));
+        sustain = p.sustain;
+    }
+    out.sort_by_key(|x| x.0);
+    out
+}
+
+// One staff of a part.
+struct Staff {
+    key: Key,
+    clef: StaffClef,
+    dynamic: f32,
+    // Accidentals in force in the bar, by step.
+    sharps: HashMap<i32, i32>,
+    events: Peekable<vec::IntoIter<(Ratio, Event)>>,
+    // Within a part of several staves.
+    number: Option<usize>,
+    // Staves of a part have voices of their own.
+    voice: usize,
+}
+
+impl Staff {
+    fn new(sh: &Sheet, t: usize, bars: &[Vec<Piece>], number: Option<usize>)
+        -> Self {
+
+        Self {
+            key: sh.key,
+            clef: StaffClef::new(&sh.tracks[t], bars),
+            dynamic: 1.,
+            sharps: HashMap::new(),
+            events: events(sh, t).into_iter().peekable(),
+            number,
+            voice: number.map_or(1, |n| (n - 1) * 4 + 1),
+        }
+    }
+
+    // The clef to write at the start of a bar, if any.
+    fn next_clef(&mut self, pieces: &[Piece], first: bool) -> Option<Clef> {
+        let changed = self.clef.next_bar(pieces);
+        if first || changed { Some(self.clef.current()) } else { None }
+    }
+
+    // The accidental to print for a tone, as to_ss writes it.
+    fn accidental(&mut self, tone: Tone) -> Option<i32> {
+        let cur = self.sharps.get(&tone.step).cloned()
+            .unwrap_or_else(|| self.key.sharps_for(tone.step.rem_euclid(7)));
+        if cur == tone.sharp {
+            return None;
+        }
+        self.sharps.insert(tone.step, tone.sharp);
+        Some(tone.sharp)
+    }
+}
+
+struct Writer {
+    x: Xml,
+    divisions: i64,
+}
+
+impl Writer {
+    fn divs(&self, len: Ratio) -> i64 {
+        (len * Ratio::from_int(2 * self.divisions)).numer()
+    }
+
+    fn attributes(&mut self, sh: &Sheet, clefs: &[Option<Clef>]) {
+        self.x.open("attributes");
+        self.x.leaf("divisions", self.divisions);
+        self.x.open("key");
+        self.x.leaf("fifths", sh.key.fifths);
+        self.x.leaf("mode", if sh.key.minor { "minor" } else { "major" });
+        self.x.close("key");
+        self.x.open("time");
+        self.x.leaf("beats", sh.meter.beats);
+        self.x.leaf("beat-type", sh.meter.unit);
+        self.x.close("time");
+        if clefs.len() > 1 {
+            self.x.leaf("staves", clefs.len());
+        }
+        self.clefs(clefs);
+        self.x.close("attributes");
+    }
+
+    fn clefs(&mut self, clefs: &[Option<Clef>]) {
+        for (k, clef) in clefs.iter().enumerate() {
+            let clef = match clef {
+                Some(x) => *x,
+                None => continue,
+            };
+            if clefs.len() > 1 {
+                self.x.open(&format!("clef number=\"{}\"", k + 1));
+            } else {
+                self.x.open("clef");
+            }
+            let (sign, line, octave) = match clef {
+                Clef::Treble => ("G", Some(2), 0),
+                Clef::Bass => ("F", Some(4), 0),
+                Clef::Alto => ("C", Some(3), 0),
+                Clef::Tenor => ("C", Some(4), 0),
+                Clef::Treble8vb => ("G", Some(2), -1),
+                Clef::Bass8va => ("F", Some(4), 1),
+                Clef::Percussion => ("percussion", None, 0),
+            };
+            self.x.leaf("sign", sign);
+            if let Some(line) = line {
+                self.x.leaf("line", line);
+            }
+            if octave != 0 {
+                self.x.leaf("clef-octave-change", octave);
+            }
+            self.x.close("clef");
+        }
+    }
+
+    fn backup(&mut self, len: Ratio) {
+        self.x.open("backup");
+        self.x.leaf("duration", self.divs(len));
+        self.x.close("backup");
+    }
+
+    // A staff's bar, with rests to fill it if the staff ends early.
+    fn write_bar(&mut self, st: &mut Staff, pieces: &[Piece], marks: &[Marks],
+                 (from, to): (Ratio, Ratio), last: bool) {
+        let end = pieces.last().map_or(from, |p| p.at + p.dur.dur());
+        let rest = mk_rest(Duration::exact(to - end));
+        let mut all = pieces.to_vec();
+        let mut at = end;
+        for dur in to_ss::spell_len(to - end) {
+            all.push(Piece {
+                note: &rest,
+                ix: usize::MAX,
+                at,
+                dur,
+                first: true,
+                last: true,
+            });
+            at += dur.dur();
+        }
+        st.sharps.clear();
+        let voice = st.voice;
+        self.write_line(st, &all, marks, voice, true);
+        if last {
+            while let Some((_, ev)) = st.events.next() {
+                self.write_event(st, &ev, Ratio::zero());
+            }
+        }
+    }
+
+    // The pieces of one voice. Marks for the main line go along with it.
+    fn write_line(&mut self, st: &mut Staff, pieces: &[Piece],
+                  marks: &[Marks], voice: usize, main: bool) {
+        let tuplets = tuplet_marks(pieces);
+        let none = Marks::default();
+        for (i, p) in pieces.iter().enumerate() {
+            if main {
+                let end = p.at + p.dur.dur();
+                while st.events.peek().is_some_and(|ev| ev.0 < end) {
+                    let (at, ev) = st.events.next().unwrap();
+                    let offset = if at > p.at {
+                        at - p.at
+                    } else {
+                        Ratio::zero()
+                    };
+                    self.write_event(st, &ev, offset);
+                }
+            }
+            let m = marks.get(p.ix).unwrap_or(&none);
+            if let Some(vs) = p.note.as_voices() {
+                for (k, v) in vs.iter().enumerate() {
+                    if k > 0 {
+                        self.backup(p.dur.dur());
+                    }
+                    let mut at = p.at;
+                    let mut ps = vec![];
+                    for (ix, n) in v.iter().enumerate() {
+                        let ds = to_ss::note_durs(n);
+                        let count = ds.len();
+                        for (j, dur) in ds.into_iter().enumerate() {
+                            ps.push(Piece {
+                                note: n,
+                                ix,
+                                at,
+                                dur,
+                                first: j == 0,
+                                last: j + 1 == count,
+                            });
+                            at += dur.dur();
+                        }
+                    }
+                    let inner = m.voices.get(k).map_or(&[][..], |x| &x[..]);
+                    self.write_line(st, &ps, inner, voice + k, false);
+                }
+                continue;
+            }
+            self.write_note(st, p, m, tuplets[i], voice);
+        }
+    }
+
+    fn write_event(&mut self, st: &Staff, ev: &Event, offset: Ratio) {
+        let placement = match ev {
+            Event::Pedal(..) => "below",
+            _ => "above",
+        };
+        self.x.open(&format!("direction placement=\"{}\"", placement));
+        self.x.open("direction-type");
+        let sound = match ev {
+            Event::Tempo(bpm, shown) => {
+                if *shown {
+                    self.x.open("metronome");
+                } else {
+                    self.x.open(r#"metronome print-object="no""#);
+                }
+                self.x.leaf("beat-unit", "quarter");
+                self.x.leaf("per-minute", bpm);
+                self.x.close("metronome");
+                Some(format!("tempo=\"{}\"", bpm))
+            }
+            Event::Words(w) => {
+                self.x.leaf(r#"words font-style="italic""#, w);
+                None
+            }
+            Event::Pedal(kind, sustain) => {
+                self.x.line(&format!("<pedal type=\"{}\" line=\"no\"/>", kind));
+                let damper = if *kind == "stop" {
+                    "no".to_owned()
+                } else if *sustain >= 1. {
+                    "yes".to_owned()
+                } else {
+                    ((sustain * 100.).round() as i32).to_string()
+                };
+                Some(format!("damper-pedal=\"{}\"", damper))
+            }
+        };
+        self.x.close("direction-type");
+        if !offset.is_zero() {
+            self.x.leaf(r#"offset sound="yes""#, self.divs(offset));
+        }
+        if let Some(n) = st.number {
+            self.x.leaf("staff", n);
+        }
+        if let Some(s) = sound {
+            self.x.line(&format!("<sound {}/>", s));
+        }
+        self.x.close("direction");
+    }
+
+    fn write_dynamic(&mut self, st: &mut Staff, amp: f32) {
+        if amp == st.dynamic {
+            return;
+        }
+        // Accents and the like aren't dynamics; the one in force stays.
+        let name = match DYNAMICS.iter().find(|x| x.1 == amp) {
+            Some(x) => x.0,
+            None => return,
+        };
+        st.dynamic = amp;
+        self.x.open(r#"direction placement="below""#);
+        self.x.open("direction-type");
+        self.x.open("dynamics");
+        self.x.line(&format!("<{}/>", name));
+        self.x.close("dynamics");
+        self.x.close("direction-type");
+        if let Some(n) = st.number {
+            self.x.leaf("staff", n);
+        }
+        self.x.close("direction");
+    }
+
+    fn write_note(&mut self, st: &mut Staff, p: &Piece, m: &Marks,
+                  (tuplet_start, tuplet_stop): (bool, bool), voice: usize) {
+        let n = p.note;
+        let tones = tones_of(n);
+        if !tones.is_empty() && p.first {
+            self.write_dynamic(st, n.amp);
+        }
+        let (kind, graces) = match &m.graces {
+            Some((kind, gs)) if p.first => (*kind, &gs[..]),
+            _ => (Grace::Plain, &[][..]),
+        };
+        for g in graces {
+            let gs = tones_of(g);
+            for (j, &t) in gs.iter().enumerate() {
+                let acc = st.accidental(t);
+                self.x.open("note");
+                if kind == Grace::Acciaccatura {
+                    self.x.line(r#"<grace slash="yes"/>"#);
+                } else {
+                    self.x.line("<grace/>");
+                }
+                if j > 0 {
+                    self.x.line("<chord/>");
+                }
+                self.pitch(t);
+                self.x.leaf("voice", voice);
+                if let Some(name) = type_name(g.duration.klass) {
+                    self.x.leaf("type", name);
+                }
+                for _ in 0..g.duration.dots {
+                    self.x.line("<dot/>");
+                }
+                if let Some(a) = acc.and_then(accidental_name) {
+                    self.x.leaf("accidental", a);
+                }
+                if let Some(n) = st.number {
+                    self.x.leaf("staff", n);
+                }
+                self.x.close("note");
+            }
+        }
+        // Tied-on pieces keep the accidental of the first, as in print.
+        let accs: Vec<Option<i32>> = tones.iter()
+            .map(|&t| if p.first { st.accidental(t) } else { None })
+            .collect();
+        let trill_acc = match m.trill {
+            Some((upper, _)) if p.first => {
+                let cur = st.sharps.get(&upper.step).cloned().unwrap_or_else(
+                    || st.key.sharps_for(upper.step.rem_euclid(7)));
+                if cur != upper.sharp { Some(upper.sharp) } else { None }
+            }
+            _ => None,
+        };
+
+        for j in 0..tones.len().max(1) {
+            self.x.open("note");
+            if j > 0 {
+                self.x.line("<chord/>");
+            }
+            match tones.get(j) {
+                Some(&t) => self.pitch(t),
+                None => self.x.line("<rest/>"),
+            }
+            self.x.leaf("duration", self.divs(p.dur.dur()));
+            let tie = !tones.is_empty();
+            if tie && !p.first {
+                self.x.line(r#"<tie type="stop"/>"#);
+            }
+            if tie && !p.last {
+                self.x.line(r#"<tie type="start"/>"#);
+            }
+            self.x.leaf("voice", voice);
+            if let Some(name) = type_name(p.dur.klass) {
+                self.x.leaf("type", name);
+            }
+            for _ in 0..p.dur.dots {
+                self.x.line("<dot/>");
+            }
+            if let Some(a) = accs.get(j).cloned().flatten()
+                .and_then(accidental_name) {
+                self.x.leaf("accidental", a);
+            }
+            let scale = p.dur.scale;
+            if scale != Ratio::one() {
+                self.x.open("time-modification");
+                self.x.leaf("actual-notes", scale.denom());
+                self.x.leaf("normal-notes", scale.numer());
+                self.x.close("time-modification");
+            }
+            if let Some(n) = st.number {
+                self.x.leaf("staff", n);
+            }
+
+            let mut ns = vec![];
+            if tie && !p.first {
+                ns.push(r#"<tied type="stop"/>"#.to_owned());
+            }
+            if tie && !p.last {
+                ns.push(r#"<tied type="start"/>"#.to_owned());
+            }
+            if j == 0 {
+                if m.slur_stop && p.last {
+                    ns.push(r#"<slur type="stop"/>"#.to_owned());
+                }
+                if m.slur_start && p.first {
+                    ns.push(r#"<slur type="start"/>"#.to_owned());
+                }
+                if tuplet_start {
+                    ns.push(r#"<tuplet type="start"/>"#.to_owned());
+                }
+                if tuplet_stop {
+                    ns.push(r#"<tuplet type="stop"/>"#.to_owned());
+                }
+            }
+            self.notations(&ns, j == 0 && p.first, m, trill_acc);
+            self.x.close("note");
+        }
+    }
+
+    fn notations(&mut self, ns: &[String], first: bool, m: &Marks,
+                 trill_acc: Option<i32>) {
+        let trill = if first { m.trill } else { None };
+        let art = if first { m.articulation } else { None };
+        if ns.is_empty() && trill.is_none() && art.is_none() {
+            return;
+        }
+        self.x.open("notations");
+        for x in ns {
+            self.x.line(x);
+        }
+        if let Some((_, k)) = trill {
+            self.x.open("ornaments");
+            self.x.line(&format!(r#"<trill-mark beats="{}"/>"#, k));
+            if let Some(a) = trill_acc.and_then(accidental_name) {
+                self.x.leaf("accidental-mark", a);
+            }
+            self.x.close("ornaments");
+        }
+        if let Some(a) = art {
+            self.x.open("articulations");
+            self.x.line(&format!("<{}/>", a));
+            self.x.close("articulations");
+        }
+        self.x.close("notations");
+    }
+
+    fn pitch(&mut self, t: Tone) {
+        const LETTERS: [&str; 7] = ["C", "D", "E", "F", "G", "A", "B"];
+        self.x.open("pitch");
+        self.x.leaf("step", LETTERS[t.step.rem_euclid(7) as usize]);
+        if t.sharp != 0 {
+            self.x.leaf("alter", t.sharp);
+        }
+        self.x.leaf("octave", 5 + t.step.div_euclid(7));
+        self.x.close("pitch");
+    }
+}
+
+// Respelled, for an accidental to print.
+pub fn tones_of(n: &Note) -> Vec<Tone> {
+    match &n.pitch {
+        Pitch::Single(t) => vec![t.respelled()],
+        Pitch::Chord(ts) => ts.iter().map(|t| t.respelled()).collect(),
+        _ => vec![],
+    }
+}
+
+fn type_name(klass: i32) -> Option<&'static str> {
+    Some(match klass {
+        1 => "whole",
+        2 => "half",
+        4 => "quarter",
+        8 => "eighth",
+        16 => "16th",
+        32 => "32nd",
+        64 => "64th",
+        128 => "128th",
+        256 => "256th",
+        512 => "512th",
+        1024 => "1024th",
+        _ => return None,
+    })
+}
+
+// Past double sharps and flats, only the <alter> can tell.
+fn accidental_name(sharp: i32) -> Option<&'static str> {
+    Some(match sharp {
+        0 => "natural",
+        1 => "sharp",
+        2 => "double-sharp",
+        -1 => "flat",
+        -2 => "flat-flat",
+        _ => return None,
+    })
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Indented XML, an element per line.
+#[derive(Default)]
+struct Xml {
+    out: String,
+    depth: usize,
+}
+
+impl Xml {
+    fn line(&mut self, s: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    // The tag may come with attributes.
+    fn open(&mut self, tag: &str) {
+        self.line(&format!("<{}>", tag));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, name: &str) {
+        self.depth -= 1;
+        self.line(&format!("</{}>", name));
+    }
+
+    fn leaf(&mut self, tag: &str, text: impl std::fmt::Display) {
+        let name = tag.split(' ').next().unwrap_or(tag);
+        self.line(&format!("<{}>{}</{}>", tag, text, name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use roxmltree::{Document, Node, ParsingOptions};
+    use crate::notation;
+
+    fn number(n: Node, tag: &str) -> Option<i64> {
+        n.children().find(|x| x.has_tag_name(tag))?.text()?.parse().ok()
+    }
+
+    // How long each measure is, in divisions, as its notes, backups and
+    // forwards have it.
+    fn measure_lens(part: Node) -> Vec<i64> {
+        part.children().filter(|m| m.has_tag_name("measure")).map(|m| {
+            let (mut at, mut most) = (0, 0);
+            for c in m.children() {
+                let d = number(c, "duration").unwrap_or(0);
+                let chord = c.children().any(|x| x.has_tag_name("chord"));
+                match c.tag_name().name() {
+                    "note" if !chord => at += d,
+                    "backup" => at -= d,
+                    "forward" => at += d,
+                    _ => (),
+                }
+                most = most.max(at);
+            }
+            most
+        }).collect()
+    }
+
+    #[test]
+    fn respells_past_double_sharps() {
+        let src = "(piano (1 4) (key C major) ((treble-C (/4 0)) ((/4 0))))";
+        let mut sh = notation::read_sheet(src.as_bytes()).unwrap();
+        sh.tracks[0].notes[0].pitch = Pitch::Single(Tone::new(0, 3));
+        let xml = write_musicxml(&sh);
+        let opts = ParsingOptions { allow_dtd: true, ..Default::default() };
+        let doc = Document::parse_with_options(&xml, opts).unwrap();
+        let note = doc.descendants().find(|x| x.has_tag_name("note")).unwrap();
+        let text = |tag| note.descendants().find(|x| x.has_tag_name(tag))
+            .and_then(|x| x.text());
+        // C### is D#.
+        assert_eq!(text("step"), Some("D"));
+        assert_eq!(text("alter"), Some("1"));
+        assert_eq!(text("accidental"), Some("sharp"));
+    }
+
+    #[test]
+    fn fills_every_measure() {
+        for f in &["kv545.ss", "kv545-m2.ss", "kv545-m3.ss"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(f);
+            let src = fs::read_to_string(path).unwrap();
+            let sh = notation::read_sheet(src.as_bytes()).unwrap();
+            let xml = write_musicxml(&sh);
+            let opts = ParsingOptions { allow_dtd: true, ..Default::default() };
+            let doc = Document::parse_with_options(&xml, opts).unwrap();
+            let divisions = doc.descendants()
+                .find_map(|x| number(x, "divisions").filter(|_| {
+                    x.has_tag_name("attributes")
+                }))
+                .unwrap();
+            let bar = sh.meter.bar_dur() * Ratio::from_int(2 * divisions);
+            let parts: Vec<_> = doc.descendants()
+                .filter(|x| x.has_tag_name("part"))
+                .collect();
+            assert_eq!(parts.len(), 1, "{}", f);
+            let lens = measure_lens(parts[0]);
+            assert!(!lens.is_empty(), "{}", f);
+            // But for a pickup.
+            assert!(Ratio::from_int(lens[0]) <= bar, "{}", f);
+            for (i, &len) in lens.iter().enumerate().skip(1) {
+                let full = Ratio::from_int(len) == bar;
+                assert!(full, "{}: measure {}", f, i + 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,1312 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::iter::Peekable;
+use std::vec;
+use itertools::Itertools;
+use crate::notes::*;
+use crate::ratio::Ratio;
+use crate::srcpos::{tokens, Pos, Token};
+use crate::notation::{
+    self, Clef, NotationError,
+    ACCIDENTALS, CLEFS, DYNAMICS, REST_AFTER,
+};
+
+// Writes a Sheet out as .ss, one system of bars at a time, with the pitches
+// placed on the staff and the accidentals each bar needs. What the reader
+// has already worked out (motifs, repeats, articulations, hairpins...) comes
+// out spelled out as amplitudes and gaps, so that read_sheet gives back the
+// same notes.
+
+pub fn save(sh: &Sheet, name: &str) -> io::Result<()> {
+    fs::write(name, write_sheet(sh))
+}
+
+// What the reader makes of a sheet, written back. It isn't the sheet as it
+// was written: motifs, repeats, slurs and ornaments come out as the notes
+// they stand for.
+pub fn dump(r: impl Read) -> Result<String, NotationError> {
+    notation::read_sheet(r).map(|sh| write_sheet(&sh))
+}
+
+pub fn write_sheet(sh: &Sheet) -> String {
+    let len = sh.tracks.iter()
+        .map(|t| t.notes.iter().map(|n| n.dur()).sum())
+        .max()
+        .unwrap_or_default();
+    let starts = bar_starts(len, sh.meter.bar_dur());
+
+    // Marks go where a staff has a note (or a bar) starting. Where none
+    // does, a note is tied over to make one: pedals in their own staff,
+    // tempo in the first.
+    let mut cuts: Vec<Vec<Ratio>> = sh.tracks.iter()
+        .map(|t| t.pedal.iter().map(|p| p.at).collect())
+        .collect();
+    let onsets: Vec<Vec<Ratio>> = sh.tracks.iter().map(|t| {
+        let mut at = Ratio::zero();
+        let mut xs = vec![at];
+        for n in &t.notes {
+            at += n.dur();
+            xs.push(at);
+        }
+        xs
+    }).collect();
+    for p in &sh.tempo.points {
+        let x = match ratio_of(p.at) {
+            Some(x) => x,
+            None => continue,
+        };
+        if !starts.contains(&x) && !onsets.iter().any(|xs| xs.contains(&x)) {
+            if let Some(cuts) = cuts.get_mut(0) {
+                cuts.push(x);
+            }
+        }
+    }
+    for cuts in &mut cuts {
+        cuts.sort();
+    }
+    let layouts: Vec<Vec<Vec<Piece>>> = sh.tracks.iter().zip(&cuts)
+        .map(|(t, cuts)| lay_out(&t.notes, &starts, cuts, len))
+        .collect();
+
+    let mut events: Vec<Vec<(Ratio, String)>> = sh.tracks.iter()
+        .map(|t| pedal_events(&t.pedal))
+        .collect();
+    let bounds: Vec<Vec<Ratio>> = layouts.iter().map(|bars| {
+        let mut xs: Vec<Ratio> = bars.iter().flatten().map(|p| p.at).collect();
+        xs.extend(starts.iter().cloned());
+        xs.push(len);
+        xs
+    }).collect();
+    let (sheet_tempo, tempo) = tempo_events(sh, &bounds);
+    for (t, at, mark) in tempo {
+        if let Some(evs) = events.get_mut(t) {
+            evs.push((at, mark));
+        }
+    }
+    for evs in &mut events {
+        evs.sort_by_key(|x| x.0);
+    }
+
+    let piano = is_piano(sh);
+    let mut staves: Vec<Staff> = sh.tracks.iter().zip(&layouts)
+        .map(|(t, bars)| Staff::new(t, bars, sh.key))
+        .collect();
+
+    let mut out = String::new();
+    if piano {
+        out.push_str("(piano\n");
+    } else {
+        let decl = sh.tracks.iter().zip(&staves).map(|(t, st)| {
+            format!("({} {} {})", staff_name(&t.name),
+                    instrument_name(t.instrument), clef_name(st.clef.current()))
+        }).join(" ");
+        out.push_str(&format!("(score\n  (staves {})\n", decl));
+    }
+    out.push_str(&format!("  ({} {}) {}", sh.meter.beats, sh.meter.unit,
+                          key_name(sh.key)));
+    if let Some(mark) = sheet_tempo {
+        out.push_str(&format!(" {}", mark));
+    }
+    out.push_str("\n  (");
+    if piano {
+        // (piano ...) starts both staves in the treble clef.
+        for st in &mut staves {
+            st.declared = Clef::Treble;
+        }
+    }
+
+    let mut events: Vec<_> = events.into_iter()
+        .map(|evs| evs.into_iter().peekable())
+        .collect();
+    let bars = starts.len();
+    for b in 0..bars {
+        if b > 0 {
+            out.push_str("\n\n   ");
+        }
+        let staff = staves.iter_mut().zip(&layouts).zip(&mut events);
+        for (t, ((st, lay), evs)) in staff.enumerate() {
+            if t > 0 {
+                out.push_str("\n   ");
+            }
+            out.push_str(&st.write_bar(&lay[b], evs, b + 1 == bars));
+        }
+    }
+    out.push_str("))\n");
+    out
+}
+
+// Whether the sheet is written as (piano ...): a treble and a bass staff,
+// both for the piano.
+pub fn is_piano(sh: &Sheet) -> bool {
+    match &sh.tracks[..] {
+        [rh, lh] => rh.name == "treble" && lh.name == "bass"
+            && rh.instrument == Instrument::Piano
+            && lh.instrument == Instrument::Piano,
+        _ => false,
+    }
+}
+
+// Where each bar starts. The bars are full but for a pickup, as the reader
+// wants them.
+pub fn bar_starts(len: Ratio, bar: Ratio) -> Vec<Ratio> {
+    let whole_bars = (len / bar).numer().div_euclid((len / bar).denom());
+    let pickup = len - bar * Ratio::from_int(whole_bars);
+    let mut out = vec![];
+    let mut at = Ratio::zero();
+    if !pickup.is_zero() {
+        out.push(at);
+        at = pickup;
+    }
+    while at < len {
+        out.push(at);
+        at += bar;
+    }
+    out
+}
+
+// A note, or the part of it that falls in one bar or is written as one of
+// several tied notes.
+#[derive(Copy, Clone)]
+pub struct Piece<'a> {
+    pub note: &'a Note,
+    // Where the note is in the notes laid out.
+    pub ix: usize,
+    pub at: Ratio,
+    pub dur: Duration,
+    pub first: bool,
+    pub last: bool,
+}
+
+// The pieces of every bar. Notes that cross a bar line (or a cut) are tied
+// across it.
+pub fn lay_out<'a>(notes: &'a [Note], starts: &[Ratio], cuts: &[Ratio],
+                   len: Ratio) -> Vec<Vec<Piece<'a>>> {
+    let mut out: Vec<Vec<Piece>> = starts.iter().map(|_| vec![]).collect();
+    let mut at = Ratio::zero();
+    let mut b = 0;
+    for (ix, n) in notes.iter().enumerate() {
+        let end = at + n.dur();
+        let mut segs = vec![];
+        let mut x = at;
+        while x < end {
+            while b + 1 < starts.len() && starts[b + 1] <= x {
+                b += 1;
+            }
+            let bar_end = starts.get(b + 1).cloned().unwrap_or(len);
+            let cut = cuts.iter().find(|&&c| c > x).cloned().unwrap_or(len);
+            // Voices can't be split up.
+            let seg_end = if n.as_voices().is_some() || bar_end <= x {
+                end
+            } else {
+                bar_end.min(cut).min(end)
+            };
+            segs.push((b, x, seg_end - x));
+            x = seg_end;
+        }
+        let durs: Vec<(usize, Ratio, Duration)> = match &segs[..] {
+            [(b, x, _)] => {
+                let mut x = *x;
+                note_durs(n).into_iter().map(|d| {
+                    x += d.dur();
+                    (*b, x - d.dur(), d)
+                }).collect()
+            }
+            _ => segs.iter().flat_map(|&(b, x, len)| {
+                let mut x = x;
+                spell_len(len).into_iter().map(move |d| {
+                    x += d.dur();
+                    (b, x - d.dur(), d)
+                })
+            }).collect(),
+        };
+        let count = durs.len();
+        for (i, (b, x, dur)) in durs.into_iter().enumerate() {
+            if let Some(bar) = out.get_mut(b) {
+                bar.push(Piece {
+                    note: n,
+                    ix,
+                    at: x,
+                    dur,
+                    first: i == 0,
+                    last: i + 1 == count,
+                });
+            }
+        }
+        at = end;
+    }
+    out
+}
+
+// How a note that fits in its bar is written: as it was, unless it came of
+// tied notes, which have to be tied again to come back the same.
+pub fn note_durs(n: &Note) -> Vec<Duration> {
+    let d = n.duration;
+    if n.as_voices().is_some() || d.klass != 1 || d.dots != 0 {
+        return vec![plain(d)];
+    }
+    let ds = spell_len(n.dur());
+    match &ds[..] {
+        [x] if *x == d || n.is_rest() => ds,
+        [x] => halves(*x),
+        _ => ds,
+    }
+}
+
+// A share of a note that is a plain value written as one: the notes of a
+// trill on an /8 are /32s.
+fn plain(d: Duration) -> Duration {
+    let (n, k) = (d.scale.numer(), d.scale.denom());
+    if n == 1 && k > 1 && k.count_ones() == 1 && d.klass as i64 * k <= 1024 {
+        Duration::new(d.klass * k as i32, d.dots)
+    } else {
+        d
+    }
+}
+
+// Written durations adding up to `len`, longest first. Lengths that aren't a
+// sum of plain notes go in the tuplet that makes them one.
+pub fn spell_len(len: Ratio) -> Vec<Duration> {
+    let whole = len / Ratio::from_int(2);
+    let mut odd = whole.denom();
+    while odd % 2 == 0 {
+        odd /= 2;
+    }
+    let mut scale = Ratio::one();
+    if odd > 1 {
+        let mut m = 1;
+        while m * 2 < odd {
+            m *= 2;
+        }
+        scale = Ratio::new(m, odd);
+    }
+    let half = Ratio::new(1, 2);
+    let mut left = whole / scale;
+    let mut out = vec![];
+    while !left.is_zero() {
+        // The longest plain note that fits, with as many dots as fit.
+        let mut klass = 1;
+        while Ratio::new(1, klass) > left {
+            klass *= 2;
+        }
+        let mut value = Ratio::new(1, klass);
+        let mut dot = value * half;
+        let mut dots = 0;
+        while dots < 8 && value + dot <= left {
+            value += dot;
+            dot = dot * half;
+            dots += 1;
+        }
+        out.push(Duration { klass: klass as i32, dots, scale });
+        left = left - value;
+    }
+    out
+}
+
+// The same length as two notes, for a tie that has to stay one.
+fn halves(d: Duration) -> Vec<Duration> {
+    if d.dots > 0 {
+        vec![Duration { dots: d.dots - 1, ..d },
+             Duration { klass: d.klass << d.dots, dots: 0, ..d }]
+    } else {
+        let h = Duration { klass: d.klass * 2, ..d };
+        vec![h, h]
+    }
+}
+
+// The gap to give the last of tied notes so that it comes back as `gap`
+// once they are merged, which scales it by the share of that note. The
+// quotient gives back what a reader made, but not every float comes of a
+// product: for those, it's the nearest.
+fn tied_gap(gap: f64, share: Ratio) -> f64 {
+    gap / share.to_f64()
+}
+
+fn pedal_events(pedal: &[Pedal]) -> Vec<(Ratio, String)> {
+    let mut out = vec![];
+    let (mut sustain, mut una_corda) = (0., false);
+    for p in pedal {
+        let una = if p.una_corda { "una-corda" } else { "tre-corde" };
+        // Whatever doesn't change the una corda is a sustain mark, even one
+        // that repeats the last.
+        if p.una_corda != una_corda {
+            out.push((p.at, una.to_owned()));
+        }
+        if p.sustain != sustain || p.una_corda == una_corda {
+            let cmd = if p.sustain > 0.75 {
+                "ped"
+            } else if p.sustain > 0.25 {
+                "half-ped"
+            } else {
+                "ped-up"
+            };
+            out.push((p.at, cmd.to_owned()));
+        }
+        sustain = p.sustain;
+        una_corda = p.una_corda;
+    }
+    out
+}
+
+// The fraction a position in the tempo map was made from, if it isn't too
+// far-fetched.
+pub fn ratio_of(x: f64) -> Option<Ratio> {
+    // Continued fractions: h/k are the convergents.
+    let (mut h0, mut h1, mut k0, mut k1) = (0_i64, 1_i64, 1_i64, 0_i64);
+    let mut y = x;
+    for _ in 0..32 {
+        let a = y.floor();
+        if a.abs() > 1e9 {
+            break;
+        }
+        let (h, k) = (a as i64 * h1 + h0, a as i64 * k1 + k0);
+        if k > 1 << 32 {
+            break;
+        }
+        let r = Ratio::new(h, k);
+        if r.to_f64() == x {
+            return Some(r);
+        }
+        h0 = h1;
+        h1 = h;
+        k0 = k1;
+        k1 = k;
+        y = 1. / (y - a);
+    }
+    None
+}
+
+// The marks that give back the tempo map, by staff: (tempo 80) for a jump
+// and (rit n beats to 60) for a ramp, which also jumps to where it starts
+// from. A jump at the very start can be sheet-wide.
+fn tempo_events(sh: &Sheet, bounds: &[Vec<Ratio>])
+    -> (Option<String>, Vec<(usize, Ratio, String)>) {
+
+    let place = |x: f64| -> (usize, Ratio) {
+        for (t, xs) in bounds.iter().enumerate() {
+            if let Some(r) = xs.iter().find(|r| r.to_f64() == x) {
+                return (t, *r);
+            }
+        }
+        let off = |r: &&Ratio| (r.to_f64() - x).abs();
+        let near = bounds.first().and_then(|xs| {
+            xs.iter().min_by(|a, b| off(a).partial_cmp(&off(b)).unwrap())
+        });
+        (0, near.cloned().unwrap_or_default())
+    };
+    let beat = sh.meter.beat_dur().to_f64();
+    let ramp = |at: Ratio, from: f64, to: &Tempo| {
+        let n = ramp_beats(at.to_f64(), to.at, beat);
+        let tag = if to.bpm < from { "rit" } else { "accel" };
+        format!("({} {} beats to {})", tag, n, to.bpm)
+    };
+
+    let pts = &sh.tempo.points;
+    let mut sheet = None;
+    let mut out = vec![];
+    let mut cur = DEFAULT_BPM;
+    let mut i = 0;
+    while i < pts.len() {
+        let p = pts[i];
+        if p.ramp {
+            // Nothing to start from but the last point.
+            let x = if i > 0 { pts[i - 1].at } else { 0. };
+            let (t, at) = place(x);
+            out.push((t, at, ramp(at, cur, &p)));
+            cur = p.bpm;
+            i += 1;
+            continue;
+        }
+        let (t, at) = place(p.at);
+        let set = format!("(tempo {})", p.bpm);
+        match pts.get(i + 1) {
+            Some(next) if next.ramp && next.at > p.at => {
+                if p.bpm != cur {
+                    out.push((t, at, set));
+                }
+                out.push((t, at, ramp(at, p.bpm, next)));
+                cur = next.bpm;
+                i += 2;
+            }
+            _ => {
+                if i == 0 && p.at == 0. {
+                    sheet = Some(set);
+                } else {
+                    out.push((t, at, set));
+                }
+                cur = p.bpm;
+                i += 1;
+            }
+        }
+    }
+    (sheet, out)
+}
+
+// A ramp's length in beats, for the reader to end it at `to`. Beats are
+// powers of two, which scale exactly.
+fn ramp_beats(from: f64, to: f64, beat: f64) -> f64 {
+    (to - from) / beat
+}
+
+// What we write into one staff, and what the reader will make of it.
+struct Staff {
+    key: Key,
+    clef: StaffClef,
+    // The clef the staff starts in, as declared.
+    declared: Clef,
+    dynamic: f32,
+    sharps: HashMap<i32, i32>,
+}
+
+// Marks still to be written into a staff, by position.
+type Events = Peekable<vec::IntoIter<(Ratio, String)>>;
+
+// Parts of a bar, with the tuplet and the gap they need to be in (if they
+// care).
+struct Entry {
+    scale: Option<Ratio>,
+    gap: Option<f64>,
+    item: Item,
+}
+
+enum Item {
+    Cmd(String),
+    // Written as a plain /4, which is played at mf.
+    Rest(String),
+    Note { dur: String, pitch: String, tie: bool },
+}
+
+fn cmd(s: String) -> Entry {
+    Entry { scale: None, gap: None, item: Item::Cmd(s) }
+}
+
+impl Staff {
+    fn new(t: &Track, bars: &[Vec<Piece>], key: Key) -> Self {
+        let clef = StaffClef::new(t, bars);
+        Self {
+            key,
+            declared: clef.current(),
+            clef,
+            dynamic: 1.,
+            sharps: HashMap::new(),
+        }
+    }
+
+    fn write_bar(&mut self, pieces: &[Piece], events: &mut Events,
+                 last: bool) -> String {
+        let mut es = vec![];
+        // The staff's own clef at the start of the first bar.
+        let changed = self.declared != self.clef.current()
+            || self.clef.next_bar(pieces);
+        if changed {
+            let clef = self.clef.current();
+            es.push(cmd(clef_name(clef).to_owned()));
+            self.declared = clef;
+        }
+        self.sharps.clear();
+        for p in pieces {
+            while let Some((_, ev)) = events.peek().filter(|ev| ev.0 <= p.at) {
+                es.push(cmd(ev.clone()));
+                events.next();
+            }
+            self.write_piece(p, &mut es);
+        }
+        if last {
+            es.extend(events.by_ref().map(|(_, ev)| cmd(ev)));
+        }
+        format!("({})", render(es))
+    }
+
+    fn write_piece(&mut self, p: &Piece, out: &mut Vec<Entry>) {
+        let n = p.note;
+        if let Some(vs) = n.as_voices() {
+            let v = self.write_voices(vs);
+            out.push(cmd(v));
+            return;
+        }
+        let dur = dur_name(p.dur);
+        let scale = Some(p.dur.scale);
+        if n.is_rest() {
+            if n.amp == 1. {
+                out.push(Entry { scale, gap: None, item: Item::Rest(dur) });
+            } else {
+                self.set_dynamic(n.amp, out);
+                out.push(Entry {
+                    scale,
+                    gap: None,
+                    item: Item::Note { dur, pitch: "r".to_owned(), tie: false },
+                });
+            }
+            return;
+        }
+        if p.first {
+            self.set_dynamic(n.amp, out);
+        }
+        let pitch = match &n.pitch {
+            Pitch::Single(t) => self.write_pitch(*t, false),
+            Pitch::Chord(ts) => {
+                let mut ps = vec![];
+                for t in ts {
+                    ps.push(self.write_pitch(*t, true));
+                }
+                format!("({})", ps.join(" "))
+            }
+            _ => unreachable!(),
+        };
+        // Only the last of tied notes has a say in the gap.
+        let gap = if !p.last {
+            None
+        } else if p.first {
+            Some(n.rest_after)
+        } else {
+            Some(tied_gap(n.rest_after, p.dur.dur() / n.dur()))
+        };
+        out.push(Entry {
+            scale,
+            gap,
+            item: Item::Note { dur, pitch, tie: !p.last },
+        });
+    }
+
+    // Each voice starts from the dynamic in force, and the first one leads
+    // after, as in the reader.
+    fn write_voices(&mut self, vs: &[Vec<Note>]) -> String {
+        let start = self.dynamic;
+        let mut after = None;
+        let mut out = vec![];
+        for v in vs {
+            self.dynamic = start;
+            let mut es = vec![];
+            let mut at = Ratio::zero();
+            for (ix, n) in v.iter().enumerate() {
+                let ds = note_durs(n);
+                let count = ds.len();
+                for (i, dur) in ds.into_iter().enumerate() {
+                    let p = Piece {
+                        note: n,
+                        ix,
+                        at,
+                        dur,
+                        first: i == 0,
+                        last: i + 1 == count,
+                    };
+                    self.write_piece(&p, &mut es);
+                    at += dur.dur();
+                }
+            }
+            after.get_or_insert(self.dynamic);
+            out.push(format!("({})", render(es)));
+        }
+        self.dynamic = after.unwrap_or(start);
+        format!("(voices {})", out.join(" "))
+    }
+
+    fn set_dynamic(&mut self, amp: f32, out: &mut Vec<Entry>) {
+        if amp == self.dynamic {
+            return;
+        }
+        let name = match DYNAMICS.iter().find(|x| x.1 == amp) {
+            Some(x) => x.0.to_owned(),
+            None => format!("(amp {})", amp_text(amp)),
+        };
+        out.push(cmd(name));
+        self.dynamic = amp;
+    }
+
+    // As a position on the staff, with the accidental if the key and the
+    // bar so far don't give it. In chords only names can carry one.
+    fn write_pitch(&mut self, tone: Tone, in_chord: bool) -> String {
+        let Tone { step, sharp } = tone.respelled();
+        let cur = self.sharps.get(&step).cloned()
+            .unwrap_or_else(|| self.key.sharps_for(step.rem_euclid(7)));
+        let ix = step - self.clef.current().bottom_line();
+        if cur == sharp {
+            return ix.to_string();
+        }
+        self.sharps.insert(step, sharp);
+        match ACCIDENTALS.iter().find(|x| x.1 == sharp) {
+            Some((acc, _)) if !in_chord => format!("({} {})", acc, ix),
+            _ => pitch_name(step, sharp),
+        }
+    }
+}
+
+// A staff's clef from bar to bar.
+pub struct StaffClef {
+    clef: Clef,
+    // Whether to follow the notes between treble and bass.
+    switch: bool,
+}
+
+impl StaffClef {
+    pub fn new(t: &Track, bars: &[Vec<Piece>]) -> Self {
+        let (clef, switch) = first_clef(t, bars);
+        Self { clef, switch }
+    }
+
+    pub fn current(&self) -> Clef {
+        self.clef
+    }
+
+    // Moves on to the next bar, and tells whether the clef changed.
+    pub fn next_bar(&mut self, pieces: &[Piece]) -> bool {
+        let clef = if self.switch {
+            bar_clef(self.clef, pieces)
+        } else {
+            self.clef
+        };
+        let changed = clef != self.clef;
+        self.clef = clef;
+        changed
+    }
+}
+
+// The clef a staff starts in, and whether it follows the notes between
+// treble and bass from there.
+fn first_clef(t: &Track, bars: &[Vec<Piece>]) -> (Clef, bool) {
+    match t.instrument {
+        Instrument::Violin => (Clef::Treble, false),
+        Instrument::Viola => (Clef::Alto, false),
+        Instrument::Cello => (Clef::Bass, false),
+        Instrument::Piano | Instrument::Voice => {
+            let steps: Vec<i32> = bars.iter()
+                .flat_map(|b| steps(b))
+                .collect();
+            let bass = mean(&steps) < -7.;
+            (if bass { Clef::Bass } else { Clef::Treble }, true)
+        }
+    }
+}
+
+// Treble or bass for a bar, changing only when the notes are well into the
+// other staff.
+fn bar_clef(clef: Clef, pieces: &[Piece]) -> Clef {
+    let m = mean(&steps(pieces));
+    match clef {
+        Clef::Bass if m > -5. => Clef::Treble,
+        Clef::Treble if m < -9. => Clef::Bass,
+        clef => clef,
+    }
+}
+
+// The reader takes amplitudes as f64s, which the shortest f32 doesn't always
+// come back from.
+fn amp_text(x: f32) -> String {
+    let s = x.to_string();
+    if s.parse::<f64>().map(|y| y as f32) == Ok(x) {
+        s
+    } else {
+        (x as f64).to_string()
+    }
+}
+
+// Groups the entries into tuplets, then gaps, then runs of notes of the same
+// length: (tuplet 3 2 (gap 0 (/8 1 2 3))).
+fn render(mut es: Vec<Entry>) -> String {
+    // Entries that don't care go along with their neighbours.
+    fill(&mut es, |e| &mut e.scale, Ratio::one());
+    fill(&mut es, |e| &mut e.gap, REST_AFTER);
+    let mut out = vec![];
+    for (scale, run) in &es.into_iter().group_by(|e| e.scale.unwrap()) {
+        let inner = render_gaps(run.collect());
+        if scale == Ratio::one() {
+            out.push(inner);
+        } else {
+            out.push(format!("(tuplet {} {} {})",
+                             scale.denom(), scale.numer(), inner));
+        }
+    }
+    out.join(" ")
+}
+
+fn fill<T: Copy>(es: &mut [Entry], field: impl Fn(&mut Entry) -> &mut Option<T>,
+                 default: T) {
+    let mut next = None;
+    for e in es.iter_mut().rev() {
+        match *field(e) {
+            Some(x) => next = Some(x),
+            None => *field(e) = next,
+        }
+    }
+    let mut prev = default;
+    for e in es.iter_mut() {
+        match *field(e) {
+            Some(x) => prev = x,
+            None => *field(e) = Some(prev),
+        }
+    }
+}
+
+fn render_gaps(es: Vec<Entry>) -> String {
+    let mut out = vec![];
+    for (gap, run) in &es.into_iter().group_by(|e| e.gap.unwrap()) {
+        let inner = render_items(run.map(|e| e.item).collect());
+        if gap == REST_AFTER {
+            out.push(inner);
+        } else {
+            out.push(format!("(gap {} {})", gap, inner));
+        }
+    }
+    out.join(" ")
+}
+
+fn render_items(items: Vec<Item>) -> String {
+    let mut out: Vec<String> = vec![];
+    // The notes of the list being written, and their length.
+    let mut run: Option<(String, Vec<String>)> = None;
+    for item in items {
+        match item {
+            Item::Note { dur, pitch, tie } => {
+                let same = run.as_ref().is_some_and(|r| r.0 == dur);
+                if !same {
+                    out.extend(run.take().map(close_run));
+                    run = Some((dur, vec![]));
+                }
+                let ps = &mut run.as_mut().unwrap().1;
+                ps.push(pitch);
+                if tie {
+                    ps.push("~".to_owned());
+                }
+            }
+            Item::Rest(dur) | Item::Cmd(dur) => {
+                out.extend(run.take().map(close_run));
+                out.push(dur);
+            }
+        }
+    }
+    out.extend(run.take().map(close_run));
+    out.join(" ")
+}
+
+fn close_run((dur, ps): (String, Vec<String>)) -> String {
+    format!("({} {})", dur, ps.join(" "))
+}
+
+// Every pitch of the pieces, as steps from C5.
+fn steps(pieces: &[Piece]) -> Vec<i32> {
+    let mut out = vec![];
+    for p in pieces {
+        collect_steps(&p.note.pitch, &mut out);
+    }
+    out
+}
+
+fn collect_steps(pitch: &Pitch, out: &mut Vec<i32>) {
+    match pitch {
+        Pitch::Rest => (),
+        Pitch::Single(t) => out.push(t.step),
+        Pitch::Chord(ts) => out.extend(ts.iter().map(|t| t.step)),
+        Pitch::Voices(vs) => for n in vs.iter().flatten() {
+            collect_steps(&n.pitch, out);
+        },
+    }
+}
+
+// Middle C for no notes at all, which is neither treble nor bass.
+fn mean(xs: &[i32]) -> f64 {
+    if xs.is_empty() {
+        -7.
+    } else {
+        xs.iter().sum::<i32>() as f64 / xs.len() as f64
+    }
+}
+
+fn dur_name(d: Duration) -> String {
+    format!("/{}{}", d.klass, ".".repeat(d.dots as usize))
+}
+
+// c4 is middle C. The tone is respelled, so that it has a name.
+fn pitch_name(step: i32, sharp: i32) -> String {
+    const LETTERS: [&str; 7] = ["c", "d", "e", "f", "g", "a", "b"];
+    let letter = LETTERS[step.rem_euclid(7) as usize];
+    let acc = match sharp {
+        0 => "n",
+        1 => "#",
+        2 => "##",
+        -1 => "b",
+        -2 => "bb",
+        _ => unreachable!(),
+    };
+    format!("{}{}{}", letter, acc, 5 + step.div_euclid(7))
+}
+
+fn clef_name(clef: Clef) -> &'static str {
+    CLEFS.iter().find(|x| x.1 == clef).unwrap().0
+}
+
+fn key_name(key: Key) -> String {
+    // Tonics around the circle of fifths, from Cb major.
+    const TONICS: [&str; 18] = [
+        "Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G",
+        "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#",
+    ];
+    let ix = key.fifths + 7 + if key.minor { 3 } else { 0 };
+    format!("(key {} {})", TONICS[ix as usize],
+            if key.minor { "minor" } else { "major" })
+}
+
+fn instrument_name(i: Instrument) -> &'static str {
+    match i {
+        Instrument::Piano => "piano",
+        Instrument::Violin => "violin",
+        Instrument::Viola => "viola",
+        Instrument::Cello => "cello",
+        Instrument::Voice => "voice",
+    }
+}
+
+// Staff names are symbols.
+fn staff_name(name: &str) -> String {
+    let s: String = name.chars().map(|c| {
+        if c.is_whitespace() || "()[]\";'#".contains(c) { '-' } else { c }
+    }).collect();
+    if s.is_empty() || s.parse::<f64>().is_ok() {
+        format!("staff-{}", s)
+    } else {
+        s
+    }
+}
+
+// Lays out a hand-written file the way write_sheet lays out its own: a
+// staff's bar to a line and a blank line between systems. The forms stay as
+// they were written, motifs, repeats and articulations and all, and so do
+// the comments.
+pub fn format(src: &str) -> Result<String, NotationError> {
+    // Only what the reader takes is worth laying out.
+    notation::parse_forms(src)?;
+    let forms = read_forms(&mut tokens(src).into_iter(), &mut 0);
+
+    let texts: Vec<String> = forms.iter().map(|f| lay(f, 0)).collect();
+    // A comment goes with the form after it.
+    let mut multi = vec![false; forms.len()];
+    let mut next = false;
+    for (i, t) in texts.iter().enumerate().rev() {
+        if !matches!(forms[i], Form::Comment(..)) {
+            next = t.contains('\n');
+        }
+        multi[i] = next;
+    }
+    let mut out = String::new();
+    for (i, (f, t)) in forms.iter().zip(&texts).enumerate() {
+        if i > 0 {
+            out.push_str(match (f, &forms[i - 1]) {
+                (Form::Comment(_, true), _) => " ",
+                (_, Form::Comment(..)) => "\n",
+                _ if multi[i] || multi[i - 1] => "\n\n",
+                _ => "\n",
+            });
+        }
+        out.push_str(t);
+    }
+    out.push('\n');
+    Ok(out)
+}
+
+const WIDTH: usize = 80;
+
+// A form as written, for the formatter.
+enum Form {
+    Atom(String),
+    // The brackets, and what's between them.
+    List(char, char, Vec<Form>),
+    // Whether it goes at the end of the line before.
+    Comment(String, bool),
+}
+
+// Up to the bracket that closes the list, if in one. `line` is the line of
+// the last token.
+fn read_forms(ts: &mut impl Iterator<Item = (Pos, Token)>,
+              line: &mut usize) -> Vec<Form> {
+    let mut out = vec![];
+    while let Some((pos, t)) = ts.next() {
+        let trailing = pos.line == *line;
+        *line = pos.line;
+        out.push(match t {
+            Token::Open(o) => {
+                let inner = read_forms(ts, line);
+                let c = if o == '[' { ']' } else { ')' };
+                Form::List(o, c, inner)
+            }
+            Token::Close(_) => break,
+            Token::Atom(s) => Form::Atom(s),
+            Token::Comment(s) => {
+                *line += s.matches('\n').count();
+                Form::Comment(s.trim_end().to_owned(), trailing)
+            }
+        });
+    }
+    out
+}
+
+// On one line, if it has no comments.
+fn flat(f: &Form) -> Option<String> {
+    match f {
+        Form::Atom(s) => Some(s.clone()),
+        Form::List(o, c, items) => {
+            let items: Option<Vec<String>> = items.iter().map(flat).collect();
+            Some(format!("{}{}{}", o, items?.join(" "), c))
+        }
+        Form::Comment(..) => None,
+    }
+}
+
+// `f` written from column `col` on. Lines after the first are indented.
+fn lay(f: &Form, col: usize) -> String {
+    let (o, c, items) = match f {
+        Form::List(o, c, items) => (*o, *c, items),
+        Form::Atom(s) | Form::Comment(s, _) => return s.clone(),
+    };
+    let head = match items.first() {
+        Some(Form::Atom(s)) => Some(&s[..]),
+        _ => None,
+    };
+    let bars = items.iter().rposition(|f| !matches!(f, Form::Comment(..)));
+    if let (Some(staves), Some(bars @ 2..)) =
+        (head.and_then(|h| sheet_staves(h, items)), bars) {
+        return lay_sheet(items, bars, col, staves);
+    }
+    if let Some(s) = flat(f).filter(|s| col + s.len() <= WIDTH) {
+        return s;
+    }
+    let mut l = Lines::new(col + 1, o);
+    let mut units = &items[..];
+    if let Some(h) = head {
+        l.push_str(h);
+        l.indent += h.len() + 1;
+        units = &items[1..];
+    }
+    // :title "..." and the like go together, a pair to a line.
+    let pairs = units.iter().any(is_keyword);
+    let mut i = 0;
+    while i < units.len() {
+        let n = match units.get(i + 1) {
+            Some(v) if pairs && is_keyword(&units[i]) && !is_keyword(v)
+                && !matches!(v, Form::Comment(..)) => 2,
+            _ => 1,
+        };
+        // The first goes after the head all the same.
+        l.fill(&units[i..i + n], pairs && i > 0);
+        l.ended |= pairs;
+        i += n;
+    }
+    l.close(c)
+}
+
+fn is_keyword(f: &Form) -> bool {
+    matches!(f, Form::Atom(s) if s.starts_with(':'))
+}
+
+// The number of staves of a (piano ...) or (score (staves ...) ...) sheet.
+fn sheet_staves(head: &str, items: &[Form]) -> Option<usize> {
+    match (head, items.get(1)) {
+        ("piano", _) => Some(2),
+        ("score", Some(Form::List(_, _, xs))) => Some(xs.iter()
+            .filter(|x| matches!(x, Form::List(..)))
+            .count())
+            .filter(|&n| n > 0),
+        _ => None,
+    }
+}
+
+// The sheet's head, then what comes before the bars (`items[bars]`), then
+// the bars.
+fn lay_sheet(items: &[Form], bars: usize, col: usize, staves: usize)
+    -> String {
+    let mut l = Lines::new(col + 1, '(');
+    l.push_str(&lay(&items[0], col + 1));
+    l.indent = col + 2;
+    l.new_line();
+    let score = matches!(&items[0], Form::Atom(s) if s == "score");
+    for (i, f) in items[1..bars].iter().enumerate() {
+        // (staves ...) has a line to itself.
+        l.fill(std::slice::from_ref(f), score && i == 0);
+    }
+    l.new_line();
+    let text = match &items[bars] {
+        Form::List(o, c, xs) => lay_bars(*o, *c, xs, col + 2, staves),
+        f => lay(f, col + 2),
+    };
+    l.push_str(&text);
+    for f in &items[bars + 1..] {
+        l.fill(std::slice::from_ref(f), false);
+    }
+    l.close(')')
+}
+
+// A staff's bar to a line, a blank line before each system, and repeats and
+// the like on a line of their own before the bars they come before.
+fn lay_bars(o: char, c: char, items: &[Form], col: usize, staves: usize)
+    -> String {
+    let mut l = Lines::new(col + 1, o);
+    let mut count = 0;
+    let mut blank = false;
+    let mut flows = false;
+    let mut i = 0;
+    while i < items.len() {
+        let f = &items[i];
+        let drop = matches!(f, Form::Atom(s) if s == "drop");
+        let n = if drop && i + 1 < items.len() { 2 } else { 1 };
+        let unit = &items[i..i + n];
+        i += n;
+        if let Form::Comment(_, true) = f {
+            l.fill(unit, false);
+            continue;
+        }
+        let flow = drop || is_flow(f);
+        if !(flow && flows) && !l.empty() {
+            if blank {
+                l.blank_line();
+            } else {
+                l.new_line();
+            }
+        }
+        blank = false;
+        l.fill(unit, false);
+        flows = flow;
+        if !flow && !matches!(f, Form::Comment(..)) {
+            count += 1;
+            blank = count % staves == 0;
+        }
+    }
+    l.close(c)
+}
+
+// Marks of repeats and jumps, as against a staff's bar.
+fn is_flow(f: &Form) -> bool {
+    match f {
+        Form::Atom(_) => true,
+        Form::List(_, _, xs) => matches!(xs.first(),
+            Some(Form::Atom(s)) if s == "repeat-end" || s == "ending"),
+        Form::Comment(..) => false,
+    }
+}
+
+// Text being laid out in lines, after an opening bracket.
+struct Lines {
+    out: String,
+    // Where lines start, and where the last one is up to.
+    indent: usize,
+    col: usize,
+    // After a comment (or what takes several lines), nothing more goes on
+    // the line.
+    ended: bool,
+    comment: bool,
+}
+
+impl Lines {
+    fn new(indent: usize, open: char) -> Self {
+        Self {
+            out: open.to_string(),
+            indent,
+            col: indent,
+            ended: false,
+            comment: false,
+        }
+    }
+
+    fn empty(&self) -> bool {
+        self.out.len() == 1
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.out.push_str(s);
+        match s.rfind('\n') {
+            Some(n) => self.col = s.len() - n - 1,
+            None => self.col += s.len(),
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.out.push('\n');
+        self.out.extend(std::iter::repeat_n(' ', self.indent));
+        self.col = self.indent;
+        self.ended = false;
+    }
+
+    fn blank_line(&mut self) {
+        self.out.push('\n');
+        self.new_line();
+    }
+
+    // Forms that go together: after what's on the line if they fit there
+    // (and `alone` doesn't want a line to themselves), else on the next.
+    fn fill(&mut self, unit: &[Form], alone: bool) {
+        let at_start = self.empty() || self.col == self.indent;
+        if let [Form::Comment(s, trailing)] = unit {
+            if !*trailing && !at_start {
+                self.new_line();
+            } else if !at_start {
+                self.push_str(" ");
+            }
+            self.push_str(s);
+            self.ended = true;
+            self.comment = true;
+            return;
+        }
+        let sep = if at_start { 0 } else { 1 };
+        let text = self.unit_text(unit, self.col + sep);
+        let fits = !text.contains('\n') && self.col + sep + text.len() <= WIDTH;
+        if !at_start && (self.ended || alone || !fits) {
+            self.new_line();
+            let text = self.unit_text(unit, self.col);
+            self.push_str(&text);
+        } else {
+            if sep > 0 {
+                self.push_str(" ");
+            }
+            self.push_str(&text);
+        }
+        self.ended = alone || text.contains('\n');
+        self.comment = false;
+    }
+
+    fn unit_text(&self, unit: &[Form], col: usize) -> String {
+        let mut out = String::new();
+        for f in unit {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            let at = col + out.len() - out.rfind('\n').map_or(0, |n| n + 1);
+            out.push_str(&lay(f, at));
+        }
+        out
+    }
+
+    // A bracket can't go after a comment.
+    fn close(mut self, c: char) -> String {
+        if self.comment {
+            self.new_line();
+        }
+        self.out.push(c);
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // The same notes, at the same places.
+    fn same(a: &[Note], b: &[Note]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| {
+            let notes = match (x.as_voices(), y.as_voices()) {
+                (Some(p), Some(q)) => p.len() == q.len()
+                    && p.iter().zip(q).all(|(p, q)| same(p, q)),
+                (None, None) => x.pitch == y.pitch,
+                _ => false,
+            };
+            notes && x.dur() == y.dur() && x.tie == y.tie
+                && x.amp == y.amp && x.rest_after == y.rest_after
+        })
+    }
+
+    fn tempo(sh: &Sheet) -> Vec<(f64, f64, bool)> {
+        sh.tempo.points.iter().map(|p| (p.at, p.bpm, p.ramp)).collect()
+    }
+
+    // Writes the sheet out and reads it back.
+    fn round_trip(name: &str, a: &Sheet) {
+        let out = write_sheet(a);
+        let b = notation::read_sheet(out.as_bytes())
+            .unwrap_or_else(|e| panic!("{}: {}\n{}", name, e, out));
+        assert_eq!(a.tracks.len(), b.tracks.len(), "{}", name);
+        for (x, y) in a.tracks.iter().zip(&b.tracks) {
+            assert!(same(&x.notes, &y.notes), "{}: {}\n{}", name, x.name, out);
+            assert!(x.pedal == y.pedal, "{}: {}", name, x.name);
+        }
+        assert_eq!(tempo(a), tempo(&b), "{}", name);
+        assert_eq!(write_sheet(&b), out, "{}", name);
+    }
+
+    #[test]
+    fn reads_back_the_same() {
+        for f in &["kv545.ss", "kv545-m2.ss", "kv545-m3.ss"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(f);
+            let src = std::fs::read_to_string(path).unwrap();
+            round_trip(f, &notation::read_sheet(src.as_bytes()).unwrap());
+        }
+    }
+
+    #[test]
+    fn gaps_and_ramps_come_back_exactly() {
+        // Gaps with more places than anyone writes, one of them on tied
+        // notes, and a ramp that starts within a tuplet.
+        let src = "(piano (6 8) (key C major) (tempo 90) \
+                   ((treble-C (tuplet 3 2 (/4 0 1) (rit 5 beats to 60) \
+                                          (/4 2)) \
+                              (/4 3 ~)) \
+                    (treble-C (/2. 0)) \
+                    ((/4. 3) (/4. 4)) ((/2. 0)) \
+                    ((/2. 5)) ((/2. 0))))";
+        let mut sh = notation::read_sheet(src.as_bytes()).unwrap();
+        sh.tracks[0].notes[2].rest_after = 0.123456789;
+        // Tied over a quarter, then a dotted quarter.
+        sh.tracks[0].notes[3].rest_after = 0.987654321;
+        round_trip("ties and ramps", &sh);
+    }
+
+    #[test]
+    fn formats_what_was_written() {
+        for f in &["kv545.ss", "kv545-m2.ss", "kv545-m3.ss",
+                   "kv545-sonata.ss"] {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(f);
+            let src = std::fs::read_to_string(path).unwrap();
+            let out = format(&src).unwrap();
+            let forms = |s: &str| notation::parse_forms(s).unwrap();
+            assert!(forms(&out) == forms(&src), "{}", f);
+            assert_eq!(format(&out).unwrap(), out, "{}", f);
+            if f.ends_with("sonata.ss") {
+                continue;
+            }
+            let a = notation::read_sheet(src.as_bytes()).unwrap();
+            let b = notation::read_sheet(out.as_bytes()).unwrap();
+            for (x, y) in a.tracks.iter().zip(&b.tracks) {
+                assert!(same(&x.notes, &y.notes), "{}: {}", f, x.name);
+            }
+        }
+    }
+
+    #[test]
+    fn formats_a_staff_bar_to_a_line() {
+        let src = "; Motifs stay.\n(define up (/8 0 1 2 3))   (piano (4 4)\n\
+                   (key C major) ( repeat-start (treble-C (use up)\n\
+                   (^ (/2 4 ~ ) ) ) ; the bass\n(/1) (ending 1) dc\n\
+                   ((/1 0)) #| rest |# (/1)))";
+        assert_eq!(format(src).unwrap(), "\
+; Motifs stay.
+(define up (/8 0 1 2 3))
+
+(piano
+  (4 4) (key C major)
+  (repeat-start
+   (treble-C (use up) (^ (/2 4 ~))) ; the bass
+   (/1)
+
+   (ending 1) dc
+   ((/1 0)) #| rest |#
+   (/1)))
+");
+    }
+
+    #[test]
+    fn respells_past_double_sharps() {
+        let src = "(piano (1 4) (key C major) ((treble-C (/4 0)) ((/4 0))))";
+        let mut sh = notation::read_sheet(src.as_bytes()).unwrap();
+        // C### and Fbbb are D# and Ebb.
+        sh.tracks[0].notes[0].pitch = Pitch::Chord(vec![Tone::new(0, 3),
+                                                        Tone::new(3, -3)]);
+        let back = write_sheet(&sh);
+        assert!(back.contains("(/4 (d#5 ebb5))"), "{}", back);
+    }
+
+    #[test]
+    fn trills_come_out_as_plain_values() {
+        let src = "(piano (1 4) (key C major) \
+                   ((treble-C (tr (/8 8)) (/8 7)) (treble-C (/4 0))))";
+        let sh = notation::read_sheet(src.as_bytes()).unwrap();
+        let out = write_sheet(&sh);
+        assert!(out.contains("/32"), "{}", out);
+        assert!(!out.contains("tuplet"), "{}", out);
+    }
+}